@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+
+use expr::Expr;
+use line::Line;
+use symbol::Symbol;
+
+/// The symbols known while lines are read, for `IF` and `IFDEF`: the `-D`
+/// defines, then labels and constant `EQU`/`SET` definitions as they are
+/// reached. Labels are defined but have no value yet.
+pub struct Known {
+    values: HashMap<String, Option<i32>>,
+}
+
+impl Known {
+    pub fn new(defines: &[Symbol]) -> Known {
+        let values = defines.iter().map(|x| (x.name.to_lowercase(), Some(x.value as i32))).collect();
+        Known { values }
+    }
+
+    /// Records the label or definition on an assembled line.
+    pub fn record(&mut self, line: &Line) {
+        let name = match line.label {
+            Some(ref l) => l.to_lowercase(),
+            None => return,
+        };
+        let mnu = line.mnu.as_ref().map(|x| x.to_lowercase()).unwrap_or_default();
+        let value = match (mnu.as_ref(), line.ops.first()) {
+            ("equ", Some(op)) | ("set", Some(op)) => self.eval(op).ok(),
+            _ => None,
+        };
+        self.values.insert(name, value);
+    }
+
+    fn eval(&self, text: &str) -> Result<i32, String> {
+        self.value(&Expr::parse(text)?)
+    }
+
+    /// Evaluates `expr` from the symbols known so far.
+    pub fn value(&self, expr: &Expr) -> Result<i32, String> {
+        expr.eval(&|s: &str| match self.values.get(s) {
+            Some(&Some(v)) => Ok(v),
+            Some(&None) => Err(format!("{} has no value before it is assembled", s)),
+            None => Err(format!("{} is not defined", s)),
+        }, 0)
+    }
+}
+
+/// One `IF ... ENDIF` block being read.
+struct Block {
+    line: u64,
+    /// Whether the block is inside code being assembled.
+    outer: bool,
+    /// Whether the current branch is assembled.
+    active: bool,
+    /// Whether an earlier branch was assembled.
+    taken: bool,
+    /// Whether `ELSE` has been seen.
+    done: bool,
+}
+
+/// The state of nested `IF`/`IFDEF`/`IFNDEF` ... `ELSEIF` ... `ELSE` ...
+/// `ENDIF` blocks.
+pub struct Conditions {
+    blocks: Vec<Block>,
+}
+
+impl Conditions {
+    pub fn new() -> Conditions {
+        Conditions { blocks: Vec::new() }
+    }
+
+    /// Whether lines are being assembled at this point.
+    pub fn active(&self) -> bool {
+        self.blocks.last().is_none_or(|x| x.active)
+    }
+
+    /// The line of the innermost block left open, if any.
+    pub fn open(&self) -> Option<u64> {
+        self.blocks.last().map(|x| x.line)
+    }
+
+    /// Handles `line` if it is a conditional directive, returning whether it
+    /// was one. Conditions in skipped code are not evaluated, and one that
+    /// cannot be evaluated counts as false.
+    pub fn directive(&mut self, line: &Line, known: &Known) -> Result<bool, String> {
+        let word = line.mnu.as_ref().map(|x| x.to_lowercase()).unwrap_or_default();
+        let active = self.active();
+        match word.as_ref() {
+            "if" | "ifdef" | "ifndef" => {
+                let value = if active { condition(&word, line, known) } else { Ok(false) };
+                let v = *value.as_ref().unwrap_or(&false);
+                self.blocks.push(Block { line: line.num, outer: active, active: v, taken: v, done: false });
+                value?;
+            },
+            "elseif" => {
+                let block = match self.blocks.last_mut() {
+                    Some(b) if !b.done => b,
+                    _ => return Err(String::from("ELSEIF without IF")),
+                };
+                let value = if block.outer && !block.taken { condition("if", line, known) } else { Ok(false) };
+                block.active = *value.as_ref().unwrap_or(&false);
+                block.taken |= block.active;
+                value?;
+            },
+            "else" => {
+                let block = match self.blocks.last_mut() {
+                    Some(b) if !b.done => b,
+                    _ => return Err(String::from("ELSE without IF")),
+                };
+                block.active = block.outer && !block.taken;
+                block.done = true;
+            },
+            "endif" => {
+                if self.blocks.pop().is_none() {
+                    return Err(String::from("ENDIF without IF"));
+                }
+            },
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+fn condition(word: &str, line: &Line, known: &Known) -> Result<bool, String> {
+    let op = match line.ops.first() {
+        Some(op) if line.ops.len() == 1 => op,
+        _ => return Err(format!("{} takes one operand", word.to_uppercase())),
+    };
+    match word {
+        "ifdef" => Ok(known.values.contains_key(&op.to_lowercase())),
+        "ifndef" => Ok(!known.values.contains_key(&op.to_lowercase())),
+        _ => known.eval(op).map(|v| v != 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use line;
+    use symbol::SymbolKind;
+
+    /// The numbers of the lines assembled, or the first error.
+    fn assembled(source: &str) -> Result<Vec<u64>, String> {
+        let mut known = Known::new(&[Symbol::new(String::from("DEBUG"), 2, SymbolKind::Number, 0)]);
+        let mut conds = Conditions::new();
+        let mut out = Vec::new();
+        for line in line::get_lines(source.to_string()) {
+            if conds.directive(&line, &known)? || !conds.active() {
+                continue;
+            }
+            known.record(&line);
+            out.push(line.num);
+        }
+        match conds.open() {
+            Some(num) => Err(format!("IF at line {} without ENDIF", num)),
+            None => Ok(out),
+        }
+    }
+
+    #[test]
+    fn nests_blocks() {
+        let source = " if debug > 1\n  if debug > 2\n   nop\n  elseif debug = 2\n   inc a\n  else\n   dec a\n  endif\n else\n  nop\n endif\n";
+        assert_eq!(assembled(source), Ok(vec![5]));
+    }
+
+    #[test]
+    fn skips_inner_blocks_without_evaluating_them() {
+        let source = " ifndef debug\n  if missing\n   nop\n  else\n   nop\n  endif\n else\n  inc a\n endif\n";
+        assert_eq!(assembled(source), Ok(vec![8]));
+    }
+
+    #[test]
+    fn sees_constants_defined_before() {
+        let source = "size equ 4\n if size = 4\n  nop\n endif\n ifdef later\n  nop\n endif\nlater equ 1\n";
+        assert_eq!(assembled(source), Ok(vec![1, 3, 8]));
+    }
+
+    #[test]
+    fn rejects_unbalanced_blocks() {
+        assert!(assembled(" else\n").is_err());
+        assert!(assembled(" endif\n").is_err());
+        assert!(assembled(" if 1\n else\n elseif 1\n endif\n").is_err());
+        assert!(assembled(" if 1\n if 0\n endif\n").is_err());
+    }
+}
@@ -0,0 +1,164 @@
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// Information that only appears in the listing.
+    Note,
+    Warning,
+    Error,
+}
+
+/// The kinds of warning, each of which can be disabled with `-Wno-<name>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Warning {
+    UnknownOption,
+    Truncation,
+    UnusedLabel,
+    DuplicateLabel,
+    DsOverlap,
+    OrgOverlap,
+    MissingEnd,
+}
+
+impl Warning {
+    pub const ALL: [Warning; 7] = [
+        Warning::UnknownOption,
+        Warning::Truncation,
+        Warning::UnusedLabel,
+        Warning::DuplicateLabel,
+        Warning::DsOverlap,
+        Warning::OrgOverlap,
+        Warning::MissingEnd,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Warning::UnknownOption => "unknown-option",
+            Warning::Truncation => "truncation",
+            Warning::UnusedLabel => "unused-label",
+            Warning::DuplicateLabel => "duplicate-label",
+            Warning::DsOverlap => "ds-overlap",
+            Warning::OrgOverlap => "org-overlap",
+            Warning::MissingEnd => "missing-end",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Warning> {
+        Warning::ALL.iter().cloned().find(|x| x.name() == name)
+    }
+}
+
+/// A message about the source, reported as `file:line:column: severity: message`.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub file: String,
+    /// Source line, starting at 1. Zero for diagnostics not tied to a line.
+    pub line: u64,
+    pub column: Option<usize>,
+    pub message: String,
+    /// The kind of warning, for diagnostics that can be disabled.
+    pub warning: Option<Warning>,
+    /// The file and line of each `INCLUDE` that led to `file`, innermost
+    /// first.
+    pub included_from: Vec<(String, u64)>,
+    /// The macros whose expansion produced the line, innermost first, each
+    /// with the file and line it was called from.
+    pub expanded_from: Box<[(String, String, u64)]>,
+}
+
+impl Diagnostic {
+    pub fn error<T: Into<String>>(line: u64, message: T) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            file: String::new(),
+            line,
+            column: None,
+            message: message.into(),
+            warning: None,
+            included_from: Vec::new(),
+            expanded_from: Box::new([]),
+        }
+    }
+
+    pub fn warning<T: Into<String>>(line: u64, kind: Warning, message: T) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            warning: Some(kind),
+            ..Diagnostic::error(line, message)
+        }
+    }
+
+    pub fn note<T: Into<String>>(line: u64, message: T) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Note,
+            ..Diagnostic::error(line, message)
+        }
+    }
+
+    /// Sets the file name unless the diagnostic already has one.
+    pub fn in_file(mut self, file: &str) -> Diagnostic {
+        if self.file.is_empty() {
+            self.file = file.to_string();
+        }
+        for call in self.expanded_from.iter_mut().filter(|x| x.1.is_empty()) {
+            call.1 = file.to_string();
+        }
+        self
+    }
+
+    /// Notes the expansions that produced the line from its
+    /// `Line::expanded_from`. Each block was called from the line of the
+    /// block around it, and the outermost from the line itself.
+    pub fn expanded(mut self, from: &[(String, u64)]) -> Diagnostic {
+        self.expanded_from = from.iter().enumerate()
+            .map(|(i, x)| (x.0.clone(), String::new(), from.get(i + 1).map(|y| y.1).unwrap_or(self.line)))
+            .collect();
+        self
+    }
+
+    /// Renders the diagnostic without its location.
+    pub fn describe(&self) -> String {
+        let severity = match self.severity {
+            Severity::Note => "note",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        match self.warning {
+            Some(w) => format!("{}: {} [-W{}]", severity, self.message, w.name()),
+            None => format!("{}: {}", severity, self.message),
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, &(ref file, line)) in self.included_from.iter().enumerate() {
+            let prefix = if i == 0 { "In file included from" } else { "                 from" };
+            let end = if i + 1 == self.included_from.len() { ":" } else { "," };
+            writeln!(f, "{} {}:{}{}", prefix, file, line, end)?;
+        }
+        for (i, &(ref name, ref file, line)) in self.expanded_from.iter().enumerate() {
+            let prefix = if i == 0 { "In expansion of" } else { "             of" };
+            let end = if i + 1 == self.expanded_from.len() { ":" } else { "," };
+            writeln!(f, "{} {} at {}:{}{}", prefix, name, file, line, end)?;
+        }
+        if !self.file.is_empty() {
+            write!(f, "{}:", self.file)?;
+        }
+        if self.line > 0 {
+            write!(f, "{}:", self.line)?;
+            if let Some(col) = self.column {
+                write!(f, "{}:", col)?;
+            }
+        }
+        if !self.file.is_empty() || self.line > 0 {
+            write!(f, " ")?;
+        }
+        write!(f, "{}", self.describe())
+    }
+}
@@ -0,0 +1,262 @@
+use omf::{self, ContentRecord};
+use opcode::{self, Opcode, Operand};
+use symbol::{Symbol, SymbolKind};
+
+/// One decoded instruction, or a byte that is not one.
+struct Decoded {
+    addr: u16,
+    bytes: Vec<u8>,
+    form: Option<&'static Opcode>,
+    /// The operand values, in source order.
+    values: Vec<u16>,
+}
+
+/// Reads a code image: Intel HEX if the file name ends in `.hex`, OMF-51 if
+/// it ends in `.obj` or `.omf`, and otherwise a binary loaded at address 0.
+pub fn load(file_name: &str, bytes: &[u8]) -> Result<Vec<ContentRecord>, String> {
+    let lower = file_name.to_lowercase();
+    if lower.ends_with(".hex") {
+        match String::from_utf8(bytes.to_vec()) {
+            Ok(text) => read_hex(&text),
+            Err(_) => Err(String::from("HEX file is not text")),
+        }
+    } else if lower.ends_with(".obj") || lower.ends_with(".omf") {
+        omf::read(bytes)
+    } else {
+        Ok(vec![ContentRecord::new(0, bytes.to_vec(), 0)])
+    }
+}
+
+/// Reads the data records of an Intel HEX file.
+fn read_hex(text: &str) -> Result<Vec<ContentRecord>, String> {
+    let mut records: Vec<ContentRecord> = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let bad = || format!("line {}: invalid HEX record", i + 1);
+        let digits = match line.strip_prefix(':') {
+            Some(d) if d.len() % 2 == 0 && d.len() >= 10 && d.bytes().all(|b| b.is_ascii_hexdigit()) => d,
+            _ => return Err(bad()),
+        };
+        let mut bytes = Vec::new();
+        for j in (0..digits.len()).step_by(2) {
+            match u8::from_str_radix(&digits[j..j + 2], 16) {
+                Ok(b) => bytes.push(b),
+                Err(_) => return Err(bad()),
+            }
+        }
+        if bytes.len() != bytes[0] as usize + 5 {
+            return Err(bad());
+        }
+        if bytes.iter().fold(0u8, |sum, x| sum.wrapping_add(*x)) != 0 {
+            return Err(format!("line {}: bad checksum", i + 1));
+        }
+        let offset = (bytes[1] as u16) << 8 | bytes[2] as u16;
+        let data = bytes[4..bytes.len() - 1].to_vec();
+        match bytes[3] {
+            0x00 => {
+                // Join records that continue the previous one
+                let next = records.last().map(|x| x.offset() as usize + x.data().len());
+                if next == Some(offset as usize) {
+                    let last = records.pop().unwrap();
+                    let mut joined = last.data();
+                    joined.extend_from_slice(&data);
+                    records.push(ContentRecord::new(last.offset(), joined, 0));
+                } else {
+                    records.push(ContentRecord::new(offset, data, 0));
+                }
+            },
+            0x01 => break,
+            0x02 | 0x04 if data.iter().any(|&x| x != 0) => {
+                return Err(format!("line {}: addresses above 64K are not supported", i + 1));
+            },
+            _ => {},
+        }
+    }
+    Ok(records)
+}
+
+/// Disassembles the records into source that asm622 assembles back to the
+/// same bytes. `symbols` names the SFRs and bits used as direct addresses.
+pub fn disassemble(records: &[ContentRecord], symbols: &[Symbol]) -> String {
+    let blocks: Vec<(u16, Vec<Decoded>)> = records.iter()
+        .filter(|x| !x.data().is_empty())
+        .map(|x| (x.offset(), decode_all(x.offset(), &x.data())))
+        .collect();
+
+    let starts: Vec<u16> = blocks.iter().flat_map(|b| b.1.iter().filter(|x| x.form.is_some()).map(|x| x.addr)).collect();
+    let mut targets: Vec<u16> = blocks.iter().flat_map(|b| b.1.iter().filter_map(target)).collect();
+    targets.sort();
+    targets.dedup();
+
+    let mut out = String::new();
+    for t in targets.iter().filter(|x| !starts.contains(x)) {
+        out += &format!("{:<8}EQU {}\n", label(*t), hex(*t as u32, 4));
+    }
+    for &(org, ref code) in &blocks {
+        out += &format!("\n        ORG {}\n", hex(org as u32, 4));
+        for ins in code {
+            let name = if ins.form.is_some() && targets.contains(&ins.addr) {
+                format!("{}:", label(ins.addr))
+            } else {
+                String::new()
+            };
+            let bytes: Vec<String> = ins.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            let text = format!("{:<8}{}", name, source(ins, symbols));
+            out += &format!("{:<40}; {:04X}  {}\n", text, ins.addr, bytes.join(" "));
+        }
+    }
+    out += "\n        END\n";
+    out
+}
+
+fn decode_all(org: u16, data: &[u8]) -> Vec<Decoded> {
+    let mut code = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let addr = org.wrapping_add(pos as u16);
+        let form = opcode::decode(data[pos]).filter(|x| pos + x.len() <= data.len());
+        let ins = match form {
+            Some(form) => {
+                let bytes = data[pos..pos + form.len()].to_vec();
+                let end = addr.wrapping_add(form.len() as u16);
+                Decoded { addr, values: form.values(&bytes, end), bytes, form: Some(form) }
+            },
+            None => Decoded { addr, bytes: vec![data[pos]], form: None, values: Vec::new() },
+        };
+        pos += ins.bytes.len();
+        code.push(ins);
+    }
+    code
+}
+
+/// The code address an instruction jumps to, if any.
+fn target(ins: &Decoded) -> Option<u16> {
+    let form = ins.form?;
+    form.operands.iter().zip(&ins.values)
+        .find(|x| matches!(*x.0, Operand::Rel | Operand::Addr11 | Operand::Addr16))
+        .map(|x| *x.1)
+}
+
+fn source(ins: &Decoded, symbols: &[Symbol]) -> String {
+    let form = match ins.form {
+        Some(f) => f,
+        None => return format!("DB {}", hex(ins.bytes[0] as u32, 2)),
+    };
+    let ops: Vec<String> = form.operands.iter().zip(&ins.values).map(|(kind, &v)| match *kind {
+        Operand::A => String::from("A"),
+        Operand::AB => String::from("AB"),
+        Operand::C => String::from("C"),
+        Operand::Dptr => String::from("DPTR"),
+        Operand::AtDptr => String::from("@DPTR"),
+        Operand::AtADptr => String::from("@A+DPTR"),
+        Operand::AtAPc => String::from("@A+PC"),
+        Operand::Reg(n) => format!("R{}", n),
+        Operand::AtReg(n) => format!("@R{}", n),
+        Operand::Imm => format!("#{}", hex(v as u32, 2)),
+        Operand::Imm16 => format!("#{}", hex(v as u32, 4)),
+        Operand::Direct => direct(v as u8, symbols),
+        Operand::Bit => bit(v as u8, symbols),
+        Operand::NotBit => format!("/{}", bit(v as u8, symbols)),
+        Operand::Rel | Operand::Addr11 | Operand::Addr16 => label(v),
+    }).collect();
+    if ops.is_empty() {
+        form.mnemonic.to_string()
+    } else {
+        format!("{:<6}{}", form.mnemonic, ops.join(", "))
+    }
+}
+
+fn label(addr: u16) -> String {
+    format!("L{:04X}", addr)
+}
+
+/// Formats `value` the way the assembler reads it, e.g. `0FFh`.
+fn hex(value: u32, width: usize) -> String {
+    format!("0{:01$X}h", value, width)
+}
+
+fn named(value: u8, kind: SymbolKind, symbols: &[Symbol]) -> Option<String> {
+    symbols.iter().find(|x| x.kind == kind && x.value == value as u16).map(|x| x.name.clone())
+}
+
+/// Names an SFR address; RAM addresses stay numeric.
+fn direct(addr: u8, symbols: &[Symbol]) -> String {
+    if addr >= 0x80 {
+        if let Some(name) = named(addr, SymbolKind::Sfr, symbols) {
+            return name;
+        }
+    }
+    hex(addr as u32, 2)
+}
+
+/// Names a bit address, as a predefined bit or as `SFR.n`.
+fn bit(addr: u8, symbols: &[Symbol]) -> String {
+    if let Some(name) = named(addr, SymbolKind::Bit, symbols) {
+        return name;
+    }
+    if addr >= 0x80 {
+        if let Some(name) = named(addr & 0xF8, SymbolKind::Sfr, symbols) {
+            return format!("{}.{}", name, addr & 7);
+        }
+    }
+    hex(addr as u32, 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opcode::OPCODES;
+    use {assemble, bit_table, sfr_table};
+
+    /// The 64K code space the records fill.
+    fn image(records: &[ContentRecord]) -> Vec<u8> {
+        let mut image = vec![0; 0x10000];
+        for r in records {
+            let start = r.offset() as usize;
+            image[start..start + r.data().len()].copy_from_slice(&r.data());
+        }
+        image
+    }
+
+    #[test]
+    fn reassembles_to_the_same_bytes() {
+        // Every opcode with the same operand bytes, then a byte that is not one
+        let mut code = Vec::new();
+        for form in OPCODES.iter() {
+            code.push(form.code);
+            code.extend_from_slice(&[0x12, 0x34][..form.len() - 1]);
+        }
+        code.push(0xA5);
+        let records = vec![ContentRecord::new(0x0100, code, 0)];
+        let mut symbols = sfr_table();
+        symbols.append(&mut bit_table());
+        let source = disassemble(&records, &symbols);
+        let reassembled = match assemble(&source) {
+            Ok(r) => r,
+            Err(e) => panic!("{:?}\n{}", e.iter().map(|x| x.describe()).collect::<Vec<_>>(), source),
+        };
+        assert_eq!(image(&reassembled), image(&records));
+    }
+
+    #[test]
+    fn reads_hex_records() {
+        let records = read_hex(":03000000020100FA\n:02000300E4FF18\n:0101000022DC\n:00000001FF\n").unwrap();
+        let records: Vec<(u16, Vec<u8>)> = records.iter().map(|x| (x.offset(), x.data())).collect();
+        assert_eq!(records, [(0, vec![0x02, 0x01, 0x00, 0xE4, 0xFF]), (0x100, vec![0x22])]);
+        // Extended addresses of zero are accepted
+        assert!(read_hex(":020000040000FA\n:00000001FF\n").is_ok());
+    }
+
+    #[test]
+    fn rejects_bad_hex_records() {
+        let error = |text: &str| read_hex(text).unwrap_err();
+        assert_eq!(error(":03000000020100FB\n"), "line 1: bad checksum");
+        assert_eq!(error("\n:04000000020100FA\n"), "line 2: invalid HEX record");
+        assert_eq!(error(":03000000020100\n"), "line 1: invalid HEX record");
+        assert_eq!(error(":020000040001F9\n"), "line 1: addresses above 64K are not supported");
+        assert_eq!(error(":03000000020\u{e9}0FA\n"), "line 1: invalid HEX record");
+    }
+}
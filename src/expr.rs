@@ -0,0 +1,619 @@
+use std::fmt;
+use lexer::{self, Kind};
+
+/// Binary operators, listed from lowest to highest precedence group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Or,
+    Xor,
+    And,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Shl,
+    Shr,
+    /// Comparisons, which give 0FFFFh when true and 0 when false.
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnOp {
+    Neg,
+    Not,
+    High,
+    Low,
+}
+
+/// An operand expression as written in the source.
+///
+/// Expressions are parsed when the line is read and evaluated once the
+/// label table is known, so `Sym` holds the (lowercase) name until then.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Num(i32),
+    Sym(String),
+    Here,
+    Unary(UnOp, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    /// `byte.n`, evaluating to the bit address of bit `n` of `byte`.
+    BitSel(Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Num(i32),
+    Ident(String),
+    Here,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    LParen,
+    RParen,
+    Dot,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+impl BinOp {
+    fn symbol(&self) -> &'static str {
+        match *self {
+            BinOp::Or => "OR",
+            BinOp::Xor => "XOR",
+            BinOp::And => "AND",
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Mod => "MOD",
+            BinOp::Shl => "SHL",
+            BinOp::Shr => "SHR",
+            BinOp::Eq => "=",
+            BinOp::Ne => "<>",
+            BinOp::Lt => "<",
+            BinOp::Le => "<=",
+            BinOp::Gt => ">",
+            BinOp::Ge => ">=",
+        }
+    }
+}
+
+/// Writes the expression back as source, with every nested operation in
+/// parentheses.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Expr::Num(n) => write!(f, "{}", n),
+            Expr::Sym(ref s) => write!(f, "{}", s),
+            Expr::Here => write!(f, "$"),
+            Expr::Unary(UnOp::Neg, ref e) => write!(f, "-{}", Nested(e)),
+            Expr::Unary(UnOp::Not, ref e) => write!(f, "NOT {}", Nested(e)),
+            Expr::Unary(UnOp::High, ref e) => write!(f, "HIGH {}", Nested(e)),
+            Expr::Unary(UnOp::Low, ref e) => write!(f, "LOW {}", Nested(e)),
+            Expr::Binary(op, ref l, ref r) => write!(f, "{} {} {}", Nested(l), op.symbol(), Nested(r)),
+            Expr::BitSel(ref byte, ref bit) => write!(f, "{}.{}", Nested(byte), Nested(bit)),
+        }
+    }
+}
+
+/// An operand of another operation, bracketed unless it is a single term.
+struct Nested<'a>(&'a Expr);
+
+impl<'a> fmt::Display for Nested<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self.0 {
+            Expr::Num(_) | Expr::Sym(_) | Expr::Here => write!(f, "{}", self.0),
+            ref e => write!(f, "({})", e),
+        }
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match *self {
+            Token::Num(n) => return write!(f, "'{}'", n),
+            Token::Ident(ref s) => return write!(f, "'{}'", s),
+            Token::Here => "$",
+            Token::Plus => "+",
+            Token::Minus => "-",
+            Token::Star => "*",
+            Token::Slash => "/",
+            Token::LParen => "(",
+            Token::RParen => ")",
+            Token::Dot => ".",
+            Token::Equal => "=",
+            Token::NotEqual => "<>",
+            Token::Less => "<",
+            Token::LessEqual => "<=",
+            Token::Greater => ">",
+            Token::GreaterEqual => ">=",
+        };
+        write!(f, "'{}'", text)
+    }
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, String> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.or_expr()?;
+        if parser.pos != parser.tokens.len() {
+            return Err(format!("Unexpected {} in expression: {}", parser.tokens[parser.pos], text));
+        }
+        Ok(expr)
+    }
+
+    /// Returns the value of a plain number, or `None` if the expression
+    /// needs the label table or location counter to be evaluated.
+    pub fn constant(&self) -> Option<i32> {
+        if self.is_constant() {
+            self.eval(&|_: &str| Err(String::new()), 0).ok()
+        } else {
+            None
+        }
+    }
+
+    /// Appends the names of all symbols used in the expression to `names`.
+    pub fn symbols(&self, names: &mut Vec<String>) {
+        match *self {
+            Expr::Num(_) | Expr::Here => {},
+            Expr::Sym(ref s) => names.push(s.clone()),
+            Expr::Unary(_, ref e) => e.symbols(names),
+            Expr::Binary(_, ref l, ref r) | Expr::BitSel(ref l, ref r) => {
+                l.symbols(names);
+                r.symbols(names);
+            },
+        }
+    }
+
+    fn is_constant(&self) -> bool {
+        match *self {
+            Expr::Num(_) => true,
+            Expr::Sym(_) | Expr::Here => false,
+            Expr::Unary(_, ref e) => e.is_constant(),
+            Expr::Binary(_, ref l, ref r) | Expr::BitSel(ref l, ref r) => l.is_constant() && r.is_constant(),
+        }
+    }
+
+    /// Evaluates the expression. `lookup` resolves a symbol name and `here`
+    /// is the address of the instruction the expression belongs to.
+    pub fn eval<F>(&self, lookup: &F, here: u16) -> Result<i32, String>
+    where F: Fn(&str) -> Result<i32, String> {
+        match *self {
+            Expr::Num(n) => Ok(n),
+            Expr::Sym(ref s) => lookup(s),
+            Expr::Here => Ok(here as i32),
+            Expr::Unary(op, ref e) => {
+                let v = e.eval(lookup, here)?;
+                Ok(match op {
+                    UnOp::Neg => v.wrapping_neg(),
+                    UnOp::Not => !v & 0xFFFF,
+                    UnOp::High => (v >> 8) & 0xFF,
+                    UnOp::Low => v & 0xFF,
+                })
+            },
+            Expr::Binary(op, ref l, ref r) => {
+                let a = l.eval(lookup, here)?;
+                let b = r.eval(lookup, here)?;
+                match op {
+                    BinOp::Or => Ok(a | b),
+                    BinOp::Xor => Ok(a ^ b),
+                    BinOp::And => Ok(a & b),
+                    BinOp::Add => Ok(a.wrapping_add(b)),
+                    BinOp::Sub => Ok(a.wrapping_sub(b)),
+                    BinOp::Mul => Ok(a.wrapping_mul(b)),
+                    BinOp::Div | BinOp::Mod if b == 0 => Err(String::from("Division by zero in expression")),
+                    BinOp::Div => Ok(a.wrapping_div(b)),
+                    BinOp::Mod => Ok(a.wrapping_rem(b)),
+                    BinOp::Shl | BinOp::Shr if b < 0 => Err(format!("Negative shift count in expression: {}", b)),
+                    BinOp::Shl => Ok(if b > 31 { 0 } else { a << b }),
+                    BinOp::Shr => Ok(if b > 31 { 0 } else { a >> b }),
+                    BinOp::Eq => Ok(truth(a == b)),
+                    BinOp::Ne => Ok(truth(a != b)),
+                    BinOp::Lt => Ok(truth(a < b)),
+                    BinOp::Le => Ok(truth(a <= b)),
+                    BinOp::Gt => Ok(truth(a > b)),
+                    BinOp::Ge => Ok(truth(a >= b)),
+                }
+            },
+            Expr::BitSel(ref byte, ref bit) => {
+                let byte = byte.eval(lookup, here)?;
+                let bit = bit.eval(lookup, here)?;
+                bit_address(byte, bit)
+            },
+        }
+    }
+}
+
+/// Maps bit `bit` of the byte at `byte` to its 8051 bit address. Only the
+/// RAM bytes 20h-2Fh and the SFRs whose address ends in 0 or 8 are bit
+/// addressable.
+pub fn bit_address(byte: i32, bit: i32) -> Result<i32, String> {
+    if !(0..=7).contains(&bit) {
+        return Err(format!("Bit number {} out of range 0-7", bit));
+    }
+    match byte {
+        0x20..=0x2F => Ok((byte - 0x20) * 8 + bit),
+        0x80..=0xFF if byte % 8 == 0 => Ok(byte + bit),
+        _ => Err(format!("Address {:#04X} is not bit addressable", byte)),
+    }
+}
+
+fn truth(value: bool) -> i32 {
+    if value { 0xFFFF } else { 0 }
+}
+
+/// Parses a numeric literal: decimal with an optional `d` suffix, hex with
+/// an `h` suffix, `0x` or `$` prefix, binary with a `b` suffix or `0b`
+/// prefix, or octal with an `o` or `q` suffix. Underscores may separate
+/// digits. Hex with a suffix must start with a digit, so `0FFh` is a number
+/// and `FFh` a symbol.
+pub fn parse_number(text: &str) -> Option<i32> {
+    let lower = text.to_lowercase().replace('_', "");
+    let (digits, radix) = if let Some(hex) = lower.strip_prefix("0x").or_else(|| lower.strip_prefix('$')) {
+        (hex, 16)
+    } else if let Some(hex) = lower.strip_suffix('h') {
+        (hex, 16)
+    } else if let Some(bin) = lower.strip_prefix("0b").filter(|x| !x.is_empty()) {
+        (bin, 2)
+    } else if let Some(bin) = lower.strip_suffix('b') {
+        (bin, 2)
+    } else if let Some(oct) = lower.strip_suffix('o').or_else(|| lower.strip_suffix('q')) {
+        (oct, 8)
+    } else if let Some(dec) = lower.strip_suffix('d') {
+        (dec, 10)
+    } else {
+        (&lower[..], 10)
+    };
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+    i32::from_str_radix(digits, radix).ok()
+}
+
+/// The value of a character constant such as `'A'`: the characters as the
+/// bytes of a number, first character highest.
+fn char_value(text: &str) -> Result<i32, String> {
+    let bytes = string(text)?;
+    match bytes.len() {
+        0 => Err(format!("Empty character constant {}", text)),
+        1 | 2 => Ok(bytes.iter().fold(0, |v, &c| v << 8 | c as i32)),
+        _ => Err(format!("Character constant {} is longer than 2 characters", text)),
+    }
+}
+
+/// Parses quoted text. Inside the quotes a doubled quote stands for itself
+/// and a backslash starts one of the escapes `\n`, `\r`, `\t`, `\0`, `\\`,
+/// `\'`, `\"` or `\xHH`.
+pub fn string(op: &str) -> Result<Vec<u8>, String> {
+    let quote = op.chars().next().unwrap_or('"');
+    let mut chars = op[1..].chars().peekable();
+    let mut bytes = Vec::new();
+    loop {
+        let c = match chars.next() {
+            Some(c) => c,
+            None => return Err(format!("Unterminated string: {}", op)),
+        };
+        let byte = match c {
+            '\\' => match chars.next() {
+                Some('n') => b'\n',
+                Some('r') => b'\r',
+                Some('t') => b'\t',
+                Some('0') => 0,
+                Some(c @ '\\') | Some(c @ '\'') | Some(c @ '"') => c as u8,
+                Some('x') => {
+                    let hex: String = (0..2).filter_map(|_| chars.next_if(|c| c.is_ascii_hexdigit())).collect();
+                    u8::from_str_radix(&hex, 16).map_err(|_| format!("Invalid escape \\x in {}", op))?
+                },
+                Some(c) => return Err(format!("Invalid escape \\{} in {}", c, op)),
+                None => return Err(format!("Unterminated string: {}", op)),
+            },
+            c if c == quote && chars.peek() == Some(&quote) => {
+                chars.next();
+                c as u8
+            },
+            c if c == quote => break,
+            c if c.is_ascii() => c as u8,
+            c => return Err(format!("Character {} is not ASCII", c)),
+        };
+        bytes.push(byte);
+    }
+    if chars.next().is_some() {
+        return Err(format!("Unexpected text after string: {}", op));
+    }
+    Ok(bytes)
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    for tok in lexer::tokenize(text).map_err(|e| e.1)? {
+        let t = match (tok.kind, tok.text.as_ref()) {
+            (Kind::Number, word) => match parse_number(word) {
+                Some(n) => Token::Num(n),
+                None => return Err(format!("Invalid number: {}", word)),
+            },
+            (Kind::Ident, word) => Token::Ident(word.to_lowercase()),
+            (Kind::Char, text) | (Kind::Str, text) => Token::Num(char_value(text)?),
+            (Kind::Operator, "+") => Token::Plus,
+            (Kind::Operator, "-") => Token::Minus,
+            (Kind::Operator, "*") => Token::Star,
+            (Kind::Operator, "/") => Token::Slash,
+            (Kind::Operator, "(") => Token::LParen,
+            (Kind::Operator, ")") => Token::RParen,
+            (Kind::Operator, "$") => Token::Here,
+            (Kind::Operator, ".") => Token::Dot,
+            (Kind::Operator, "=") => Token::Equal,
+            (Kind::Operator, "<>") => Token::NotEqual,
+            (Kind::Operator, "<") => Token::Less,
+            (Kind::Operator, "<=") => Token::LessEqual,
+            (Kind::Operator, ">") => Token::Greater,
+            (Kind::Operator, ">=") => Token::GreaterEqual,
+            (_, t) => return Err(format!("Unexpected '{}' in expression: {}", t, text)),
+        };
+        tokens.push(t);
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Ident(s)) => s.eq_ignore_ascii_case(keyword),
+            _ => false,
+        }
+    }
+
+    fn expect(&mut self, tok: Token) -> Result<(), String> {
+        if self.peek() == Some(&tok) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("Expected {} in expression", tok))
+        }
+    }
+
+    // OR, XOR
+    fn or_expr(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.and_expr()?;
+        loop {
+            let op = if self.peek_keyword("or") {
+                BinOp::Or
+            } else if self.peek_keyword("xor") {
+                BinOp::Xor
+            } else {
+                return Ok(lhs);
+            };
+            self.pos += 1;
+            let rhs = self.and_expr()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    // AND
+    fn and_expr(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.not_expr()?;
+        while self.peek_keyword("and") {
+            self.pos += 1;
+            let rhs = self.not_expr()?;
+            lhs = Expr::Binary(BinOp::And, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    // NOT
+    fn not_expr(&mut self) -> Result<Expr, String> {
+        if self.peek_keyword("not") {
+            self.pos += 1;
+            let e = self.not_expr()?;
+            return Ok(Expr::Unary(UnOp::Not, Box::new(e)));
+        }
+        self.rel_expr()
+    }
+
+    // EQ NE LT LE GT GE, = <> < <= > >=
+    fn rel_expr(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.add_expr()?;
+        loop {
+            let op = match self.peek() {
+                Some(&Token::Equal) => BinOp::Eq,
+                Some(&Token::NotEqual) => BinOp::Ne,
+                Some(&Token::Less) => BinOp::Lt,
+                Some(&Token::LessEqual) => BinOp::Le,
+                Some(&Token::Greater) => BinOp::Gt,
+                Some(&Token::GreaterEqual) => BinOp::Ge,
+                _ if self.peek_keyword("eq") => BinOp::Eq,
+                _ if self.peek_keyword("ne") => BinOp::Ne,
+                _ if self.peek_keyword("lt") => BinOp::Lt,
+                _ if self.peek_keyword("le") => BinOp::Le,
+                _ if self.peek_keyword("gt") => BinOp::Gt,
+                _ if self.peek_keyword("ge") => BinOp::Ge,
+                _ => return Ok(lhs),
+            };
+            self.pos += 1;
+            let rhs = self.add_expr()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    // + -
+    fn add_expr(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.mul_expr()?;
+        loop {
+            let op = match self.peek() {
+                Some(&Token::Plus) => BinOp::Add,
+                Some(&Token::Minus) => BinOp::Sub,
+                _ => return Ok(lhs),
+            };
+            self.pos += 1;
+            let rhs = self.mul_expr()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    // * / MOD SHL SHR
+    fn mul_expr(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.unary_expr()?;
+        loop {
+            let op = match self.peek() {
+                Some(&Token::Star) => BinOp::Mul,
+                Some(&Token::Slash) => BinOp::Div,
+                _ if self.peek_keyword("mod") => BinOp::Mod,
+                _ if self.peek_keyword("shl") => BinOp::Shl,
+                _ if self.peek_keyword("shr") => BinOp::Shr,
+                _ => return Ok(lhs),
+            };
+            self.pos += 1;
+            let rhs = self.unary_expr()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    // unary + -, HIGH, LOW
+    fn unary_expr(&mut self) -> Result<Expr, String> {
+        let op = match self.peek() {
+            Some(&Token::Minus) => Some(UnOp::Neg),
+            Some(&Token::Plus) => {
+                self.pos += 1;
+                return self.unary_expr();
+            },
+            _ if self.peek_keyword("high") => Some(UnOp::High),
+            _ if self.peek_keyword("low") => Some(UnOp::Low),
+            _ => None,
+        };
+        match op {
+            Some(op) => {
+                self.pos += 1;
+                let e = self.unary_expr()?;
+                Ok(Expr::Unary(op, Box::new(e)))
+            },
+            None => self.bit_expr(),
+        }
+    }
+
+    // byte.n
+    fn bit_expr(&mut self) -> Result<Expr, String> {
+        let mut byte = self.primary()?;
+        while self.peek() == Some(&Token::Dot) {
+            self.pos += 1;
+            let bit = self.primary()?;
+            byte = Expr::BitSel(Box::new(byte), Box::new(bit));
+        }
+        Ok(byte)
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        let tok = match self.peek() {
+            Some(t) => t.clone(),
+            None => return Err(String::from("Unexpected end of expression")),
+        };
+        self.pos += 1;
+        match tok {
+            Token::Num(n) => Ok(Expr::Num(n)),
+            Token::Here => Ok(Expr::Here),
+            Token::Ident(s) => Ok(Expr::Sym(s)),
+            Token::LParen => {
+                let e = self.or_expr()?;
+                self.expect(Token::RParen)?;
+                Ok(e)
+            },
+            t => Err(format!("Unexpected {} in expression", t)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assemble;
+
+    fn value(text: &str) -> Result<i32, String> {
+        Expr::parse(text)?.eval(&|s: &str| Err(format!("Unknown symbol {}", s)), 0x100)
+    }
+
+    #[test]
+    fn follows_operator_precedence() {
+        let cases: &[(&str, i32)] = &[
+            ("1 + 2 * 3", 7),
+            ("2 * 3 + 1", 7),
+            ("(1 + 2) * 3", 9),
+            ("10 - 4 - 3", 3),
+            ("1 SHL 2 + 1", 5),
+            ("17 MOD 5 * 2", 4),
+            ("-2 * 3", -6),
+            ("HIGH 1234h + 1", 0x13),
+            ("LOW (1234h + 1)", 0x35),
+            ("1 + 1 = 2", 0xFFFF),
+            ("1 < 2 AND 3 > 4", 0),
+            ("NOT 0 AND 0FFh", 0xFF),
+            ("1 OR 2 AND 3", 3),
+            ("6 XOR 3 OR 8", 13),
+            ("20h.3 + 1", 4),
+            ("$ + 2", 0x102),
+        ];
+        for &(text, expected) in cases {
+            assert_eq!(value(text), Ok(expected), "{}", text);
+        }
+    }
+
+    #[test]
+    fn rejects_negative_shift_counts() {
+        assert!(value("1 SHL -1").is_err());
+        assert!(value("1 SHR -1").is_err());
+        assert_eq!(value("1 SHL 40"), Ok(0));
+    }
+
+    #[test]
+    fn division_does_not_overflow() {
+        assert_eq!(value("(-7FFFFFFFh - 1) / -1"), Ok(i32::MIN));
+        assert_eq!(value("(-7FFFFFFFh - 1) MOD -1"), Ok(0));
+        assert!(value("1 / 0").is_err());
+        assert!(value("1 MOD 0").is_err());
+    }
+
+    #[test]
+    fn errors_show_tokens_as_written() {
+        assert_eq!(Expr::parse("1 2").unwrap_err(), "Unexpected '2' in expression: 1 2");
+        assert_eq!(Expr::parse("(1").unwrap_err(), "Expected ')' in expression");
+        assert_eq!(Expr::parse("1 + HIGH (x - 2)").unwrap().to_string(), "1 + (HIGH (x - 2))");
+    }
+
+    #[test]
+    fn maps_bits_to_bit_addresses() {
+        let symbols = |s: &str| match s {
+            "acc" => Ok(0xE0),
+            "sp" => Ok(0x81),
+            _ => Err(format!("Unknown symbol {}", s)),
+        };
+        let bit = |text: &str| Expr::parse(text)?.eval(&symbols, 0);
+        assert_eq!(bit("ACC.7"), Ok(0xE7));
+        assert_eq!(bit("20h.3"), Ok(0x03));
+        assert_eq!(bit("SP.1"), Err(String::from("Address 0x81 is not bit addressable")));
+        assert_eq!(bit("2Fh.8"), Err(String::from("Bit number 8 out of range 0-7")));
+    }
+
+    #[test]
+    fn resolves_bit_symbols() {
+        let records = assemble("ready bit 20h.3\n setb ready\n clr tr0\n jb acc.7, $\n").unwrap();
+        assert_eq!(records[0].data(), vec![0xD2, 0x03, 0xC2, 0x8C, 0x20, 0xE7, 0xFD]);
+    }
+}
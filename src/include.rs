@@ -0,0 +1,260 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use diagnostic::Diagnostic;
+
+/// Where a line of the expanded source came from.
+#[derive(Debug, Clone)]
+pub struct Origin {
+    pub file: String,
+    pub line: u64,
+    /// The `INCLUDE` lines that brought the file in, innermost first.
+    pub chain: Vec<(String, u64)>,
+}
+
+/// The source with every include file expanded in place, and the origin of
+/// each of its lines.
+pub struct Source {
+    pub text: String,
+    pub origins: Vec<Origin>,
+}
+
+/// Reads `path` and expands its `INCLUDE "file"` and `$INCLUDE(file)`
+/// lines. Include files are looked for next to the including file and then
+/// in each of `dirs`. Errors are reported against the expanded source,
+/// whose lines `locate` maps back to their files.
+pub fn expand(path: &Path, dirs: &[PathBuf]) -> Result<(Source, Vec<Diagnostic>), String> {
+    let text = read(path).map_err(|why| format!("couldn't read {}: {}", path.display(), why))?;
+    let mut source = Source { text: String::new(), origins: Vec::new() };
+    let mut errors = Vec::new();
+    let mut stack = vec![(path.to_path_buf(), 0)];
+    expand_text(&text, &mut stack, dirs, &mut source, &mut errors);
+    Ok((source, errors))
+}
+
+fn read(path: &Path) -> ::std::io::Result<String> {
+    let mut text = String::new();
+    File::open(path)?.read_to_string(&mut text)?;
+    Ok(text)
+}
+
+/// Appends `text`, the file on top of `stack`, to `source`. Each stack
+/// entry is a file being expanded and the line of it being read.
+fn expand_text(text: &str, stack: &mut Vec<(PathBuf, u64)>, dirs: &[PathBuf], source: &mut Source, errors: &mut Vec<Diagnostic>) {
+    for (i, line) in text.lines().enumerate() {
+        let num = i as u64 + 1;
+        stack.last_mut().unwrap().1 = num;
+        let name = match directive(line) {
+            Some(n) => n,
+            None => {
+                push_line(source, stack, line);
+                continue;
+            },
+        };
+
+        // The directive itself stays in the listing, commented out
+        push_line(source, stack, &format!(";{}", line));
+        let here = source.origins.len() as u64;
+        let file = match find(&name, &stack.last().unwrap().0, dirs) {
+            Some(f) => f,
+            None => {
+                errors.push(Diagnostic::error(here, format!("include file {} not found", name)));
+                continue;
+            },
+        };
+        if let Some(pos) = stack.iter().position(|x| same_file(&x.0, &file)) {
+            let mut cycle: Vec<String> = stack[pos..].iter().map(|x| x.0.display().to_string()).collect();
+            cycle.push(file.display().to_string());
+            errors.push(Diagnostic::error(here, format!("include cycle: {}", cycle.join(" -> "))));
+            continue;
+        }
+        match read(&file) {
+            Ok(inner) => {
+                stack.push((file, 0));
+                expand_text(&inner, stack, dirs, source, errors);
+                stack.pop();
+            },
+            Err(why) => errors.push(Diagnostic::error(here, format!("couldn't read {}: {}", file.display(), why))),
+        }
+    }
+}
+
+fn push_line(source: &mut Source, stack: &[(PathBuf, u64)], line: &str) {
+    let (ref file, num) = stack[stack.len() - 1];
+    source.text += line;
+    source.text += "\n";
+    source.origins.push(Origin {
+        file: file.display().to_string(),
+        line: num,
+        chain: stack[..stack.len() - 1].iter().rev().map(|x| (x.0.display().to_string(), x.1)).collect(),
+    });
+}
+
+/// The file named by an include directive on `line`, if it is one.
+fn directive(line: &str) -> Option<String> {
+    let code = line.split(';').next().unwrap_or("").trim();
+    let name = if let Some(rest) = keyword(code, "$include") {
+        rest.trim().strip_prefix('(')?.strip_suffix(')')?
+    } else {
+        let rest = keyword(code, "include")?;
+        if !rest.starts_with(|c: char| c.is_whitespace() || c == '"') {
+            return None;
+        }
+        rest
+    };
+    let name = name.trim();
+    let name = name.strip_prefix('"').and_then(|x| x.strip_suffix('"')).unwrap_or(name);
+    if name.is_empty() {
+        None
+    } else {
+        Some(name.to_string())
+    }
+}
+
+/// The rest of `code` if it starts with `word`, in any case.
+fn keyword<'a>(code: &'a str, word: &str) -> Option<&'a str> {
+    match code.get(..word.len()) {
+        Some(w) if w.eq_ignore_ascii_case(word) => Some(&code[word.len()..]),
+        _ => None,
+    }
+}
+
+/// Looks for an include file next to the file including it, then in the
+/// search path.
+fn find(name: &str, from: &Path, dirs: &[PathBuf]) -> Option<PathBuf> {
+    let local = from.parent().map(|x| x.join(name)).unwrap_or_else(|| PathBuf::from(name));
+    let mut candidates = vec![local];
+    candidates.extend(dirs.iter().map(|x| x.join(name)));
+    candidates.into_iter().find(|x| x.is_file())
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// The line of its own file that `line` of the expanded source came from.
+pub fn line_number(origins: &[Origin], line: u64) -> u64 {
+    origin(origins, line).map(|x| x.line).unwrap_or(line)
+}
+
+/// Names where `line` of the expanded source came from for tables such as
+/// the map: `12` in the main file, `defs.inc:12` in an include file.
+pub fn source_line(origins: &[Origin], line: u64) -> String {
+    match origin(origins, line) {
+        Some(o) if !o.chain.is_empty() => format!("{}:{}", o.file, o.line),
+        _ => line_number(origins, line).to_string(),
+    }
+}
+
+/// Names the range of lines from `first` to `last` of the expanded source,
+/// as `3-5` or `defs.inc:3-5`, giving the file once if both are in it.
+pub fn source_lines(origins: &[Origin], first: u64, last: u64) -> String {
+    let (a, b) = (source_line(origins, first), source_line(origins, last));
+    match (origin(origins, first), origin(origins, last)) {
+        _ if first == last => a,
+        (Some(x), Some(y)) if x.file == y.file && x.chain == y.chain => format!("{}-{}", a, y.line),
+        _ => format!("{}-{}", a, b),
+    }
+}
+
+/// Names where `line` of the expanded source came from in a message:
+/// `line 12`, or `line 12 of defs.inc` in an include file.
+pub fn position(origins: &[Origin], line: u64) -> String {
+    match origin(origins, line) {
+        Some(o) if !o.chain.is_empty() => format!("line {} of {}", o.line, o.file),
+        _ => format!("line {}", line_number(origins, line)),
+    }
+}
+
+fn origin(origins: &[Origin], line: u64) -> Option<&Origin> {
+    line.checked_sub(1).and_then(|i| origins.get(i as usize))
+}
+
+/// Moves each diagnostic from its line in the expanded source to the file
+/// and line it came from.
+pub fn locate(diagnostics: &mut [Diagnostic], origins: &[Origin]) {
+    for d in diagnostics.iter_mut().filter(|x| x.line > 0) {
+        if let Some(origin) = origins.get(d.line as usize - 1) {
+            d.file = origin.file.clone();
+            d.line = origin.line;
+            d.included_from = origin.chain.clone();
+        }
+        for call in d.expanded_from.iter_mut() {
+            if let Some(origin) = call.2.checked_sub(1).and_then(|i| origins.get(i as usize)) {
+                call.1 = origin.file.clone();
+                call.2 = origin.line;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Writes `files` to a new directory and expands the first of them.
+    fn expand_files(test: &str, files: &[(&str, &str)]) -> (Source, Vec<Diagnostic>) {
+        let dir = ::std::env::temp_dir().join(format!("asm622-{}-{}", test, ::std::process::id()));
+        for &(name, text) in files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, text).unwrap();
+        }
+        let expanded = expand(&dir.join(files[0].0), &[]).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        expanded
+    }
+
+    #[test]
+    fn expands_nested_includes() {
+        let (source, errors) = expand_files("nested", &[
+            ("main.asm", " nop\n include \"inc/a.inc\"\n ret\n"),
+            ("inc/a.inc", " inc a\n$include(b.inc)\n"),
+            ("inc/b.inc", " dec a\n"),
+        ]);
+        assert!(errors.is_empty());
+        let lines: Vec<&str> = source.text.lines().collect();
+        assert_eq!(lines, vec![" nop", "; include \"inc/a.inc\"", " inc a", ";$include(b.inc)", " dec a", " ret"]);
+        let b = &source.origins[4];
+        assert!(b.file.ends_with("b.inc"));
+        assert_eq!(b.line, 1);
+        assert_eq!(b.chain.iter().map(|x| x.1).collect::<Vec<_>>(), vec![2, 2]);
+        assert_eq!(source.origins[5].line, 3);
+    }
+
+    #[test]
+    fn reports_include_cycles() {
+        let (_, errors) = expand_files("cycle", &[
+            ("main.asm", " include a.inc\n"),
+            ("a.inc", " include b.inc\n"),
+            ("b.inc", " include a.inc\n"),
+        ]);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].message.starts_with("include cycle"), "{}", errors[0].message);
+        assert_eq!(errors[0].line, 3);
+    }
+
+    fn origins() -> Vec<Origin> {
+        let main = |line| Origin { file: String::from("main.asm"), line, chain: Vec::new() };
+        let inc = |line| Origin { file: String::from("defs.inc"), line, chain: vec![(String::from("main.asm"), 2)] };
+        vec![main(1), main(2), inc(1), inc(2), main(3)]
+    }
+
+    #[test]
+    fn names_lines_in_their_own_file() {
+        let origins = origins();
+        assert_eq!(line_number(&origins, 4), 2);
+        assert_eq!(line_number(&origins, 5), 3);
+        assert_eq!(source_line(&origins, 5), "3");
+        assert_eq!(source_line(&origins, 4), "defs.inc:2");
+        assert_eq!(source_lines(&origins, 3, 4), "defs.inc:1-2");
+        assert_eq!(source_lines(&origins, 4, 5), "defs.inc:2-3");
+        assert_eq!(position(&origins, 3), "line 1 of defs.inc");
+        assert_eq!(position(&origins, 2), "line 2");
+    }
+}
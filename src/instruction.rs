@@ -1,7 +1,13 @@
+use std::fmt;
 use line::Line;
+use expr::{self, Expr};
+use lexer::{self, Kind};
+use symbol::{self, Definition, Symbol, SymbolKind};
+use opcode::{self, Opcode, Operand};
+use section::Space;
+use cond::Known;
 use self::Mnemonic::*;
 use self::OpType::*;
-use std::ops::Index;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 enum OpType {
@@ -14,19 +20,58 @@ enum OpType {
     R5,
     R6,
     R7,
-    Data(u8),
-    Data16(u16),
-    Addr(u8),
-    Addr16(u16),
+    /// An immediate value, whose width is set by the form it is used in.
+    Data(i32),
+    /// An address, whose width is set by the form it is used in.
+    Addr(i32),
     AtR0,
     AtR1,
     C,
     Label(String),
+    DataExpr(Expr),
+    AddrExpr(Expr),
+    /// `/bit`
+    NotBit(u8),
+    NotBitExpr(Expr),
     Dptr,
     AtDptr,
     AB,
     AtADptr,
     AtAPc,
+    /// Quoted text in a data list.
+    Str(Vec<u8>),
+}
+
+impl fmt::Display for OpType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            A => write!(f, "A"),
+            R0 => write!(f, "R0"),
+            R1 => write!(f, "R1"),
+            R2 => write!(f, "R2"),
+            R3 => write!(f, "R3"),
+            R4 => write!(f, "R4"),
+            R5 => write!(f, "R5"),
+            R6 => write!(f, "R6"),
+            R7 => write!(f, "R7"),
+            Data(d) => write!(f, "#{}", d),
+            Addr(a) => write!(f, "{}", hex(a)),
+            AtR0 => write!(f, "@R0"),
+            AtR1 => write!(f, "@R1"),
+            C => write!(f, "C"),
+            Label(ref s) => write!(f, "{}", s),
+            DataExpr(ref e) => write!(f, "#{}", e),
+            AddrExpr(ref e) => write!(f, "{}", e),
+            NotBit(b) => write!(f, "/{}", hex(b as i32)),
+            NotBitExpr(ref e) => write!(f, "/{}", e),
+            Dptr => write!(f, "DPTR"),
+            AtDptr => write!(f, "@DPTR"),
+            AB => write!(f, "AB"),
+            AtADptr => write!(f, "@A+DPTR"),
+            AtAPc => write!(f, "@A+PC"),
+            Str(ref s) => write!(f, "\"{}\"", String::from_utf8_lossy(s)),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -42,8 +87,13 @@ enum Mnemonic {
     Mul,
     Cpl,
     Db,
+    Dw,
+    Dd,
     Cseg,
     Dseg,
+    Iseg,
+    Bseg,
+    Xseg,
     Org,
     Nop,
     Ajmp,
@@ -79,113 +129,190 @@ enum Mnemonic {
     Clr,
     Sjmp,
     Ds,
+    Dbit,
     Movc,
+    Call,
+    Define(SymbolKind),
+}
+
+impl Mnemonic {
+    /// The mnemonic as it appears in the opcode table.
+    fn name(&self) -> String {
+        format!("{:?}", self).to_uppercase()
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct Instruction {
     offset:u16 ,
+    /// Address of the section the instruction belongs to.
+    base: u16,
     num: u64,
     pub label: Option<String>,
     mnemonic: Option<Mnemonic>,
     ops: Vec<OpType>,
+    /// Generic `JMP`/`CALL`, whose short or long form is picked by the layout.
+    generic: bool,
+    /// For a relaxed conditional branch, the AJMP or LJMP that reaches the
+    /// target in place of the relative jump.
+    far: Option<Mnemonic>,
+    /// Text of a line produced by a macro, for the listing.
+    expansion: Option<String>,
+    /// The expansions that produced the line, see `Line::expanded_from`.
+    expanded_from: Vec<(String, u64)>,
+    /// Column of the mnemonic and of each operand, where the line was read
+    /// from the source.
+    column: Option<usize>,
+    columns: Vec<usize>,
 }
 
 impl Instruction {
+    /// Encodes the displacement of a relative branch, which is counted from
+    /// the end of the instruction and must fit in a signed byte.
     fn short_jmp(&self, addr: u16) -> Result<u8, String> {
-        // println!("{:x}", addr+0x7d );
-        // println!("{:x}", self.offset+self.len() as u16);
-        // println!("{:x}", (addr as i32-(self.offset as i32+self.len() as i32)));
-        if addr as u64+0x7F <  self.offset as u64+self.len() as u64{
-            return Err(format!("Address out of range at {}",self.num));
+        let end = self.pc() as i32 + self.short_len();
+        let distance = addr as i32 - end;
+        if !(-0x80..=0x7F).contains(&distance) {
+            let hint = if self.is_branch() { " (use -r to relax it)" } else { "" };
+            return Err(format!("Branch target {:04X} is {:+} bytes from the end of the instruction, \
+                outside the range -128 to +127{}", addr, distance, hint));
+        }
+        Ok(distance as u8)
+    }
 
+    /// Returns the top three bits of the 11-bit AJMP/ACALL target, which go
+    /// in the opcode. The target must be in the same 2K page as PC+2.
+    fn page_bits(&self, addr: u16) -> Result<u8, String> {
+        let next = self.pc().wrapping_add(2);
+        let page = next & 0xF800;
+        if addr & 0xF800 != page {
+            return Err(format!("Target {:04X} is outside the 2K page of PC+2 ({:04X}-{:04X})",
+                addr, page, page | 0x07FF));
         }
-        Ok(((addr as u16+0x100-(self.offset as u16+self.len() as u16))%0x100)as u8)
-    }
-
-    pub fn to_hex(&self) -> Result<Vec<u8>, String> {
-        match &self.mnemonic {
-            &Some(Div) => self.div(),
-            &Some(Mov) => self.mov(),
-            &Some(Movc) => self.movc(),
-            &Some(Movx) => self.movx(),
-            &Some(Inc) => self.inc(),
-            &Some(Dec) => self.dec(),
-            &Some(Cpl) => self.cpl(),
-            &Some(Cjne) => self.cjne(),
-            &Some(Pop) => self.pop(),
-            &Some(Push) => self.push(),
-            &Some(Add) => self.add(),
-            &Some(Addc) => self.addc(),
-            &Some(Orl) => self.orl(),
-            &Some(Anl) => self.anl(),
-            &Some(Subb) => self.subb(),
-            &Some(Xch) => self.xch(),
-            &Some(Xrl) => self.xrl(),
-            &Some(Jb) => self.jb(),
-            &Some(Ret) => self.ret(),
-            &Some(Clr) => self.clr(),
-            &Some(Setb) => self.setb(),
-            &Some(Nop) => self.nop(),
-            &Some(Djnz) => self.djnz(),
-            &Some(Ajmp) => self.ajmp(),
-            &Some(Ljmp)=> self.ljmp(),
-            &Some(Acall) => self.acall(),
-            &Some(Lcall)=> self.lcall(),
-            &Some(Rr) => self.rr(),
-            &Some(Rrc) => self.rrc(),
-            &Some(Rl) => self.rl(),
-            &Some(Reti) => self.reti(),
-            &Some(Rlc) => self.rlc(),
-            &Some(Jc) => self.jc(),
-
-            &Some(Jnc)=> self.jnc(),
-            &Some(Jz) => self.jz(),
-            &Some(Jnz) => self.jnz(),
-            &Some(Jmp) => self.jmp(),
-            &Some(Mul) => self.mul(),
-            &Some(Swap) => self.swap(),
-            &Some(Da) => self.da(),
-            &Some(Jnb) => self.jnb(),
-            &Some(Jbc) => self.jbc(),
-            &Some(Xchd) => self.xchd(),
-            &Some(Org) => Ok(vec![]),
-            &Some(Cseg)=> Ok(vec![]),
-            &Some(Dseg) => Ok(vec![]),
-            &Some(Db) => self.db(),
-            &Some(Ds)=> self.ds(),
-            &Some(Sjmp)=>self.sjmp(),
-            &None => Ok(vec![]),
-            //&Some(ref a) => Err(format!("Unimplemented: {:?}", a)),
+        Ok((((addr >> 8) & 0x07) as u8) << 5)
+    }
+
+    /// Address of the instruction.
+    fn pc(&self) -> u16 {
+        self.base.wrapping_add(self.offset)
+    }
+
+    /// Encodes the instruction. Errors give the column of the operand at
+    /// fault, or else of the mnemonic.
+    pub fn to_hex(&self) -> Result<Vec<u8>, (Option<usize>, String)> {
+        if self.far.is_some() {
+            return self.far_branch();
+        }
+        match self.mnemonic {
+            Some(Org) | Some(Cseg) | Some(Dseg) | Some(Iseg) | Some(Bseg) | Some(Xseg) | Some(Dbit)
+            | Some(Define(_)) | None => Ok(vec![]),
+            Some(Db) | Some(Dw) | Some(Dd) => self.data(),
+            Some(Ds) => self.ds().map_err(|e| (self.column(0), e)),
+            Some(_) => self.encode(),
         }
     }
 
-    pub fn is_new_section(&self) -> Option<u16> {
-        let is_new = match self.mnemonic {
-            Some(Org) | Some(Cseg) | Some(Dseg) => true,
-            _ => false,
+    /// Column of operand `i`, or of the mnemonic if that is not known.
+    fn column(&self, i: usize) -> Option<usize> {
+        self.columns.get(i).cloned().or(self.column)
+    }
+
+    /// Encodes a machine instruction using the first form in the opcode
+    /// table that its operands fit.
+    fn encode(&self) -> Result<Vec<u8>, (Option<usize>, String)> {
+        let form = match self.opcode() {
+            Some(f) => f,
+            None => return Err((self.column, self.no_form())),
         };
-        if !is_new{
-            return None;
+        let mut code = form.code;
+        let mut bytes = Vec::new();
+        for (i, (kind, op)) in form.operands.iter().zip(&self.ops).enumerate() {
+            self.operand(*kind, op, &mut code, &mut bytes).map_err(|e| (self.column(i), e))?;
         }
-        if self.ops.len()<1 {
-            return None;
+        if form.reversed() {
+            bytes.reverse();
         }
-        match self.ops[0] {
-            Addr(d) => Some(d as u16),
-            Addr16(d) => Some(d),
-            Label(_) => {
-                match self.ops[1] {
-                    Addr(d) => Some(d as u16),
-                    Addr16(d) => Some(d),
-                    _ =>panic!("ORG, CSEG, or DSEG has invalid addr at line {}",self.num)
-                }
+        bytes.insert(0, code);
+        Ok(bytes)
+    }
+
+    /// Appends the bytes of an operand of the given form, or sets its bits
+    /// in `code`.
+    fn operand(&self, kind: Operand, op: &OpType, code: &mut u8, bytes: &mut Vec<u8>) -> Result<(), String> {
+        match (kind, op) {
+            (Operand::Imm, &Data(d)) => bytes.push(immediate(d, 8)? as u8),
+            (Operand::Imm16, &Data(d)) => {
+                let d = immediate(d, 16)?;
+                bytes.extend_from_slice(&[(d >> 8) as u8, d as u8]);
+            },
+            (Operand::Imm16, &Addr(d)) | (Operand::Addr16, &Addr(d)) => {
+                let d = code_address(d)?;
+                bytes.extend_from_slice(&[(d >> 8) as u8, d as u8]);
+            },
+            (Operand::Direct, &Addr(d)) => bytes.push(data_address(d, "Direct address")?),
+            (Operand::Bit, &Addr(d)) => bytes.push(data_address(d, "Bit address")?),
+            (Operand::NotBit, &NotBit(d)) => bytes.push(d),
+            (Operand::Rel, &Addr(d)) => bytes.push(self.short_jmp(code_address(d)?)?),
+            (Operand::Addr11, &Addr(d)) => {
+                let d = code_address(d)?;
+                *code |= self.page_bits(d)?;
+                bytes.push(d as u8);
             },
-            _ =>panic!("ORG, CSEG, or DSEG has invalid addr at line {}",self.num)
+            (_, &DataExpr(_)) | (_, &AddrExpr(_)) | (_, &NotBitExpr(_)) => {
+                return Err(String::from("Operand has not been resolved"));
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    /// The opcode table entry matching the mnemonic and operands.
+    fn opcode(&self) -> Option<&'static Opcode> {
+        let name = self.mnemonic.as_ref()?.name();
+        let found = opcode::forms(&name).find(|form| {
+            form.operands.len() == self.ops.len()
+                && form.operands.iter().zip(&self.ops).all(|(kind, op)| fits(op, *kind))
+        });
+        found
+    }
+
+    /// Explains why no form of the instruction matches its operands.
+    fn no_form(&self) -> String {
+        let name = self.mnemonic.as_ref().map(|x| x.name()).unwrap_or_default();
+        let counts: Vec<usize> = opcode::forms(&name).map(|x| x.operands.len()).collect();
+        if !counts.is_empty() && counts.iter().all(|&n| n > self.ops.len()) {
+            return format!("Too few arguments for {}", name);
+        }
+        if !counts.is_empty() && counts.iter().all(|&n| n < self.ops.len()) {
+            return format!("Too many arguments for {}", name);
+        }
+        let ops: Vec<String> = self.ops.iter().map(|x| x.to_string()).collect();
+        format!("Invalid operation: {} {}", name, ops.join(","))
+    }
+
+    pub fn is_new_section(&self) -> Result<Option<u16>, String> {
+        let is_new = self.segment().is_some() || matches!(self.mnemonic, Some(Org));
+        if !is_new{
+            return Ok(None);
+        }
+        if self.ops.len()<1 {
+            return Ok(None);
         }
+        let invalid = String::from("ORG or segment directive has invalid address");
+        let addr = match (&self.ops[0], self.ops.get(1)) {
+            (&Addr(d), _) | (&Label(_), Some(&Addr(d))) => d,
+            _ => return Err(invalid),
+        };
+        code_address(addr).map(Some)
     }
 
-    pub fn from_line(line:Line, offset: u16) -> Result<Self, String> {
+    /// Reads a line. Errors give the column of the operand at fault, or else
+    /// of the mnemonic.
+    pub fn from_line(line:Line, offset: u16) -> Result<Self, (Option<usize>, String)> {
+        let column = line.column;
+        let operand_columns = line.columns.clone();
+        let at = |i: usize, e: String| (operand_columns.get(i).cloned().or(column), e);
+        let mut columns = Vec::new();
         let mne;
         // let op1;
         // let op2;
@@ -195,12 +322,17 @@ impl Instruction {
             mne = match line.mnu.unwrap().to_lowercase().as_ref() {
                 "div" => Some(Div),
                 "db" => Some(Db),
+                "dw" => Some(Dw),
+                "dd" => Some(Dd),
                 "mov" => Some(Mnemonic::Mov),
                 "sjmp" => Some(Mnemonic::Sjmp),
                 "add" => Some(Mnemonic::Add),
                 "org" => Some(Mnemonic::Org),
                 "cseg" => Some(Cseg),
                 "dseg" => Some(Dseg),
+                "iseg" => Some(Iseg),
+                "bseg" => Some(Bseg),
+                "xseg" => Some(Xseg),
                 "nop" => Some(Nop),
                 "ajmp" => Some(Ajmp),
                 "ljmp" => Some(Ljmp),
@@ -233,6 +365,7 @@ impl Instruction {
                 "anl" => Some(Anl),
                 "cpl" => Some(Cpl),
                 "ds" => Some(Ds),
+                "dbit" => Some(Dbit),
                 "swap" => Some(Swap),
                 "push" => Some(Push),
                 "pop" => Some(Pop),
@@ -242,8 +375,16 @@ impl Instruction {
                 "setb" => Some(Setb),
                 "movc" => Some(Movc),
                 "jmp" => Some(Jmp),
+                "call" => Some(Call),
+                "equ" => Some(Define(SymbolKind::Number)),
+                "set" => Some(Define(SymbolKind::Set)),
+                "data" => Some(Define(SymbolKind::Data)),
+                "idata" => Some(Define(SymbolKind::Idata)),
+                "xdata" => Some(Define(SymbolKind::Xdata)),
+                "bit" => Some(Define(SymbolKind::Bit)),
+                "code" => Some(Define(SymbolKind::Code)),
                 "end" => None,
-                m @ _ => return Err(format!("unknown mnemonic: line {}: {}",line.num,m)),
+                m => return Err((column, format!("Unknown mnemonic: {}",m))),
             };
         }
         else {
@@ -313,8 +454,34 @@ impl Instruction {
         //     op2 = None;
         // }
 
-        for op in line.ops
+        if let Some(Define(_)) = mne {
+            if line.label.is_none() {
+                return Err((column, String::from("Symbol definition without a name")));
+            }
+            if line.ops.len() != 1 {
+                return Err((column, String::from("Symbol definition takes one value")));
+            }
+            let e = Expr::parse(&line.ops[0]).map_err(|e| at(0, e))?;
+            let columns = line.columns.clone();
+            return Ok(Instruction{offset, base: 0, num: line.num, label: line.label, mnemonic: mne, ops: vec![AddrExpr(e)], generic: false, far: None, expansion: line.expansion, expanded_from: line.expanded_from, column, columns});
+        }
+
+        if matches!(mne, Some(Db) | Some(Dw) | Some(Dd)) {
+            let ops = line.ops.iter().enumerate().map(|(i, x)| data_item(x).map_err(|e| at(i, e))).collect::<Result<_, _>>()?;
+            let columns = line.columns.clone();
+            return Ok(Instruction{offset, base: 0, num: line.num, label: line.label, mnemonic: mne, ops, generic: false, far: None, expansion: line.expansion, expanded_from: line.expanded_from, column, columns});
+        }
+
+        for (i, op) in line.ops.iter().enumerate()
         {
+            let mut op = op.clone();
+            let here = line.columns.get(i).cloned();
+            // `CSEG AT 100h` arrives as a single operand
+            if op.to_lowercase().starts_with("at ") {
+                ops.push(Label("at".to_string()));
+                columns.extend(here);
+                op = op[3..].to_string();
+            }
             let op_tmp= match op.to_lowercase().as_ref() {
                 "@r0" => OpType::AtR0,
                 "@r1" => OpType::AtR1,
@@ -329,1050 +496,1024 @@ impl Instruction {
                 "c" => OpType::C,
                 "a" => OpType::A,
                 "ab" => AB,
-                "b" => Label("B".to_string()),
                 "dptr" => Dptr,
                 "@a+pc" =>AtAPc,
                 "@a+dptr" => AtADptr,
                 "@dptr" => AtDptr,
-                op @ _ => {
-                    match other_op(op.to_string()){
-                        Ok(op) => op,
-                        Err(e) => return Err(e),
-                    }
-                },
+                _ => other_op(op.clone()).map_err(|e| at(i, e))?,
             };
             ops.push(op_tmp);
+            columns.extend(here);
 
         }
 
-        Ok(Instruction{offset:offset, num: line.num, label:line.label, mnemonic: mne, ops:ops})
-    }
-
-    pub fn offset(&self) ->u16 {
-        self.offset
-    }
-
-    pub fn len(&self) ->i32 {
-        let mut len = 0;
-        let mut movdptr = 0;
-        if self.mnemonic.is_some() {
-            match self.mnemonic.clone().unwrap(){
-                Org |Cseg | Dseg=> return 0,
-                Db => {
-                    if self.ops.len() == 1 {
-                        match self.ops[0] {
-                            Label(ref l)=> return l.len() as i32,
-                            _ => {},
-                        };
-                    }
-                },
-                Ds => {
-                    if self.ops.len() == 1 {
-                        match self.ops[0] {
-                            Addr(ref l)=> return *l as i32,
-                            _ => {},
-                        };
-                    }
-                },
-                Mov => movdptr +=1,
-                Lcall | Ljmp => return 3,
-                Cjne => return 3,
-                _ =>{},
-            }
-            len +=1;
-            if self.ops.len() > 0 {
-                match self.ops[0] {
-                    Data(_)| Addr(_)| Addr16(_)=> len+=1,
-                    Label(_) => len+=1,
-                    Data16(_) => len+=2,
-                    Dptr => movdptr+=1,
-                    _ => {},
-                };
-            }
-            if self.ops.len() > 1 {
-                match self.ops[1] {
-                    Addr16(_)| Addr(_) => len+=1,
-                    Data(_)=> {
-                        len+=1;
-                        if movdptr == 2 {
-                            len+=1;
-                        }
-                    },
-                    Label(_) => len+=1,
-                    Data16(_) => len+=2,
-                    _ => {},
-                };
+        // Generic JMP/CALL start in their short form and are lengthened by
+        // `relax` once the layout shows the target is out of reach
+        let mut mne = mne;
+        let mut generic = false;
+        let is_target = match ops.first() {
+            Some(&AddrExpr(_)) | Some(&Addr(_)) => ops.len() == 1,
+            _ => false,
+        };
+        if is_target {
+            match mne {
+                Some(Jmp) => { mne = Some(Sjmp); generic = true; },
+                Some(Call) => { mne = Some(Acall); generic = true; },
+                _ => {},
             }
         }
 
-        len
+        Ok(Instruction{offset, base: 0, num: line.num, label:line.label, mnemonic: mne, ops, generic, far: None, expansion: line.expansion, expanded_from: line.expanded_from, column, columns})
     }
 
-    pub fn fix_label(&mut self, table: &Vec<(String, u16)>) -> Result<(), String>{
-        if self.mnemonic.is_none() {
-            return Ok(())
-        }
-        match self.mnemonic.clone().unwrap() {
-            Cseg | Db => return Ok(()),
-            _ => {},
-        }
-        let label_table = table.clone();
-        for i in 0 .. self.ops.len() {
-            let temp_op = match self.ops[i] {
-                OpType::Label(ref label_full) => {
-                    if label_full.to_lowercase() == "at"
-                    {
-                        OpType::Label(label_full.clone())
-                    }
-                    else {
-                        let split: Vec<&str> = label_full.split('.').collect();
-                        let label = split[0];
-                        let index = label_table.iter().position(|x| x.0.to_lowercase() == label);
-                        if index.is_none(){
-                            return Err(format!("Could not find label: line {}: {}",self.num,label));
-                        }
-                        let addr = label_table[index.unwrap()].1;
-                        let mut add = 0;
-                        if split.len()>1 {
-                            add = match u8::from_str_radix(split[1], 16){
-                                Ok(a) => a,
-                                Err(_) => 0,
-                            };
-                        }
-                        OpType::Addr16(addr+add as u16)
-                    }
-                },
-                ref o@_=>o.clone(),
-            };
-            self.ops[i] = temp_op;
-        }
+    /// Lengthens a generic `JMP`/`CALL` whose target is out of reach of its
+    /// current form: SJMP becomes AJMP then LJMP, ACALL becomes LCALL. Forms
+    /// only ever grow, so repeating the layout always converges. Returns
+    /// whether the instruction changed.
+    ///
+    /// With `branches`, out of range SJMPs and conditional branches are
+    /// relaxed as well, see `relax_branch`.
+    pub fn relax(&mut self, table: &[Symbol], branches: bool) -> bool {
+        if branches && self.is_branch() && !self.generic {
+            return self.relax_branch(table);
+        }
+        if !self.generic {
+            return false;
+        }
+        let target = match self.target(table) {
+            Some(t) => t,
+            None => return false,
+        };
+        let next = self.pc() as i32 + 2;
+        let same_page = next & 0xF800 == target & 0xF800;
+        let form = match self.mnemonic {
+            Some(Sjmp) if (-0x80..=0x7F).contains(&(target - next)) => return false,
+            Some(Sjmp) if same_page => Ajmp,
+            Some(Ajmp) | Some(Acall) if same_page => return false,
+            Some(Sjmp) | Some(Ajmp) => Ljmp,
+            Some(Acall) => Lcall,
+            _ => return false,
+        };
+        self.mnemonic = Some(form);
+        true
+    }
 
-        Ok(())
+    /// Rewrites a conditional branch whose target is out of the signed 8-bit
+    /// range into the inverted branch over an AJMP or LJMP to the target.
+    /// Branches without an inverse (CJNE, DJNZ, JBC) instead branch to the
+    /// jump, with an SJMP over it for the fall through path. An SJMP is
+    /// replaced by the jump.
+    fn relax_branch(&mut self, table: &[Symbol]) -> bool {
+        let target = match self.target(table) {
+            Some(t) => t,
+            None => return false,
+        };
+        let short = self.short_len();
+        let end = self.pc() as i32 + short;
+        if self.far.is_none() && (-0x80..=0x7F).contains(&(target - end)) {
+            return false;
+        }
+        let next = self.pc() as i32 + self.jump_at() + 2;
+        let same_page = next & 0xF800 == target & 0xF800;
+        let jump = match self.far {
+            Some(Ljmp) => return false,
+            Some(Ajmp) if same_page => return false,
+            _ if same_page => Ajmp,
+            _ => Ljmp,
+        };
+        self.far = Some(jump);
+        true
     }
 
-    // pub fn validate(&self) -> Result<(), String> {
-    //     match &self.mnemonic {
-    //         &None => Ok(()),
-    //         &Some(Nop) => {
-    //             if self.ops.len() > 0 {
-    //                 return Err(String::from("NOP takes no operands"))
-    //             }
-    //             Ok(())
-    //         },
-    //
-    //         _ => Ok(()),
-    //     }
-    // }
-}
+    /// Describes how a relaxed branch was rewritten, for the listing.
+    pub fn relaxation(&self) -> Option<String> {
+        let jump = self.far.as_ref()?;
+        let mnemonic = self.mnemonic.as_ref()?;
+        Some(match self.inverse() {
+            None if matches!(self.mnemonic, Some(Sjmp)) => format!("SJMP out of range, rewritten as {}", jump.name()),
+            Some(inv) => format!("{} out of range, rewritten as {} over {}", mnemonic.name(), inv.name(), jump.name()),
+            None => format!("{} out of range, rewritten as {} to {} with SJMP over it",
+                mnemonic.name(), mnemonic.name(), jump.name()),
+        })
+    }
 
-impl Instruction {
-    fn cjne(&self) -> Result<Vec<u8>, String> {
-        if self.ops.len() < 3 {
-            return Err(format!("Too few arguments for CJNE: {}",self.num));
-        }
-        if self.ops.len() > 3 {
-            return Err(format!("Too many arguments for CJNE: {}",self.num));
-        }
-        match (self.ops.index(0), self.ops.index(1), self.ops.index(2)) {
-            (&A, &Data(d), &Addr16(a)) => Ok(vec![0xB4,d,self.short_jmp(a)?]),
-            (&A, &Addr(d), &Addr16(a)) => Ok(vec![0xB5,d as u8,self.short_jmp(a)?]),
-            (&AtR0, &Data(d), &Addr16(a)) => Ok(vec![0xB6,d,self.short_jmp(a)?]),
-            (&AtR1, &Data(d), &Addr16(a)) => Ok(vec![0xB7,d,self.short_jmp(a)?]),
-            (&R0, &Data(d), &Addr16(a)) => Ok(vec![0xB8,d,self.short_jmp(a)?]),
-            (&R1, &Data(d), &Addr16(a)) => Ok(vec![0xB9,d,self.short_jmp(a)?]),
-            (&R2, &Data(d), &Addr16(a)) => Ok(vec![0xBA,d,self.short_jmp(a)?]),
-            (&R3, &Data(d), &Addr16(a)) => Ok(vec![0xBB,d,self.short_jmp(a)?]),
-            (&R4, &Data(d), &Addr16(a)) => Ok(vec![0xBC,d,self.short_jmp(a)?]),
-            (&R5, &Data(d), &Addr16(a)) => Ok(vec![0xBD,d,self.short_jmp(a)?]),
-            (&R6, &Data(d), &Addr16(a)) => Ok(vec![0xBE,d,self.short_jmp(a)?]),
-            (&R7, &Data(d), &Addr16(a)) => Ok(vec![0xBF,d,self.short_jmp(a)?]),
-            (ref a@_, ref b@_,ref c@_) => Err(format!("Invalid operation: CJNE {:?},{:?},{:?}", a,b,c)),
-        }
+    /// Describes every value cut to its low byte to fit a byte operand, with
+    /// the column of the operand.
+    pub fn truncations(&self) -> Vec<(Option<usize>, String)> {
+        let bytes: Vec<usize> = match (self.mnemonic.as_ref(), self.opcode()) {
+            (Some(Db), _) => (0..self.ops.len()).collect(),
+            (_, Some(form)) => (0..form.operands.len()).filter(|&i| form.operands[i] == Operand::Imm).collect(),
+            _ => Vec::new(),
+        };
+        bytes.into_iter().filter_map(|i| match self.ops[i] {
+            Data(d) if !in_bits(d as i64, 8) => {
+                Some((self.column(i), format!("Value {} truncated to {:02X}h", d, d as u8)))
+            },
+            _ => None,
+        }).collect()
     }
 
-    fn clr(&self) -> Result<Vec<u8>, String> {
-        if self.ops.len() < 1{
-            return Err(format!("Too few arguments for CLR: {}",self.num));
-        }
-        if self.ops.len() > 1 {
-            return Err(format!("Too many arguments for CLR: {}",self.num));
-        }
-        match self.ops[0] {
-            A => Ok(vec![0xE4]),
-            Addr(d) => Ok(vec![0xC2,d]),
-            C => Ok(vec![0xC3]),
-            ref a@_ => Err(format!("Invalid operation: CLR {:?}", a)),
+    /// Evaluates the target of a jump, its last operand.
+    fn target(&self, table: &[Symbol]) -> Option<i32> {
+        match self.ops.last() {
+            Some(AddrExpr(e)) => e.eval(&|s: &str| symbol::lookup(table, s), self.pc()).ok(),
+            Some(&Addr(d)) => Some(d),
+            _ => None,
         }
     }
 
-    fn setb(&self) -> Result<Vec<u8>, String> {
-        if self.ops.len() < 1{
-            return Err(format!("Too few arguments for SETB: {}",self.num));
-        }
-        if self.ops.len() > 1 {
-            return Err(format!("Too many arguments for SETB: {}",self.num));
-        }
-        match self.ops[0] {
-            Addr(d)=> Ok(vec![0xD2,d]),
-            C => Ok(vec![0xD3]),
-            ref a@_ => Err(format!("Invalid operation: SETB {:?}", a)),
-        }
+    /// Whether the instruction is a relative branch, which `-r` relaxes.
+    fn is_branch(&self) -> bool {
+        matches!(self.mnemonic, Some(Sjmp) | Some(Jz) | Some(Jnz) | Some(Jc) | Some(Jnc) | Some(Jb) | Some(Jnb)
+            | Some(Jbc) | Some(Djnz) | Some(Cjne))
     }
 
-    fn cpl(&self) -> Result<Vec<u8>, String> {
-        if self.ops.len() < 1{
-            return Err(format!("Too few arguments for CPL: {}",self.num));
-        }
-        if self.ops.len() > 1{
-            return Err(format!("Too many arguments for CPL: {}",self.num));
-        }
-        match self.ops[0] {
-            Addr(d) => Ok(vec![0xB2,d]),
-            C => Ok(vec![0xB3]),
-            A => Ok(vec![0xF4]),
-            ref a@_ => Err(format!("Invalid operation: CPL {:?}", a)),
+    /// The branch taken on the opposite condition, if there is one.
+    fn inverse(&self) -> Option<Mnemonic> {
+        match self.mnemonic {
+            Some(Jz) => Some(Jnz),
+            Some(Jnz) => Some(Jz),
+            Some(Jc) => Some(Jnc),
+            Some(Jnc) => Some(Jc),
+            Some(Jb) => Some(Jnb),
+            Some(Jnb) => Some(Jb),
+            _ => None,
         }
     }
 
-    fn djnz(&self) -> Result<Vec<u8>, String> {
-        let op = "DJNZ";
-        if self.ops.len() < 2 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 2 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match (self.ops.index(0),self.ops.index(1)) {
-            (&R0,&Addr16(d)) => Ok(vec![0xD8,self.short_jmp(d)?]),
-            (&R1,&Addr16(d)) => Ok(vec![0xD9,self.short_jmp(d)?]),
-            (&R2,&Addr16(d)) => Ok(vec![0xDA,self.short_jmp(d)?]),
-            (&R3,&Addr16(d)) => Ok(vec![0xDB,self.short_jmp(d)?]),
-            (&R4,&Addr16(d)) => Ok(vec![0xDC,self.short_jmp(d)?]),
-            (&R5,&Addr16(d)) => Ok(vec![0xDD,self.short_jmp(d)?]),
-            (&R6,&Addr16(d)) => Ok(vec![0xDE,self.short_jmp(d)?]),
-            (&R7,&Addr16(d)) => Ok(vec![0xDF,self.short_jmp(d)?]),
-            (&Addr(d),&Addr16(a)) => Ok(vec![0xD5,d,self.short_jmp(a)?]),
-            (a@_,b@_) => Err(format!("Invalid operation: {} {:?},{:?}",op, a,b)),
-        }
+    /// Encodes a relaxed branch, see `relax_branch`.
+    fn far_branch(&self) -> Result<Vec<u8>, (Option<usize>, String)> {
+        let last = self.ops.len() - 1;
+        let jump_len = match self.far {
+            Some(Ajmp) => 2,
+            _ => 3,
+        };
+        let mut branch = self.clone();
+        branch.far = None;
+        let short = branch.len() as u16;
+        let mut bytes = match self.inverse() {
+            None if matches!(self.mnemonic, Some(Sjmp)) => Vec::new(),
+            Some(inv) => {
+                branch.mnemonic = Some(inv);
+                branch.ops[last] = Addr(self.pc().wrapping_add(short + jump_len) as i32);
+                branch.to_hex()?
+            },
+            None => {
+                branch.ops[last] = Addr(self.pc().wrapping_add(short + 2) as i32);
+                let mut bytes = branch.to_hex()?;
+                bytes.extend_from_slice(&[0x80, jump_len as u8]);
+                bytes
+            },
+        };
+        let jump = Instruction {
+            offset: self.offset.wrapping_add(self.jump_at() as u16),
+            mnemonic: self.far.clone(),
+            ops: vec![self.ops[last].clone()],
+            far: None,
+            ..self.clone()
+        };
+        bytes.append(&mut jump.to_hex()?);
+        Ok(bytes)
     }
 
-    fn mov(&self) -> Result<Vec<u8>, String> {
-        let op = "MOV";
-        if self.ops.len() < 2 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 2  {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match (self.ops.index(0),self.ops.index(1)) {
-            (&Addr(d),&A) => Ok(vec![0xF5,d]),
-            (&AtR0,&A) => Ok(vec![0xF6]),
-            (&AtR1,&A) => Ok(vec![0xF7]),
-            (&R0,&A) => Ok(vec![0xF8]),
-            (&R1,&A) => Ok(vec![0xF9]),
-            (&R2,&A) => Ok(vec![0xFA]),
-            (&R3,&A) => Ok(vec![0xFB]),
-            (&R4,&A) => Ok(vec![0xFC]),
-            (&R5,&A) => Ok(vec![0xFD]),
-            (&R6,&A) => Ok(vec![0xFE]),
-            (&R7,&A) => Ok(vec![0xFF]),
-            (&A,&Data(d)) => Ok(vec![0x74,d]),
-            (&Addr(a),&Data(d)) => Ok(vec![0x75,a,d]),
-            (&AtR0,&Data(d)) => Ok(vec![0x76,d]),
-            (&AtR1,&Data(d)) => Ok(vec![0x77,d]),
-            (&R0,&Data(d)) => Ok(vec![0x78,d]),
-            (&R1,&Data(d)) => Ok(vec![0x79,d]),
-            (&R2,&Data(d)) => Ok(vec![0x7A,d]),
-            (&R3,&Data(d)) => Ok(vec![0x7B,d]),
-            (&R4,&Data(d)) => Ok(vec![0x7C,d]),
-            (&R5,&Data(d)) => Ok(vec![0x7D,d]),
-            (&R6,&Data(d)) => Ok(vec![0x7E,d]),
-            (&R7,&Data(d)) => Ok(vec![0x7F,d]),
-            (&Addr(d),&AB) => Ok(vec![0x84,d]),
-            (&Addr(d),&Addr(a)) => Ok(vec![0x85,a,d]),
-            (&Addr(d),&AtR0) => Ok(vec![0x86,d]),
-            (&Addr(d),&AtR1) => Ok(vec![0x87,d]),
-            (&Addr(d),&R0) => Ok(vec![0x88,d]),
-            (&Addr(d),&R1) => Ok(vec![0x89,d]),
-            (&Addr(d),&R2) => Ok(vec![0x8A,d]),
-            (&Addr(d),&R3) => Ok(vec![0x8B,d]),
-            (&Addr(d),&R4) => Ok(vec![0x8C,d]),
-            (&Addr(d),&R5) => Ok(vec![0x8D,d]),
-            (&Addr(d),&R6) => Ok(vec![0x8E,d]),
-            (&Addr(d),&R7) => Ok(vec![0x8F,d]),
-            (&A,&Addr(d)) => Ok(vec![0xE5,d]),
-            (&A,&AtR0) => Ok(vec![0xE6]),
-            (&A,&AtR1) => Ok(vec![0xE7]),
-            (&A,&R0) => Ok(vec![0xE8]),
-            (&A,&R1) => Ok(vec![0xE9]),
-            (&A,&R2) => Ok(vec![0xEA]),
-            (&A,&R3) => Ok(vec![0xEB]),
-            (&A,&R4) => Ok(vec![0xEC]),
-            (&A,&R5) => Ok(vec![0xED]),
-            (&A,&R6) => Ok(vec![0xEE]),
-            (&A,&R7) => Ok(vec![0xEF]),
-            (&Dptr,&Data16(d)) => Ok(vec![0x90,(d/0x100)as u8,(d%0x100) as u8]),
-            (&Dptr,&Addr16(d)) => Ok(vec![0x90,(d/0x100)as u8,(d%0x100) as u8]),
-            (&Dptr,&Data(d)) => Ok(vec![0x90,0x00,d]),
-
-            (&Addr(d),&C) => Ok(vec![0x92,d]),
-            (&C,&Addr(d)) => Ok(vec![0xA2,d]),
-
-            (&AtR0,&Addr(d)) => Ok(vec![0xA6,d]),
-            (&AtR1,&Addr(d)) => Ok(vec![0xA7,d]),
-            (&R0,&Addr(d)) => Ok(vec![0xA8,d]),
-            (&R1,&Addr(d)) => Ok(vec![0xA9,d]),
-            (&R2,&Addr(d)) => Ok(vec![0xAA,d]),
-            (&R3,&Addr(d)) => Ok(vec![0xAB,d]),
-            (&R4,&Addr(d)) => Ok(vec![0xAC,d]),
-            (&R5,&Addr(d)) => Ok(vec![0xAD,d]),
-            (&R6,&Addr(d)) => Ok(vec![0xAE,d]),
-            (&R7,&Addr(d)) => Ok(vec![0xAF,d]),
-            (a@_,b@_) => Err(format!("Invalid operation: {} {:?},{:?}",op, a,b)),
-        }
+    /// Returns the symbol defined by an `EQU`, `SET`, `DATA`, ... line.
+    pub fn definition(&self, base: u16) -> Option<Definition> {
+        let kind = match self.mnemonic {
+            Some(Define(kind)) => kind,
+            _ => return None,
+        };
+        let expr = match self.ops.first() {
+            Some(AddrExpr(e)) => e.clone(),
+            _ => return None,
+        };
+        Some(Definition {
+            name: self.label.clone().unwrap_or_default(),
+            kind,
+            expr,
+            here: base.wrapping_add(self.offset),
+            line: self.num,
+        })
     }
 
-    fn inc(&self) -> Result<Vec<u8>, String> {
-        let op ="INC";
-        if self.ops.len() < 1 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 1 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match self.ops[0] {
-            A => Ok(vec![0x04]),
-            Addr(d) => Ok(vec![0x05,d]),
-            AtR0 => Ok(vec![0x06]),
-            AtR1 => Ok(vec![0x07]),
-            R0 => Ok(vec![0x08]),
-            R1 => Ok(vec![0x09]),
-            R2 => Ok(vec![0x0A]),
-            R3 => Ok(vec![0x0B]),
-            R4 => Ok(vec![0x0C]),
-            R5 => Ok(vec![0x0D]),
-            R6 => Ok(vec![0x0E]),
-            R7 => Ok(vec![0x0F]),
-            Dptr => Ok(vec![0xA3]),
-            ref a@_ => Err(format!("Invalid operation: {} {:?}",op, a)),
-        }
+    pub fn set_offset(&mut self, offset: u16) {
+        self.offset = offset;
     }
 
-    fn dec(&self) -> Result<Vec<u8>, String> {
-        let op ="DEC";
-        if self.ops.len() < 1 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 1 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match self.ops[0] {
-            A => Ok(vec![0x14]),
-            Addr(d) => Ok(vec![0x15,d]),
-            AtR0 => Ok(vec![0x16]),
-            AtR1 => Ok(vec![0x17]),
-            R0 => Ok(vec![0x18]),
-            R1 => Ok(vec![0x19]),
-            R2 => Ok(vec![0x1A]),
-            R3 => Ok(vec![0x1B]),
-            R4 => Ok(vec![0x1C]),
-            R5 => Ok(vec![0x1D]),
-            R6 => Ok(vec![0x1E]),
-            R7 => Ok(vec![0x1F]),
-            ref a@_ => Err(format!("Invalid operation: {} {:?}",op, a)),
-        }
+    pub fn set_base(&mut self, base: u16) {
+        self.base = base;
     }
 
-    fn pop(&self) -> Result<Vec<u8>, String> {
-        let op ="POP";
-        if self.ops.len() < 1 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 1 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match self.ops[0] {
-            Addr(d) => Ok(vec![0xD0,d]),
-            ref a@_ => Err(format!("Invalid operation: {} {:?}",op, a)),
-        }
+    pub fn offset(&self) ->u16 {
+        self.offset
     }
 
-    fn push(&self) -> Result<Vec<u8>, String> {
-        let op ="PUSH";
-        if self.ops.len() < 1 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 1 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match self.ops[0] {
-            Addr(d) => Ok(vec![0xC0,d]),
+    pub fn num(&self) -> u64 {
+        self.num
+    }
 
-            ref a@_ => Err(format!("Invalid operation: {} {:?}",op, a)),
-        }
+    pub fn expansion(&self) -> Option<&str> {
+        self.expansion.as_deref()
     }
 
-    fn add(&self) -> Result<Vec<u8>, String> {
-        let op = "ADD";
-        if self.ops.len() < 2 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 2 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match (self.ops.index(0),self.ops.index(1)) {
-            (&A,&Data(d)) => Ok(vec![0x24,d]),
-            (&A,&Addr(d)) => Ok(vec![0x25,d]),
-            (&A,&AtR0) => Ok(vec![0x26]),
-            (&A,&AtR1) => Ok(vec![0x27]),
-            (&A,&R0) => Ok(vec![0x28]),
-            (&A,&R1) => Ok(vec![0x29]),
-            (&A,&R2) => Ok(vec![0x2A]),
-            (&A,&R3) => Ok(vec![0x2B]),
-            (&A,&R4) => Ok(vec![0x2C]),
-            (&A,&R5) => Ok(vec![0x2D]),
-            (&A,&R6) => Ok(vec![0x2E]),
-            (&A,&R7) => Ok(vec![0x2F]),
-            (a@_,b@_) => Err(format!("Invalid operation: {} {:?},{:?}",op, a,b)),
-        }
+    pub fn expanded_from(&self) -> &[(String, u64)] {
+        &self.expanded_from
     }
 
-    fn addc(&self) -> Result<Vec<u8>, String> {
-        let op = "ADDC";
-        if self.ops.len() < 2 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 2 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match (self.ops.index(0),self.ops.index(1)) {
-            (&A,&Data(d)) => Ok(vec![0x34,d]),
-            (&A,&Addr(d)) => Ok(vec![0x35,d]),
-            (&A,&AtR0) => Ok(vec![0x36]),
-            (&A,&AtR1) => Ok(vec![0x37]),
-            (&A,&R0) => Ok(vec![0x38]),
-            (&A,&R1) => Ok(vec![0x39]),
-            (&A,&R2) => Ok(vec![0x3A]),
-            (&A,&R3) => Ok(vec![0x3B]),
-            (&A,&R4) => Ok(vec![0x3C]),
-            (&A,&R5) => Ok(vec![0x3D]),
-            (&A,&R6) => Ok(vec![0x3E]),
-            (&A,&R7) => Ok(vec![0x3F]),
-            (a@_,b@_) => Err(format!("Invalid operation: {} {:?},{:?}",op, a,b)),
+    /// Returns the names of the symbols used by the operands.
+    pub fn references(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        for op in &self.ops {
+            match *op {
+                DataExpr(ref e) | AddrExpr(ref e) | NotBitExpr(ref e) => e.symbols(&mut names),
+                _ => {},
+            }
         }
+        names
     }
 
-    fn orl(&self) -> Result<Vec<u8>, String> {
-        let op = "ORL";
-        if self.ops.len() < 2 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 2 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match (self.ops.index(0),self.ops.index(1)) {
-            (&Addr(a),&A) => Ok(vec![0x42,a]),
-            (&Addr(a),&Data(d)) => Ok(vec![0x43,a,d]),
-            (&A,&Data(d)) => Ok(vec![0x44,d]),
-            (&A,&Addr(d)) => Ok(vec![0x45,d]),
-            (&A,&AtR0) => Ok(vec![0x46]),
-            (&A,&AtR1) => Ok(vec![0x47]),
-            (&A,&R0) => Ok(vec![0x48]),
-            (&A,&R1) => Ok(vec![0x49]),
-            (&A,&R2) => Ok(vec![0x4A]),
-            (&A,&R3) => Ok(vec![0x4B]),
-            (&A,&R4) => Ok(vec![0x4C]),
-            (&A,&R5) => Ok(vec![0x4D]),
-            (&A,&R6) => Ok(vec![0x4E]),
-            (&A,&R7) => Ok(vec![0x4F]),
-            (&C,&Addr(d)) => Ok(vec![0x72,d]),
-            (a@_,b@_) => Err(format!("Invalid operation: {} {:?},{:?}",op, a,b)),
-        }
+    /// Whether the instruction is a machine instruction, not data or a
+    /// directive.
+    pub fn is_code(&self) -> bool {
+        !self.is_allocation() && !matches!(self.mnemonic, Some(Db) | Some(Dw) | Some(Dd))
     }
 
-    fn anl(&self) -> Result<Vec<u8>, String> {
-        let op = "ANL";
-        if self.ops.len() < 2 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 2 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match (self.ops.index(0),self.ops.index(1)) {
-            (&Addr(a),&A) => Ok(vec![0x52,a]),
-            (&Addr(a),&Data(d)) => Ok(vec![0x53,a,d]),
-            (&A,&Data(d)) => Ok(vec![0x54,d]),
-            (&A,&Addr(d)) => Ok(vec![0x55,d]),
-            (&A,&AtR0) => Ok(vec![0x56]),
-            (&A,&AtR1) => Ok(vec![0x57]),
-            (&A,&R0) => Ok(vec![0x58]),
-            (&A,&R1) => Ok(vec![0x59]),
-            (&A,&R2) => Ok(vec![0x5A]),
-            (&A,&R3) => Ok(vec![0x5B]),
-            (&A,&R4) => Ok(vec![0x5C]),
-            (&A,&R5) => Ok(vec![0x5D]),
-            (&A,&R6) => Ok(vec![0x5E]),
-            (&A,&R7) => Ok(vec![0x5F]),
-            (&C,&Addr(d)) => Ok(vec![0x82,d]),
-
-            (a@_,b@_) => Err(format!("Invalid operation: {} {:?},{:?}",op, a,b)),
-        }
+    /// Whether the instruction only allocates addresses, as a directive,
+    /// label, `DS` or `DBIT` does. Only these may appear outside CODE.
+    pub fn is_allocation(&self) -> bool {
+        self.segment().is_some()
+            || matches!(self.mnemonic, Some(Org) | Some(Define(_)) | Some(Ds) | Some(Dbit) | None)
     }
 
-    fn subb(&self) -> Result<Vec<u8>, String> {
-        let op = "SUBB";
-        if self.ops.len() < 2 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 2 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match (self.ops.index(0),self.ops.index(1)) {
-            (&A,&Data(d)) => Ok(vec![0x94,d]),
-            (&A,&Addr(d)) => Ok(vec![0x95,d]),
-            (&A,&AtR0) => Ok(vec![0x96]),
-            (&A,&AtR1) => Ok(vec![0x97]),
-            (&A,&R0) => Ok(vec![0x98]),
-            (&A,&R1) => Ok(vec![0x99]),
-            (&A,&R2) => Ok(vec![0x9A]),
-            (&A,&R3) => Ok(vec![0x9B]),
-            (&A,&R4) => Ok(vec![0x9C]),
-            (&A,&R5) => Ok(vec![0x9D]),
-            (&A,&R6) => Ok(vec![0x9E]),
-            (&A,&R7) => Ok(vec![0x9F]),
-
-            (a@_,b@_) => Err(format!("Invalid operation: {} {:?},{:?}",op, a,b)),
+    /// The address space a `CSEG`, `DSEG`, `ISEG`, `BSEG` or `XSEG`
+    /// switches to.
+    pub fn segment(&self) -> Option<Space> {
+        match self.mnemonic {
+            Some(Cseg) => Some(Space::Code),
+            Some(Dseg) => Some(Space::Data),
+            Some(Iseg) => Some(Space::Idata),
+            Some(Bseg) => Some(Space::Bit),
+            Some(Xseg) => Some(Space::Xdata),
+            _ => None,
         }
     }
 
-    fn xch(&self) -> Result<Vec<u8>, String> {
-        let op = "XCH";
-        if self.ops.len() < 2 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 2 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match (self.ops.index(0),self.ops.index(1)) {
-            (&A,&Addr(d)) => Ok(vec![0xC5,d]),
-            (&A,&AtR0) => Ok(vec![0xC6]),
-            (&A,&AtR1) => Ok(vec![0xC7]),
-            (&A,&R0) => Ok(vec![0xC8]),
-            (&A,&R1) => Ok(vec![0xC9]),
-            (&A,&R2) => Ok(vec![0xCA]),
-            (&A,&R3) => Ok(vec![0xCB]),
-            (&A,&R4) => Ok(vec![0xCC]),
-            (&A,&R5) => Ok(vec![0xCD]),
-            (&A,&R6) => Ok(vec![0xCE]),
-            (&A,&R7) => Ok(vec![0xCF]),
-
-            (a@_,b@_) => Err(format!("Invalid operation: {} {:?},{:?}",op, a,b)),
-        }
+    pub fn is_ds(&self) -> bool {
+        matches!(self.mnemonic, Some(Ds) | Some(Dbit))
     }
 
-    fn xrl(&self) -> Result<Vec<u8>, String> {
-        let op = "XRL";
-        if self.ops.len() < 2 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 2 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match (self.ops.index(0),self.ops.index(1)) {
-            (&Addr(d),&A) => Ok(vec![0x62,d]),
-            (&Addr(a),&Data(d)) => Ok(vec![0x63,a,d]),
-            (&A,&Data(d)) => Ok(vec![0x64,d]),
-            (&A,&Addr(d)) => Ok(vec![0x65,d]),
-            (&A,&AtR0) => Ok(vec![0x66]),
-            (&A,&AtR1) => Ok(vec![0x67]),
-            (&A,&R0) => Ok(vec![0x68]),
-            (&A,&R1) => Ok(vec![0x69]),
-            (&A,&R2) => Ok(vec![0x6A]),
-            (&A,&R3) => Ok(vec![0x6B]),
-            (&A,&R4) => Ok(vec![0x6C]),
-            (&A,&R5) => Ok(vec![0x6D]),
-            (&A,&R6) => Ok(vec![0x6E]),
-            (&A,&R7) => Ok(vec![0x6F]),
-
-            (a@_,b@_) => Err(format!("Invalid operation: {} {:?},{:?}",op, a,b)),
-        }
+    pub fn is_dbit(&self) -> bool {
+        matches!(self.mnemonic, Some(Dbit))
     }
 
-    fn movx(&self) -> Result<Vec<u8>, String> {
-        let op = "MOVX";
-        if self.ops.len() < 2 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 2 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match (self.ops.index(0),self.ops.index(1)) {
-            (&A,&AtDptr) => Ok(vec![0xE0]),
-            // (&Some(&Acall),&Addr16(d),&None) => Ok(vec![0xE1,d as u8]),
-            (&A,&AtR0) => Ok(vec![0xE2]),
-            (&A,&AtR1) => Ok(vec![0xE3]),
-
-
-            (&AtDptr,&A) => Ok(vec![0xF0]),
-            // (&Some(&Acall),&Addr16(d),&None) => Ok(vec![0xF1,d as u8]),
-            (&AtR0,&A) => Ok(vec![0xF2]),
-            (&AtR1,&A) => Ok(vec![0xF3]),
-            (a@_,b@_) => Err(format!("Invalid operation: {} {:?},{:?}",op, a,b)),
-        }
+    /// Whether the line had neither a label nor a mnemonic.
+    pub fn is_blank(&self) -> bool {
+        self.label.is_none() && self.mnemonic.is_none()
     }
 
-    fn movc(&self) -> Result<Vec<u8>, String> {
-        let op = "MOVC";
-        if self.ops.len() < 2 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 2 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match (self.ops.index(0),self.ops.index(1)) {
-            (&A,&AtAPc) => Ok(vec![0x83]),
-            (&A,&AtADptr) => Ok(vec![0x93]),
-            (a@_,b@_) => Err(format!("Invalid operation: {} {:?},{:?}",op, a,b)),
+    pub fn len(&self) -> i32 {
+        match self.far {
+            Some(Ajmp) => self.jump_at() + 2,
+            Some(_) => self.jump_at() + 3,
+            None => self.short_len(),
         }
     }
 
-    fn ret(&self) -> Result<Vec<u8>, String> {
-        let op = "RET";
-        if self.ops.len() > 0 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
+    /// Offset of the jump in a relaxed branch: after the inverted branch, or
+    /// after the branch and the SJMP over the jump. An SJMP is replaced.
+    fn jump_at(&self) -> i32 {
+        match self.mnemonic {
+            Some(Sjmp) => 0,
+            _ if self.inverse().is_some() => self.short_len(),
+            _ => self.short_len() + 2,
         }
-        Ok(vec![0x22])
     }
 
-    fn nop(&self) -> Result<Vec<u8>, String> {
-        let op = "NOP";
-        if self.ops.len() > 0 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
+    fn short_len(&self) -> i32 {
+        match self.mnemonic {
+            Some(Org) | Some(Cseg) | Some(Dseg) | Some(Iseg) | Some(Bseg) | Some(Xseg) | Some(Define(_)) | None => 0,
+            Some(Db) | Some(Dw) | Some(Dd) | Some(Ds) | Some(Dbit) => self.data_len(),
+            Some(_) => self.opcode().map(|x| x.len() as i32).unwrap_or(1),
         }
-        Ok(vec![0x00])
     }
 
-    fn jb(&self) -> Result<Vec<u8>, String> {
-        let op = "JB";
-        if self.ops.len() < 2 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 2 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
+    /// Length of `DB`, `DW`, `DD`, `DS` and `DBIT`, which are not in the
+    /// opcode table. `DBIT` counts bits.
+    fn data_len(&self) -> i32 {
+        if let Some(size) = self.item_size() {
+            return self.ops.iter().map(|x| match *x {
+                Str(ref s) if size == 1 => s.len() as i32,
+                _ => size as i32,
+            }).sum();
         }
-        match (self.ops.index(0),self.ops.index(1)) {
-            (&Addr(a),&Addr16(d)) => Ok(vec![0x20,a,self.short_jmp(d)?]),
-            (a@_,b@_) => Err(format!("Invalid operation: {} {:?},{:?}",op, a,b)),
+        match (&self.mnemonic, self.ops.first()) {
+            (&Some(Ds), Some(&Addr(d))) | (&Some(Dbit), Some(&Addr(d))) if self.ops.len() == 1 => d.max(0),
+            _ => 1 + self.ops.len().min(2) as i32,
         }
     }
 
-    fn ajmp(&self) -> Result<Vec<u8>, String> {
-        let op = "AJMP";
-        if self.ops.len() < 1 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 1 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
+    /// Resolves the size of a `DS` or `DBIT` from the constants defined
+    /// before it, since it sets the address of everything after it.
+    pub fn resolve_size(&mut self, known: &Known) -> Result<(), (Option<usize>, String)> {
+        if !self.is_ds() {
+            return Ok(());
         }
-        match self.ops[0] {
-            Addr16(d) => Ok(vec![0x01,d as u8]),
-            ref a@_ => Err(format!("Invalid operation: {} {:?}",op, a)),
+        let size = match self.ops.first() {
+            Some(AddrExpr(e)) => known.value(e),
+            _ => return Ok(()),
+        };
+        let name = self.mnemonic.as_ref().map(|x| x.name()).unwrap_or_default();
+        match size {
+            Ok(d) => self.ops[0] = Addr(d),
+            Err(e) => return Err((self.column(0), format!("{} size must be a constant defined before it: {}", name, e))),
         }
+        Ok(())
     }
 
-    fn ljmp(&self) -> Result<Vec<u8>, String> {
-        let op = "LJMP";
-        if self.ops.len() < 1 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
+    /// Resolves every operand expression against the label table. `base`
+    /// is the address of the section, used for the location counter `$`.
+    pub fn fix_label(&mut self, table: &[Symbol], base: u16) -> Result<(), (Option<usize>, String)>{
+        if self.mnemonic.is_none() {
+            return Ok(())
         }
-        if self.ops.len() > 1 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
+        match self.mnemonic.clone().unwrap() {
+            Cseg | Define(_) => return Ok(()),
+            _ => {},
         }
-        match self.ops[0] {
-            Addr16(d) => Ok(vec![0x02,(d/0x100) as u8, (d%100) as u8]),
-            ref a@_ => Err(format!("Invalid operation: {} {:?}",op, a)),
+        let here = base.wrapping_add(self.offset);
+        let lookup = |name: &str| symbol::lookup(table, name);
+        for (i, op) in self.ops.iter_mut().enumerate() {
+            let column = self.columns.get(i).cloned().or(self.column);
+            let value = match *op {
+                DataExpr(ref e) => e.eval(&lookup, here).map(Data),
+                AddrExpr(ref e) => e.eval(&lookup, here).map(Addr),
+                NotBitExpr(ref e) => e.eval(&lookup, here).and_then(not_bit_op),
+                _ => continue,
+            };
+            *op = value.map_err(|e| (column, e))?;
         }
+        Ok(())
     }
 
-    fn acall(&self) -> Result<Vec<u8>, String> {
-        let op = "ACALL";
-        if self.ops.len() < 1 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 1 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match self.ops[0] {
-            Addr16(d) => Ok(vec![0x11,d as u8]),
-            ref a@_ => Err(format!("Invalid operation: {} {:?}",op, a)),
+    // pub fn validate(&self) -> Result<(), String> {
+    //     match &self.mnemonic {
+    //         &None => Ok(()),
+    //         &Some(Nop) => {
+    //             if self.ops.len() > 0 {
+    //                 return Err(String::from("NOP takes no operands"))
+    //             }
+    //             Ok(())
+    //         },
+    //
+    //         _ => Ok(()),
+    //     }
+    // }
+}
+
+impl Instruction {
+    /// Bytes in each item of a `DB`, `DW` or `DD` list.
+    fn item_size(&self) -> Option<usize> {
+        match self.mnemonic {
+            Some(Db) => Some(1),
+            Some(Dw) => Some(2),
+            Some(Dd) => Some(4),
+            _ => None,
         }
     }
 
-    fn lcall(&self) -> Result<Vec<u8>, String> {
-        let op = "LCALL";
-        if self.ops.len() < 1 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 1 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match self.ops[0] {
-            Addr16(d) => Ok(vec![0x12,(d/0x100) as u8, (d%100) as u8]),
-            ref a@_ => Err(format!("Invalid operation: {} {:?}",op, a)),
+    /// Encodes a `DB`, `DW` or `DD` list. Words are big-endian. A string
+    /// gives one byte per character in `DB`, and in `DW` or `DD` is packed
+    /// into a single item.
+    fn data(&self) -> Result<Vec<u8>, (Option<usize>, String)> {
+        let op = self.mnemonic.as_ref().map(|x| x.name()).unwrap_or_default();
+        let size = self.item_size().unwrap_or(1);
+        if self.ops.is_empty() {
+            return Err((self.column, format!("Too few arguments for {}", op)));
+        }
+        let mut bytes = Vec::new();
+        for (i, item) in self.ops.iter().enumerate() {
+            let fail = |e: String| Err((self.column(i), e));
+            let v = match *item {
+                Str(ref s) if size == 1 => {
+                    bytes.extend_from_slice(s);
+                    continue;
+                },
+                Str(ref s) if s.len() > size => {
+                    return fail(format!("String is longer than {} characters for {}", size, op));
+                },
+                Str(ref s) => s.iter().fold(0i64, |v, &c| v << 8 | c as i64),
+                Data(v) => v as i64,
+                DataExpr(_) => return fail(String::from("Operand has not been resolved")),
+                ref a => return fail(format!("Invalid operation: {} {}", op, a)),
+            };
+            // A byte takes a 16-bit value, cut with a warning from `truncations`
+            let bits = 8 * size as u32;
+            if size < 4 && !in_bits(v, bits.max(16)) {
+                return fail(format!("Value {} does not fit in {} bits for {}", v, bits, op));
+            }
+            bytes.extend((0..size).rev().map(|i| (v >> (8 * i)) as u8));
         }
+        Ok(bytes)
     }
 
-    fn sjmp(&self) -> Result<Vec<u8>, String> {
-        let op = "SJMP";
+    fn ds(&self) -> Result<Vec<u8>, String> {
+        //TODO: make this better
+        let op = "DS";
         if self.ops.len() < 1 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
+            return Err(format!("Too few arguments for {}",op));
         }
         if self.ops.len() > 1 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
+            return Err(format!("Too many arguments for {}",op));
         }
         match self.ops[0] {
-            Addr16(d) => Ok(vec![0x80,self.short_jmp(d)?]),
-            ref a@_ => Err(format!("Invalid operation: {} {:?}",op, a)),
+            Addr(d) if d >= 0 => Ok(vec![0x10;d as usize]),
+            ref a => Err(format!("Invalid operation: {} {}", op, a)),
         }
     }
 
-    fn rr(&self) -> Result<Vec<u8>, String> {
-        let op = "rr";
-        if self.ops.len() < 1 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 1 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match self.ops[0] {
-            A => Ok(vec![0x03]),
-            ref a@_ => Err(format!("Invalid operation: {} {:?}",op, a)),
+}
+
+/// Parses an item of a data list: quoted text or an expression.
+fn data_item(op: &str) -> Result<OpType, String> {
+    let op = op.trim();
+    if let Ok([ref t]) = lexer::tokenize(op).as_deref() {
+        if t.kind == Kind::Str || t.kind == Kind::Char {
+            return expr::string(op).map(Str);
         }
     }
+    let e = Expr::parse(op)?;
+    match e.constant() {
+        Some(v) => Ok(Data(v)),
+        None => Ok(DataExpr(e)),
+    }
+}
 
-    fn rrc(&self) -> Result<Vec<u8>, String> {
-        let op = "RRC";
-        if self.ops.len() < 1 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 1 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match self.ops[0] {
-            A => Ok(vec![0x13]),
-            ref a@_ => Err(format!("Invalid operation: {} {:?}",op, a)),
-        }
+fn other_op(op: String) -> Result<OpType, String> {
+    let op = op.trim();
+    if let Some(bit) = op.strip_prefix('/') {
+        let e = Expr::parse(bit)?;
+        return match e.constant() {
+            Some(b) => not_bit_op(b),
+            None => Ok(NotBitExpr(e)),
+        };
+    }
+    if let Some(rest) = op.strip_prefix('#') {
+        let e = Expr::parse(rest)?;
+        return match e.constant() {
+            Some(d) => Ok(Data(d)),
+            None => Ok(DataExpr(e)),
+        };
+    }
+    let e = Expr::parse(op)?;
+    match e.constant() {
+        Some(a) => Ok(Addr(a)),
+        None => Ok(AddrExpr(e)),
     }
+}
 
-    fn rl(&self) -> Result<Vec<u8>, String> {
-        let op = "RL";
-        if self.ops.len() < 1 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 1 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match self.ops[0] {
-            A => Ok(vec![0x23]),
-            ref a@_ => Err(format!("Invalid operation: {} {:?}",op, a)),
-        }
+fn not_bit_op(b: i32) -> Result<OpType, String> {
+    if (0..=0xFF).contains(&b) {
+        Ok(NotBit(b as u8))
+    } else {
+        Err(String::from("Invalid bit address"))
     }
+}
 
-    fn reti(&self) -> Result<Vec<u8>, String> {
-        let op = "RETI";
-        if self.ops.len() > 0 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        Ok(vec![0x32])
+/// Whether an operand can be used where the instruction form expects `kind`.
+/// Values fit every operand of their kind whatever their magnitude, resolved
+/// or not, so the form and length of an instruction are known before its
+/// labels are. Whether the value fits the form is checked when encoding.
+fn fits(op: &OpType, kind: Operand) -> bool {
+    match (op, kind) {
+        (&A, Operand::A) | (&AB, Operand::AB) | (&C, Operand::C) | (&Dptr, Operand::Dptr)
+        | (&AtDptr, Operand::AtDptr) | (&AtADptr, Operand::AtADptr) | (&AtAPc, Operand::AtAPc)
+        | (&AtR0, Operand::AtReg(0)) | (&AtR1, Operand::AtReg(1)) => true,
+        (&R0, Operand::Reg(n)) => n == 0,
+        (&R1, Operand::Reg(n)) => n == 1,
+        (&R2, Operand::Reg(n)) => n == 2,
+        (&R3, Operand::Reg(n)) => n == 3,
+        (&R4, Operand::Reg(n)) => n == 4,
+        (&R5, Operand::Reg(n)) => n == 5,
+        (&R6, Operand::Reg(n)) => n == 6,
+        (&R7, Operand::Reg(n)) => n == 7,
+        (&Data(_), Operand::Imm) | (&Data(_), Operand::Imm16)
+        | (&DataExpr(_), Operand::Imm) | (&DataExpr(_), Operand::Imm16) => true,
+        (&Addr(_), Operand::Direct) | (&Addr(_), Operand::Bit) | (&Addr(_), Operand::Rel)
+        | (&Addr(_), Operand::Addr11) | (&Addr(_), Operand::Addr16)
+        | (&AddrExpr(_), Operand::Direct) | (&AddrExpr(_), Operand::Bit) | (&AddrExpr(_), Operand::Rel)
+        | (&AddrExpr(_), Operand::Addr11) | (&AddrExpr(_), Operand::Addr16) => true,
+        // `MOV DPTR,label` without the `#`
+        (&Addr(_), Operand::Imm16) | (&AddrExpr(_), Operand::Imm16) => true,
+        (&NotBit(_), Operand::NotBit) | (&NotBitExpr(_), Operand::NotBit) => true,
+        _ => false,
     }
+}
 
-    fn rlc(&self) -> Result<Vec<u8>, String> {
-        let op = "RLC";
-        if self.ops.len() < 1 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 1 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match self.ops[0] {
-            A => Ok(vec![0x33]),
-            ref a@_ => Err(format!("Invalid operation: {} {:?}",op, a)),
-        }
+/// Whether `v` fits in `bits` as either a signed or an unsigned number.
+fn in_bits(v: i64, bits: u32) -> bool {
+    v >= -(1 << (bits - 1)) && v < 1 << bits
+}
+
+/// Checks that an immediate value fits in `bits`, as either a signed or an
+/// unsigned number. A byte takes a 16-bit value, cut to its low byte with a
+/// warning from `truncations`.
+fn immediate(d: i32, bits: u32) -> Result<u16, String> {
+    if !in_bits(d as i64, bits.max(16)) {
+        return Err(format!("Immediate value {} does not fit in {} bits", d, bits));
     }
+    Ok(d as u16)
+}
 
-    fn jc(&self) -> Result<Vec<u8>, String> {
-        let op = "JC";
-        if self.ops.len() < 1 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 1 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match self.ops[0] {
-            Addr16(d) => Ok(vec![0x40,self.short_jmp(d)?]),
-            ref a@_ => Err(format!("Invalid operation: {} {:?}",op, a)),
-        }
+/// Checks an address in the 256 byte data or bit space.
+fn data_address(a: i32, what: &str) -> Result<u8, String> {
+    if !(0..=0xFF).contains(&a) {
+        return Err(format!("{} {} is outside 00h-0FFh", what, hex(a)));
     }
+    Ok(a as u8)
+}
 
-    fn jnc(&self) -> Result<Vec<u8>, String> {
-        let op = "JNC";
-        if self.ops.len() < 1 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 1 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match self.ops[0] {
-            Addr16(d) => Ok(vec![0x50,self.short_jmp(d)?]),
-            ref a@_ => Err(format!("Invalid operation: {} {:?}",op, a)),
-        }
+fn hex(v: i32) -> String {
+    if v < 0 { format!("-{:X}h", -(v as i64)) } else { format!("{:X}h", v) }
+}
+
+/// Checks an address in the 64K code space.
+fn code_address(a: i32) -> Result<u16, String> {
+    if !(0..=0xFFFF).contains(&a) {
+        return Err(format!("Address {} is outside 0000h-0FFFFh", hex(a)));
     }
+    Ok(a as u16)
+}
 
-    fn jz(&self) -> Result<Vec<u8>, String> {
-        let op = "JZ";
-        if self.ops.len() < 1 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 1 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match self.ops[0] {
-            Addr16(d) => Ok(vec![0x60,self.short_jmp(d)?]),
-            ref a@_ => Err(format!("Invalid operation: {} {:?}",op, a)),
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use line;
+    use opcode::OPCODES;
+
+    /// Every opcode with a known-good encoding, assembled at address 0.
+    /// `near` is 10h, `far` 1234h and `pageN` is 23h into the Nth 256 byte
+    /// block of the first 2K page.
+    const ENCODINGS: &[(&str, &[u8])] = &[
+        ("nop", &[0x00]),
+        ("ajmp near", &[0x01, 0x10]),
+        ("acall near", &[0x11, 0x10]),
+        ("ajmp page1", &[0x21, 0x23]),
+        ("acall page1", &[0x31, 0x23]),
+        ("ajmp page2", &[0x41, 0x23]),
+        ("acall page2", &[0x51, 0x23]),
+        ("ajmp page3", &[0x61, 0x23]),
+        ("acall page3", &[0x71, 0x23]),
+        ("ajmp page4", &[0x81, 0x23]),
+        ("acall page4", &[0x91, 0x23]),
+        ("ajmp page5", &[0xA1, 0x23]),
+        ("acall page5", &[0xB1, 0x23]),
+        ("ajmp page6", &[0xC1, 0x23]),
+        ("acall page6", &[0xD1, 0x23]),
+        ("ajmp page7", &[0xE1, 0x23]),
+        ("acall page7", &[0xF1, 0x23]),
+        ("ljmp far", &[0x02, 0x12, 0x34]),
+        ("lcall far", &[0x12, 0x12, 0x34]),
+        ("rr a", &[0x03]),
+        ("rrc a", &[0x13]),
+        ("rl a", &[0x23]),
+        ("rlc a", &[0x33]),
+        ("inc a", &[0x04]),
+        ("inc 30h", &[0x05, 0x30]),
+        ("inc @r0", &[0x06]),
+        ("inc @r1", &[0x07]),
+        ("inc r0", &[0x08]),
+        ("inc r1", &[0x09]),
+        ("inc r2", &[0x0A]),
+        ("inc r3", &[0x0B]),
+        ("inc r4", &[0x0C]),
+        ("inc r5", &[0x0D]),
+        ("inc r6", &[0x0E]),
+        ("inc r7", &[0x0F]),
+        ("dec a", &[0x14]),
+        ("dec 30h", &[0x15, 0x30]),
+        ("dec @r0", &[0x16]),
+        ("dec @r1", &[0x17]),
+        ("dec r0", &[0x18]),
+        ("dec r1", &[0x19]),
+        ("dec r2", &[0x1A]),
+        ("dec r3", &[0x1B]),
+        ("dec r4", &[0x1C]),
+        ("dec r5", &[0x1D]),
+        ("dec r6", &[0x1E]),
+        ("dec r7", &[0x1F]),
+        ("jbc 21h, near", &[0x10, 0x21, 0x0D]),
+        ("jb 21h, near", &[0x20, 0x21, 0x0D]),
+        ("jnb 21h, near", &[0x30, 0x21, 0x0D]),
+        ("ret", &[0x22]),
+        ("reti", &[0x32]),
+        ("add a, #55h", &[0x24, 0x55]),
+        ("add a, 30h", &[0x25, 0x30]),
+        ("add a, @r0", &[0x26]),
+        ("add a, @r1", &[0x27]),
+        ("add a, r0", &[0x28]),
+        ("add a, r1", &[0x29]),
+        ("add a, r2", &[0x2A]),
+        ("add a, r3", &[0x2B]),
+        ("add a, r4", &[0x2C]),
+        ("add a, r5", &[0x2D]),
+        ("add a, r6", &[0x2E]),
+        ("add a, r7", &[0x2F]),
+        ("addc a, #55h", &[0x34, 0x55]),
+        ("addc a, 30h", &[0x35, 0x30]),
+        ("addc a, @r0", &[0x36]),
+        ("addc a, @r1", &[0x37]),
+        ("addc a, r0", &[0x38]),
+        ("addc a, r1", &[0x39]),
+        ("addc a, r2", &[0x3A]),
+        ("addc a, r3", &[0x3B]),
+        ("addc a, r4", &[0x3C]),
+        ("addc a, r5", &[0x3D]),
+        ("addc a, r6", &[0x3E]),
+        ("addc a, r7", &[0x3F]),
+        ("subb a, #55h", &[0x94, 0x55]),
+        ("subb a, 30h", &[0x95, 0x30]),
+        ("subb a, @r0", &[0x96]),
+        ("subb a, @r1", &[0x97]),
+        ("subb a, r0", &[0x98]),
+        ("subb a, r1", &[0x99]),
+        ("subb a, r2", &[0x9A]),
+        ("subb a, r3", &[0x9B]),
+        ("subb a, r4", &[0x9C]),
+        ("subb a, r5", &[0x9D]),
+        ("subb a, r6", &[0x9E]),
+        ("subb a, r7", &[0x9F]),
+        ("orl a, #55h", &[0x44, 0x55]),
+        ("orl a, 30h", &[0x45, 0x30]),
+        ("orl a, @r0", &[0x46]),
+        ("orl a, @r1", &[0x47]),
+        ("orl a, r0", &[0x48]),
+        ("orl a, r1", &[0x49]),
+        ("orl a, r2", &[0x4A]),
+        ("orl a, r3", &[0x4B]),
+        ("orl a, r4", &[0x4C]),
+        ("orl a, r5", &[0x4D]),
+        ("orl a, r6", &[0x4E]),
+        ("orl a, r7", &[0x4F]),
+        ("anl a, #55h", &[0x54, 0x55]),
+        ("anl a, 30h", &[0x55, 0x30]),
+        ("anl a, @r0", &[0x56]),
+        ("anl a, @r1", &[0x57]),
+        ("anl a, r0", &[0x58]),
+        ("anl a, r1", &[0x59]),
+        ("anl a, r2", &[0x5A]),
+        ("anl a, r3", &[0x5B]),
+        ("anl a, r4", &[0x5C]),
+        ("anl a, r5", &[0x5D]),
+        ("anl a, r6", &[0x5E]),
+        ("anl a, r7", &[0x5F]),
+        ("xrl a, #55h", &[0x64, 0x55]),
+        ("xrl a, 30h", &[0x65, 0x30]),
+        ("xrl a, @r0", &[0x66]),
+        ("xrl a, @r1", &[0x67]),
+        ("xrl a, r0", &[0x68]),
+        ("xrl a, r1", &[0x69]),
+        ("xrl a, r2", &[0x6A]),
+        ("xrl a, r3", &[0x6B]),
+        ("xrl a, r4", &[0x6C]),
+        ("xrl a, r5", &[0x6D]),
+        ("xrl a, r6", &[0x6E]),
+        ("xrl a, r7", &[0x6F]),
+        ("orl 30h, a", &[0x42, 0x30]),
+        ("orl 30h, #55h", &[0x43, 0x30, 0x55]),
+        ("anl 30h, a", &[0x52, 0x30]),
+        ("anl 30h, #55h", &[0x53, 0x30, 0x55]),
+        ("xrl 30h, a", &[0x62, 0x30]),
+        ("xrl 30h, #55h", &[0x63, 0x30, 0x55]),
+        ("jc near", &[0x40, 0x0E]),
+        ("jnc near", &[0x50, 0x0E]),
+        ("jz near", &[0x60, 0x0E]),
+        ("jnz near", &[0x70, 0x0E]),
+        ("sjmp near", &[0x80, 0x0E]),
+        ("orl c, 21h", &[0x72, 0x21]),
+        ("anl c, 21h", &[0x82, 0x21]),
+        ("orl c, /21h", &[0xA0, 0x21]),
+        ("anl c, /21h", &[0xB0, 0x21]),
+        ("jmp @a+dptr", &[0x73]),
+        ("mov a, #55h", &[0x74, 0x55]),
+        ("mov 30h, #55h", &[0x75, 0x30, 0x55]),
+        ("mov @r0, #55h", &[0x76, 0x55]),
+        ("mov @r1, #55h", &[0x77, 0x55]),
+        ("mov r0, #55h", &[0x78, 0x55]),
+        ("mov r1, #55h", &[0x79, 0x55]),
+        ("mov r2, #55h", &[0x7A, 0x55]),
+        ("mov r3, #55h", &[0x7B, 0x55]),
+        ("mov r4, #55h", &[0x7C, 0x55]),
+        ("mov r5, #55h", &[0x7D, 0x55]),
+        ("mov r6, #55h", &[0x7E, 0x55]),
+        ("mov r7, #55h", &[0x7F, 0x55]),
+        ("movc a, @a+pc", &[0x83]),
+        ("movc a, @a+dptr", &[0x93]),
+        ("div ab", &[0x84]),
+        ("mul ab", &[0xA4]),
+        ("mov 30h, 40h", &[0x85, 0x40, 0x30]),
+        ("mov 30h, @r0", &[0x86, 0x30]),
+        ("mov 30h, @r1", &[0x87, 0x30]),
+        ("mov 30h, r0", &[0x88, 0x30]),
+        ("mov 30h, r1", &[0x89, 0x30]),
+        ("mov 30h, r2", &[0x8A, 0x30]),
+        ("mov 30h, r3", &[0x8B, 0x30]),
+        ("mov 30h, r4", &[0x8C, 0x30]),
+        ("mov 30h, r5", &[0x8D, 0x30]),
+        ("mov 30h, r6", &[0x8E, 0x30]),
+        ("mov 30h, r7", &[0x8F, 0x30]),
+        ("mov dptr, #1234h", &[0x90, 0x12, 0x34]),
+        ("mov 21h, c", &[0x92, 0x21]),
+        ("mov c, 21h", &[0xA2, 0x21]),
+        ("inc dptr", &[0xA3]),
+        ("mov @r0, 30h", &[0xA6, 0x30]),
+        ("mov @r1, 30h", &[0xA7, 0x30]),
+        ("mov r0, 30h", &[0xA8, 0x30]),
+        ("mov r1, 30h", &[0xA9, 0x30]),
+        ("mov r2, 30h", &[0xAA, 0x30]),
+        ("mov r3, 30h", &[0xAB, 0x30]),
+        ("mov r4, 30h", &[0xAC, 0x30]),
+        ("mov r5, 30h", &[0xAD, 0x30]),
+        ("mov r6, 30h", &[0xAE, 0x30]),
+        ("mov r7, 30h", &[0xAF, 0x30]),
+        ("cpl 21h", &[0xB2, 0x21]),
+        ("cpl c", &[0xB3]),
+        ("cpl a", &[0xF4]),
+        ("cjne a, #55h, near", &[0xB4, 0x55, 0x0D]),
+        ("cjne a, 30h, near", &[0xB5, 0x30, 0x0D]),
+        ("cjne @r0, #55h, near", &[0xB6, 0x55, 0x0D]),
+        ("cjne @r1, #55h, near", &[0xB7, 0x55, 0x0D]),
+        ("cjne r0, #55h, near", &[0xB8, 0x55, 0x0D]),
+        ("cjne r1, #55h, near", &[0xB9, 0x55, 0x0D]),
+        ("cjne r2, #55h, near", &[0xBA, 0x55, 0x0D]),
+        ("cjne r3, #55h, near", &[0xBB, 0x55, 0x0D]),
+        ("cjne r4, #55h, near", &[0xBC, 0x55, 0x0D]),
+        ("cjne r5, #55h, near", &[0xBD, 0x55, 0x0D]),
+        ("cjne r6, #55h, near", &[0xBE, 0x55, 0x0D]),
+        ("cjne r7, #55h, near", &[0xBF, 0x55, 0x0D]),
+        ("push 30h", &[0xC0, 0x30]),
+        ("pop 30h", &[0xD0, 0x30]),
+        ("clr 21h", &[0xC2, 0x21]),
+        ("clr c", &[0xC3]),
+        ("clr a", &[0xE4]),
+        ("setb 21h", &[0xD2, 0x21]),
+        ("setb c", &[0xD3]),
+        ("swap a", &[0xC4]),
+        ("da a", &[0xD4]),
+        ("xch a, 30h", &[0xC5, 0x30]),
+        ("xch a, @r0", &[0xC6]),
+        ("xch a, @r1", &[0xC7]),
+        ("xch a, r0", &[0xC8]),
+        ("xch a, r1", &[0xC9]),
+        ("xch a, r2", &[0xCA]),
+        ("xch a, r3", &[0xCB]),
+        ("xch a, r4", &[0xCC]),
+        ("xch a, r5", &[0xCD]),
+        ("xch a, r6", &[0xCE]),
+        ("xch a, r7", &[0xCF]),
+        ("djnz 30h, near", &[0xD5, 0x30, 0x0D]),
+        ("xchd a, @r0", &[0xD6]),
+        ("xchd a, @r1", &[0xD7]),
+        ("djnz r0, near", &[0xD8, 0x0E]),
+        ("djnz r1, near", &[0xD9, 0x0E]),
+        ("djnz r2, near", &[0xDA, 0x0E]),
+        ("djnz r3, near", &[0xDB, 0x0E]),
+        ("djnz r4, near", &[0xDC, 0x0E]),
+        ("djnz r5, near", &[0xDD, 0x0E]),
+        ("djnz r6, near", &[0xDE, 0x0E]),
+        ("djnz r7, near", &[0xDF, 0x0E]),
+        ("movx a, @dptr", &[0xE0]),
+        ("movx @dptr, a", &[0xF0]),
+        ("movx a, @r0", &[0xE2]),
+        ("movx @r0, a", &[0xF2]),
+        ("movx a, @r1", &[0xE3]),
+        ("movx @r1, a", &[0xF3]),
+        ("mov a, 30h", &[0xE5, 0x30]),
+        ("mov a, @r0", &[0xE6]),
+        ("mov a, @r1", &[0xE7]),
+        ("mov a, r0", &[0xE8]),
+        ("mov a, r1", &[0xE9]),
+        ("mov a, r2", &[0xEA]),
+        ("mov a, r3", &[0xEB]),
+        ("mov a, r4", &[0xEC]),
+        ("mov a, r5", &[0xED]),
+        ("mov a, r6", &[0xEE]),
+        ("mov a, r7", &[0xEF]),
+        ("mov 30h, a", &[0xF5, 0x30]),
+        ("mov @r0, a", &[0xF6]),
+        ("mov @r1, a", &[0xF7]),
+        ("mov r0, a", &[0xF8]),
+        ("mov r1, a", &[0xF9]),
+        ("mov r2, a", &[0xFA]),
+        ("mov r3, a", &[0xFB]),
+        ("mov r4, a", &[0xFC]),
+        ("mov r5, a", &[0xFD]),
+        ("mov r6, a", &[0xFE]),
+        ("mov r7, a", &[0xFF]),
+    ];
+
+    fn table() -> Vec<Symbol> {
+        let mut table = vec![
+            Symbol::new(String::from("near"), 0x10, SymbolKind::Code, 1),
+            Symbol::new(String::from("far"), 0x1234, SymbolKind::Code, 1),
+        ];
+        for n in 1..8 {
+            table.push(Symbol::new(format!("page{}", n), n * 0x100 + 0x23, SymbolKind::Code, 1));
+        }
+        table
     }
 
-    fn jnz(&self) -> Result<Vec<u8>, String> {
-        let op = "JNZ";
-        if self.ops.len() < 1 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 1 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match self.ops[0] {
-            Addr16(d) => Ok(vec![0x70,self.short_jmp(d)?]),
-            ref a@_ => Err(format!("Invalid operation: {} {:?}",op, a)),
-        }
+    fn assemble(source: &str) -> Result<Vec<u8>, String> {
+        let line = line::get_lines(source.to_string()).remove(0);
+        let mut ins = Instruction::from_line(line, 0).map_err(|e| e.1)?;
+        let len = ins.len();
+        ins.fix_label(&table(), 0).map_err(|e| e.1)?;
+        let bytes = ins.to_hex().map_err(|e| e.1)?;
+        assert_eq!(len, ins.len(), "length of {} changed when its labels were resolved", source);
+        assert_eq!(bytes.len() as i32, len, "length of {}", source);
+        Ok(bytes)
     }
 
-    fn jmp(&self) -> Result<Vec<u8>, String> {
-        let op = "JMP";
-        if self.ops.len() < 1 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 1 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match self.ops[0] {
-            AtADptr => Ok(vec![0x73]),
-            ref a@_ => Err(format!("Invalid operation: {} {:?}",op, a)),
+    #[test]
+    fn encodes_every_form() {
+        for &(source, expected) in ENCODINGS {
+            match assemble(source) {
+                Ok(bytes) => assert_eq!(bytes, expected, "{}", source),
+                Err(e) => panic!("{}: {}", source, e),
+            }
         }
     }
 
-    fn mul(&self) -> Result<Vec<u8>, String> {
-        let op = "MUL";
-        if self.ops.len() < 1 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 1 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match self.ops[0] {
-            AB => Ok(vec![0xA4]),
-            ref a@_ => Err(format!("Invalid operation: {} {:?}",op, a)),
-        }
+    #[test]
+    fn covers_every_opcode() {
+        let mut covered: Vec<u8> = ENCODINGS.iter().map(|x| x.1[0]).collect();
+        covered.sort();
+        covered.dedup();
+        let defined: Vec<u8> = OPCODES.iter().map(|x| x.code).collect();
+        assert_eq!(covered, defined);
+        assert_eq!(defined.len(), 255);
+        assert!(!defined.contains(&0xA5));
     }
 
-    fn div(&self) -> Result<Vec<u8>, String> {
-        let op = "DIV";
-        if self.ops.len() < 1 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 1 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match self.ops[0] {
-            AB => Ok(vec![0x84]),
-            ref a@_ => Err(format!("Invalid operation: {} {:?}",op, a)),
+    #[test]
+    fn opcode_table_lengths_match() {
+        for &(source, expected) in ENCODINGS {
+            let form = OPCODES.iter().find(|x| x.code == expected[0]).unwrap();
+            assert_eq!(form.len(), expected.len(), "{}", source);
         }
     }
 
-    fn swap(&self) -> Result<Vec<u8>, String> {
-        let op = "SWAP";
-        if self.ops.len() < 1 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 1 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match self.ops[0] {
-            A => Ok(vec![0xC4]),
-            ref a@_ => Err(format!("Invalid operation: {} {:?}",op, a)),
+    #[test]
+    fn rejects_invalid_forms() {
+        for source in &["mov a, #12345h", "mov @r0, @r1", "mov", "nop a", "ajmp far", "sjmp far"] {
+            assert!(assemble(source).is_err(), "{}", source);
         }
     }
 
-    fn da(&self) -> Result<Vec<u8>, String> {
-        let op = "DA";
-        if self.ops.len() < 1 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 1 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
+    #[test]
+    fn reads_every_number_format() {
+        let formats = ["#165", "#165d", "#0A5h", "#0A5H", "#0xA5", "#$A5", "#10100101b", "#0b1010_0101",
+            "#245o", "#245q", "#'\\xA5'", "#0A0h + 5"];
+        for format in &formats {
+            let source = format!("mov a, {}", format);
+            assert_eq!(assemble(&source).ok(), Some(vec![0x74, 0xA5]), "{}", source);
         }
-        match self.ops[0] {
-            A => Ok(vec![0xD4]),
-            ref a@_ => Err(format!("Invalid operation: {} {:?}",op, a)),
+        assert_eq!(assemble("mov dptr, #'AB'"), Ok(vec![0x90, 0x41, 0x42]));
+        for source in &["mov a, #A5h", "mov a, #12b", "mov a, #8q", "mov a, #0x"] {
+            assert!(assemble(source).is_err(), "{}", source);
         }
     }
 
-    fn jnb(&self) -> Result<Vec<u8>, String> {
-        let op = "JNB";
-        if self.ops.len() < 2 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 2 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match (self.ops.index(0), self.ops.index(1)) {
-            (&Addr(a),&Addr16(d)) => Ok(vec![0x30,a,self.short_jmp(d)?]),
-            (a@_,b@_) => Err(format!("Invalid operation: {} {:?},{:?}",op, a,b)),
-        }
+    #[test]
+    fn reports_the_column_of_the_operand_at_fault() {
+        let read = |source: &str| Instruction::from_line(line::get_lines(source.to_string()).remove(0), 0);
+        assert_eq!(read(" mov a, #HIGH").unwrap_err().0, Some(9));
+        assert_eq!(read(" frob a").unwrap_err().0, Some(2));
+        assert_eq!(read(" mov r0, #70000").unwrap().to_hex().unwrap_err().0, Some(10));
+        assert_eq!(read(" db 1, 2, 99999").unwrap().to_hex().unwrap_err().0, Some(11));
+        assert_eq!(read(" sjmp nowhere").unwrap().fix_label(&table(), 0).unwrap_err().0, Some(7));
     }
 
-    fn jbc(&self) -> Result<Vec<u8>, String> {
-        let op = "JBC";
-        if self.ops.len() < 2 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 2 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match (self.ops.index(0),self.ops.index(1)) {
-            (&Addr(b),&Addr16(d)) => Ok(vec![0x10,b,self.short_jmp(d)?]),
-            (ref a@_,ref b@_) => Err(format!("Invalid operation: {} {:?},{:?}",op, a,b)),
+    #[test]
+    fn sizes_operands_by_form() {
+        assert_eq!(assemble("ljmp 10h"), Ok(vec![0x02, 0x00, 0x10]));
+        assert_eq!(assemble("lcall 5"), Ok(vec![0x12, 0x00, 0x05]));
+        assert_eq!(assemble("mov dptr, #10h"), Ok(vec![0x90, 0x00, 0x10]));
+        assert_eq!(assemble("mov dptr, #-1"), Ok(vec![0x90, 0xFF, 0xFF]));
+        assert_eq!(assemble("mov 30h, #-1"), Ok(vec![0x75, 0x30, 0xFF]));
+        // A 16-bit value is cut to a byte with a warning, anything larger fails
+        assert_eq!(assemble("mov a, #1234h"), Ok(vec![0x74, 0x34]));
+        assert_eq!(assemble("db 256, -129"), Ok(vec![0x00, 0x7F]));
+        for source in &["mov a, #10000h", "mov a, #-8001h", "db 10000h", "mov 100h, a", "setb 100h", "mov dptr, #10000h", "ljmp 10000h"] {
+            assert!(assemble(source).is_err(), "{}", source);
         }
     }
 
-    fn xchd(&self) -> Result<Vec<u8>, String> {
-        let op = "XCHD";
-        if self.ops.len() < 2 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 2 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match (self.ops.index(0),self.ops.index(1)) {
-            (&A,&AtR0) => Ok(vec![0xD6]),
-            (&A,&AtR1) => Ok(vec![0xD7]),
-            (ref a@_,ref b@_) => Err(format!("Invalid operation: {} {:?},{:?}",op, a,b)),
-        }
+    #[test]
+    fn encodes_data_lists() {
+        assert_eq!(assemble("db 'a\\nb', '\\\\', 'it''s', \"say \"\"hi\"\"\""), Ok(b"a\nb\\it'ssay \"hi\"".to_vec()));
+        assert_eq!(assemble("db 'a,b:c;d', 0 ; comment"), Ok(b"a,b:c;d\0".to_vec()));
+        // Words and double words are big-endian
+        assert_eq!(assemble("dw 1234h, -2"), Ok(vec![0x12, 0x34, 0xFF, 0xFE]));
+        assert_eq!(assemble("dd 12345678h, -2"), Ok(vec![0x12, 0x34, 0x56, 0x78, 0xFF, 0xFF, 0xFF, 0xFE]));
+        // A string in DW or DD is one value, its last character lowest
+        assert_eq!(assemble("dw 'AB', 'C'"), Ok(vec![0x41, 0x42, 0x00, 0x43]));
+        assert_eq!(assemble("dd 'ABC'"), Ok(vec![0x00, 0x41, 0x42, 0x43]));
+        assert_eq!(assemble("dw 'ABC'"), Err(String::from("String is longer than 2 characters for DW")));
     }
 
-    fn db(&self) -> Result<Vec<u8>, String> {
-        //TODO: make this better
-        let op = "DB";
-        if self.ops.len() < 1 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        let mut vec_ret = Vec::new();
-        for tmp_op in &self.ops{
-            match tmp_op {
-                &Label(ref l) => {
-                    let mut v = l.clone().into_bytes();
-                    v.remove(l.len()-1);
-                    v.remove(0);
-                    v.push(0);
-                    vec_ret.append(&mut v);
-                },
-                &Addr(d) => {
-                    vec_ret.push(d);
-                },
-                ref a@_ => return Err(format!("Invalid operation: {} {:?}",op, a)),
-            }
+    /// The records `source` assembles to, as address and bytes.
+    fn program(source: &str) -> Vec<(u16, Vec<u8>)> {
+        match ::assemble(source) {
+            Ok(records) => records.iter().filter(|x| !x.data().is_empty()).map(|x| (x.offset(), x.data())).collect(),
+            Err(e) => panic!("{}: {:?}", source, e.iter().map(|x| x.describe()).collect::<Vec<_>>()),
         }
-        Ok(vec_ret)
     }
 
-    fn ds(&self) -> Result<Vec<u8>, String> {
-        //TODO: make this better
-        let op = "DS";
-        if self.ops.len() < 1 {
-            return Err(format!("Too few arguments for {}: {}",op,self.num));
-        }
-        if self.ops.len() > 1 {
-            return Err(format!("Too many arguments for {}: {}",op,self.num));
-        }
-        match self.ops[0] {
-            Addr(d) => Ok(vec![0x10;d as usize]),
-            ref a@_ => Err(format!("Invalid operation: {} {:?}",op, a)),
-        }
+    #[test]
+    fn picks_sjmp_ajmp_or_ljmp_for_jmp() {
+        assert_eq!(program(" jmp next\n org 81h\nnext: nop\n end\n")[0], (0, vec![0x80, 0x7F]));
+        assert_eq!(program(" jmp next\n org 82h\nnext: nop\n end\n")[0], (0, vec![0x01, 0x82]));
+        assert_eq!(program(" jmp next\n org 800h\nnext: nop\n end\n")[0], (0, vec![0x02, 0x08, 0x00]));
+        assert_eq!(program(" org 100h\nback: nop\n org 17Eh\n jmp back\n end\n")[1], (0x17E, vec![0x80, 0x80]));
+        assert_eq!(program(" org 100h\nback: nop\n org 17Fh\n jmp back\n end\n")[1], (0x17F, vec![0x21, 0x00]));
+        assert_eq!(program(" org 100h\nback: nop\n org 1000h\n jmp back\n end\n")[1], (0x1000, vec![0x02, 0x01, 0x00]));
     }
 
-}
+    #[test]
+    fn picks_acall_or_lcall_for_call() {
+        // The page is that of the next instruction, PC+2
+        assert_eq!(program(" org 7FEh\n call sub\n org 900h\nsub: ret\n end\n")[0], (0x7FE, vec![0x31, 0x00]));
+        assert_eq!(program(" org 7FDh\n call sub\n org 900h\nsub: ret\n end\n")[0], (0x7FD, vec![0x12, 0x09, 0x00]));
+        assert_eq!(program(" org 800h\nsub: ret\n org 0FFDh\n call sub\n end\n")[1], (0xFFD, vec![0x11, 0x00]));
+        assert_eq!(program(" org 800h\nsub: ret\n org 0FFEh\n call sub\n end\n")[1], (0xFFE, vec![0x12, 0x08, 0x00]));
+    }
 
-fn other_op(mut op: String) -> Result<OpType, String> {
-    op.trim();
-    if op.starts_with("#") {
-        op.remove(0);
-        if op.ends_with("h") {
-            let len = op.len();
-            op.remove(len -1);
-            match u8::from_str_radix(&op, 16){
-                Ok(d) => return Ok(Data(d)),
-                Err(_) => {},
-            };
-            return  match u16::from_str_radix(&op, 16){
-                Ok(d) => Ok(Data16(d)),
-                Err(_) => return Err(String::from("Invalid Data")),
-            }
-        }
-        match u8::from_str_radix(&op, 10) {
-            Ok(a) => return Ok(Data(a)),
-            Err(_) => {},
-        }
-        match u16::from_str_radix(&op, 10) {
-            Ok(a) => return Ok(Data16(a)),
-            Err(_) => return Err(String::from("Invalid Data")),
-        }
+    /// The first record and the notes from assembling `source` with `-r`.
+    fn relaxed(source: &str) -> (Vec<u8>, Vec<String>) {
+        let build = ::build(source, &[], &::Options::parse(&[String::from("-r")], false).0, Vec::new());
+        let notes = build.diagnostics.iter().map(|x| x.describe()).collect();
+        (build.records.first().map(|x| x.data()).unwrap_or_default(), notes)
     }
-    if op.ends_with("h") {
-        let len = op.len();
-        let mut temp = op.clone();
-        temp.remove(len -1);
-        match u8::from_str_radix(&temp, 16){
-            Ok(a) => return Ok( Addr(a)),
-            Err(_) => {},
-        }
-        match u16::from_str_radix(&temp, 16){
-            Ok(a) => return Ok( Addr16(a)),
-            Err(_) => return Err(String::from("Invalid Address")),
-        }
+
+    #[test]
+    fn relaxes_out_of_range_branches() {
+        let far = |branch: &str| relaxed(&format!(" {}\n org 800h\nfar: sjmp far\n end\n", branch));
+        assert_eq!(far("jz far"), (vec![0x70, 0x03, 0x02, 0x08, 0x00],
+            vec![String::from("note: JZ out of range, rewritten as JNZ over LJMP")]));
+        assert_eq!(far("jb p1.0, far"), (vec![0x30, 0x90, 0x03, 0x02, 0x08, 0x00],
+            vec![String::from("note: JB out of range, rewritten as JNB over LJMP")]));
+        assert_eq!(far("djnz r7, far"), (vec![0xDF, 0x02, 0x80, 0x03, 0x02, 0x08, 0x00],
+            vec![String::from("note: DJNZ out of range, rewritten as DJNZ to LJMP with SJMP over it")]));
+        assert_eq!(far("cjne a, #1, far"), (vec![0xB4, 0x01, 0x02, 0x80, 0x03, 0x02, 0x08, 0x00],
+            vec![String::from("note: CJNE out of range, rewritten as CJNE to LJMP with SJMP over it")]));
+        assert_eq!(far("sjmp far"), (vec![0x02, 0x08, 0x00],
+            vec![String::from("note: SJMP out of range, rewritten as LJMP")]));
+        // In range, nothing changes
+        assert_eq!(relaxed(" jz far\nfar: sjmp far\n end\n"), (vec![0x60, 0x00, 0x80, 0xFE], Vec::new()));
     }
-    if op.starts_with("0x") {
-        let mut temp = op.clone();
-        temp.remove(0);
-        temp.remove(0);
-        match u8::from_str_radix(&temp, 16){
-            Ok(a) => return Ok( Addr(a)),
-            Err(_) => {},
-        }
-        match u16::from_str_radix(&temp, 16){
-            Ok(a) => return Ok( Addr16(a)),
-            Err(_) => return Err(String::from("Invalid Address")),
+
+    #[test]
+    fn relaxes_until_the_layout_settles() {
+        // The first branch is in range until the second one grows
+        let (bytes, notes) = relaxed(" jz next\n jz far\n ds 125\nnext: sjmp next\n org 800h\nfar: sjmp far\n end\n");
+        assert_eq!(bytes[..9], [0x70, 0x02, 0x01, 0x86, 0x70, 0x03, 0x02, 0x08, 0x00]);
+        assert_eq!(notes, ["note: JZ out of range, rewritten as JNZ over AJMP", "note: JZ out of range, rewritten as JNZ over LJMP"]);
+    }
+
+    #[test]
+    fn relaxes_only_with_the_option() {
+        assert_eq!(assemble("jz far").unwrap_err(), "Branch target 1234 is +4658 bytes from the end of the instruction, \
+            outside the range -128 to +127 (use -r to relax it)");
+        assert!(::assemble(" jz far\n org 800h\nfar: sjmp far\n end\n").is_err());
+    }
+
+    /// The bytes of the branch in `source`, or the message of its error.
+    fn branch(source: &str) -> Result<Vec<u8>, String> {
+        match ::assemble(source) {
+            Ok(records) => Ok(records.iter().find(|x| x.data().len() > 1).map(|x| x.data()).unwrap_or_default()),
+            Err(e) => Err(e.iter().map(|x| x.message.clone()).collect::<Vec<_>>().join("\n")),
         }
     }
-    match u8::from_str_radix(&op, 10){
-        Ok(a) => return Ok( Addr(a)),
-        Err(_) => {},
+
+    #[test]
+    fn checks_the_range_of_relative_branches() {
+        for &(mnemonic, code) in &[("sjmp", 0x80), ("jnz", 0x70)] {
+            let forward = |org: &str| branch(&format!(" {} next\n org {}\nnext: nop\n end\n", mnemonic, org));
+            let back = |org: &str| branch(&format!(" org 100h\nback: nop\n org {}\n {} back\n end\n", org, mnemonic));
+            assert_eq!(forward("81h"), Ok(vec![code, 0x7F]));
+            assert!(forward("82h").unwrap_err().contains("is +128 bytes from the end"), "{}", mnemonic);
+            assert_eq!(back("17Eh"), Ok(vec![code, 0x80]));
+            assert!(back("17Fh").unwrap_err().contains("is -129 bytes from the end"), "{}", mnemonic);
+        }
     }
-    match u16::from_str_radix(&op, 10){
-        Ok(a) => return Ok( Addr16(a)),
-        Err(_) => {},
+
+    #[test]
+    fn checks_the_page_of_absolute_jumps() {
+        // The page is that of PC+2, so the last two bytes of a page reach the next one
+        let at = |org: &str, ins: &str| branch(&format!(" org {}\n {}\n end\n", org, ins));
+        assert_eq!(at("7FDh", "ajmp 7FFh"), Ok(vec![0xE1, 0xFF]));
+        assert_eq!(at("7FEh", "ajmp 900h"), Ok(vec![0x21, 0x00]));
+        assert_eq!(at("7FEh", "ajmp 7FFh"), Err(String::from("Target 07FF is outside the 2K page of PC+2 (0800-0FFF)")));
+        assert_eq!(at("7FEh", "acall 900h"), Ok(vec![0x31, 0x00]));
+        assert_eq!(at("7FDh", "acall 800h"), Err(String::from("Target 0800 is outside the 2K page of PC+2 (0000-07FF)")));
     }
-    match u64::from_str_radix(&op, 10) {
-        Ok(_) => return Err(String::from("Invalid Address")),
-        Err(_) => {},
-    };
-    Ok(Label(op))
 }
@@ -0,0 +1,150 @@
+/// The kinds of token in a source line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// A name: mnemonic, label, symbol, register or keyword operator.
+    Ident,
+    /// A numeric literal in any radix, still as written.
+    Number,
+    /// `"text"`
+    Str,
+    /// `'c'`
+    Char,
+    /// `+ - * / ( ) . $ = <> < <= > >=` and any other symbol.
+    Operator,
+    At,
+    Hash,
+    Comma,
+    Colon,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: Kind,
+    /// The token as written, quotes included.
+    pub text: String,
+    /// Column of the first character, starting at 1.
+    pub column: usize,
+    /// Byte range of the token in the line.
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Splits a line into tokens, stopping at a `;` comment. Errors give the
+/// column they were found at.
+pub fn tokenize(line: &str) -> Result<Vec<Token>, (usize, String)> {
+    let mut tokens = Vec::new();
+    let mut chars = line.char_indices().peekable();
+    let mut column = 0;
+    while let Some((start, c)) = chars.next() {
+        column += 1;
+        let first = column;
+        let is_name = |c: char| c.is_alphanumeric() || c == '_' || c == '?';
+        let kind = match c {
+            ';' => break,
+            _ if c.is_whitespace() => continue,
+            // `$` alone is the location counter, `$FF` a hex number
+            _ if is_name(c) || (c == '$' && chars.peek().is_some_and(|x| x.1.is_ascii_hexdigit())) => {
+                while let Some(&(_, c)) = chars.peek() {
+                    if !is_name(c) {
+                        break;
+                    }
+                    chars.next();
+                    column += 1;
+                }
+                if c.is_ascii_digit() || c == '$' { Kind::Number } else { Kind::Ident }
+            },
+            '"' | '\'' => {
+                let mut closed = false;
+                while let Some((_, q)) = chars.next() {
+                    column += 1;
+                    if q == '\\' {
+                        if chars.next().is_some() {
+                            column += 1;
+                        }
+                    } else if q == c && chars.peek().is_some_and(|x| x.1 == c) {
+                        // A doubled quote stands for itself
+                        chars.next();
+                        column += 1;
+                    } else if q == c {
+                        closed = true;
+                        break;
+                    }
+                }
+                if !closed {
+                    return Err((first, String::from("Unterminated string")));
+                }
+                if c == '"' { Kind::Str } else { Kind::Char }
+            },
+            '@' => Kind::At,
+            '#' => Kind::Hash,
+            ',' => Kind::Comma,
+            ':' => Kind::Colon,
+            '<' | '>' => {
+                if let Some(&(_, next)) = chars.peek() {
+                    if next == '=' || (c == '<' && next == '>') {
+                        chars.next();
+                        column += 1;
+                    }
+                }
+                Kind::Operator
+            },
+            _ => Kind::Operator,
+        };
+        let end = chars.peek().map(|x| x.0).unwrap_or(line.len());
+        tokens.push(Token { kind, text: line[start..end].to_string(), column: first, start, end });
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The kind and text of each token.
+    fn tokens(line: &str) -> Vec<(Kind, String)> {
+        tokenize(line).unwrap().into_iter().map(|x| (x.kind, x.text)).collect()
+    }
+
+    #[test]
+    fn tells_the_location_counter_from_hex() {
+        assert_eq!(tokens("$ + $1F - $"), [(Kind::Operator, String::from("$")), (Kind::Operator, String::from("+")),
+            (Kind::Number, String::from("$1F")), (Kind::Operator, String::from("-")), (Kind::Operator, String::from("$"))]);
+        assert_eq!(tokens("$G"), [(Kind::Operator, String::from("$")), (Kind::Ident, String::from("G"))]);
+    }
+
+    #[test]
+    fn reads_doubled_quotes_and_escapes_inside_strings() {
+        assert_eq!(tokens("'it''s', \"a\"\"b\", '\\''"), [(Kind::Char, String::from("'it''s'")), (Kind::Comma, String::from(",")),
+            (Kind::Str, String::from("\"a\"\"b\"")), (Kind::Comma, String::from(",")), (Kind::Char, String::from("'\\''"))]);
+        assert_eq!(tokens("'a;b' ; comment"), [(Kind::Char, String::from("'a;b'"))]);
+    }
+
+    #[test]
+    fn reports_an_unterminated_string_at_its_quote() {
+        assert_eq!(tokenize(" db 'abc"), Err((5, String::from("Unterminated string"))));
+        assert_eq!(tokenize(" db \"a\"\""), Err((5, String::from("Unterminated string"))));
+        assert_eq!(tokenize(" db 'a\\'"), Err((5, String::from("Unterminated string"))));
+    }
+
+    #[test]
+    fn gives_the_column_and_byte_range_of_each_token() {
+        // The column counts characters, the range bytes
+        let spans: Vec<(Kind, usize, usize, usize)> = tokenize("lbl: mov @r0,#\"\u{e9}\"+$<=0Fh ;x").unwrap().iter()
+            .map(|x| (x.kind, x.column, x.start, x.end))
+            .collect();
+        assert_eq!(spans, [
+            (Kind::Ident, 1, 0, 3),
+            (Kind::Colon, 4, 3, 4),
+            (Kind::Ident, 6, 5, 8),
+            (Kind::At, 10, 9, 10),
+            (Kind::Ident, 11, 10, 12),
+            (Kind::Comma, 13, 12, 13),
+            (Kind::Hash, 14, 13, 14),
+            (Kind::Str, 15, 14, 18),
+            (Kind::Operator, 18, 18, 19),
+            (Kind::Operator, 19, 19, 20),
+            (Kind::Operator, 20, 20, 22),
+            (Kind::Number, 22, 22, 25),
+        ]);
+    }
+}
@@ -1,10 +1,26 @@
 // use std::fmt::Display;
+use lexer::{self, Kind, Token};
 
+#[derive(Debug, Clone)]
 pub struct Line {
     pub label: Option<String>,
     pub mnu: Option<String>,
     pub ops: Vec<String>,
     pub num: u64,
+    /// Column of the mnemonic, for lines read from the source.
+    pub column: Option<usize>,
+    /// Column of each operand.
+    pub columns: Vec<usize>,
+    /// For a line produced by a macro, its text as shown in the listing
+    /// under the line that expanded it.
+    pub expansion: Option<String>,
+    /// For a line produced by a macro, `REPT`, `IRP` or `IRPC`, each block
+    /// expanding it with the line of the block its text came from,
+    /// innermost first.
+    pub expanded_from: Vec<(String, u64)>,
+    /// For a line that could not be read, the column and the reason. It is
+    /// reported only if conditional assembly keeps the line.
+    pub error: Option<(usize, String)>,
 }
 
 impl Line {
@@ -13,9 +29,26 @@ impl Line {
             label:label,
             mnu:mnu,
             ops:ops,
-            num:num
+            num:num,
+            column: None,
+            columns: Vec::new(),
+            expansion: None,
+            expanded_from: Vec::new(),
+            error: None,
         }
     }
+
+    /// Adds the operand made of `tokens`, which must not be empty. `next`
+    /// is the column after it, where a missing operand is reported.
+    fn operand(&mut self, text: &str, tokens: &[Token], next: usize) -> Result<(), (usize, String)> {
+        let (first, last) = match (tokens.first(), tokens.last()) {
+            (Some(f), Some(l)) => (f, l),
+            _ => return Err((next, String::from("Missing operand"))),
+        };
+        self.ops.push(text[first.start..last.end].to_string());
+        self.columns.push(first.column);
+        Ok(())
+    }
 }
 
 // impl Display for Line {
@@ -41,43 +74,82 @@ impl Line {
 //     }
 // }
 
-pub fn get_lines(file_text: String) -> Vec<Line>{
-    let mut lines= Vec::new();
-    let read_lines = file_text.lines();
-    let mut line_num = 1u64;
-    //get each line of the program
-    for mut line in read_lines {
-        let mut mnu = None;
-        let mut ops = Vec::new();
-        let mut label= None;
-        line=line.trim();
-        if line.contains(";"){
-            let tmp: Vec<&str> = line.split(';').collect();
-            line = tmp[0];
+/// Splits the source into lines of label, mnemonic and operands. A line
+/// that cannot be read is left blank with its error, so the others can
+/// still be checked.
+pub fn get_lines(file_text: String) -> Vec<Line> {
+    let mut lines = Vec::new();
+    for (i, text) in file_text.lines().enumerate() {
+        let num = i as u64 + 1;
+        match read_line(num, text) {
+            Ok(l) => lines.push(l),
+            Err(e) => lines.push(Line { error: Some(e), ..Line::new(num, None, None, Vec::new()) }),
         }
+    }
+    lines
+}
 
-        if line.contains(":"){
-            let tmp: Vec<&str> = line.split(':').collect();
-            label = Some(tmp[0].to_string());
-            line = tmp[1];
-        }
+fn read_line(num: u64, text: &str) -> Result<Line, (usize, String)> {
+    let tokens = lexer::tokenize(text)?;
+    let mut rest = &tokens[..];
+    let mut label = None;
+    // A label is a name before a colon; in a macro body it may be joined
+    // to a parameter by `&`, as in `loop&n:`
+    let colon = rest.iter().position(|t| t.kind == Kind::Colon).unwrap_or(0);
+    let name = &rest[..colon];
+    let joined = name.windows(2).all(|w| w[0].end == w[1].start);
+    if colon > 0 && joined && name[0].kind == Kind::Ident && name.iter().all(|t| matches!(t.kind, Kind::Ident | Kind::Number) || t.text == "&") {
+        label = Some(text[name[0].start..name[colon - 1].end].to_string());
+        rest = &rest[colon + 1..];
+    }
+    let mnu = match rest.first() {
+        Some(t) if t.kind == Kind::Ident => t,
+        Some(t) => return Err((t.column, format!("Expected a mnemonic, found {}", t.text))),
+        None => return Ok(Line::new(num, label, None, Vec::new())),
+    };
+    rest = &rest[1..];
+    let mut line = Line::new(num, label, Some(mnu.text.clone()), Vec::new());
+    line.column = Some(mnu.column);
 
-        let mut cmd: Vec<&str> = line.split(|c| c == ' ' || c == ',' || c == ':'|| c == '\t').collect();
-        cmd.retain(|x| x.to_string() != "");
+    // `name EQU expr`: the name is the label and the directive the mnemonic
+    if let Some(word) = rest.first() {
+        if line.label.is_none() && word.kind == Kind::Ident && is_definition(&word.text) {
+            line.label = line.mnu.take();
+            line.mnu = Some(word.text.clone());
+            line.column = Some(word.column);
+            rest = &rest[1..];
+        }
+    }
 
-        if cmd.len() > 0 {
-            mnu = Some(cmd[0].to_string());
-            if cmd.len() > 1 {
-                for i in 1 .. cmd.len(){
-                    ops.push(cmd[i].to_string())
-                }
-            }
+    // Operands are separated by commas outside parentheses, and keep the
+    // text between their first and last token (`x SHL 2`, `AT 100h`)
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, t) in rest.iter().enumerate() {
+        match (t.kind, t.text.as_ref()) {
+            (Kind::Operator, "(") => depth += 1,
+            (Kind::Operator, ")") if depth > 0 => depth -= 1,
+            (Kind::Comma, _) if depth == 0 => {
+                line.operand(text, &rest[start..i], t.column)?;
+                start = i + 1;
+            },
+            _ => {},
         }
-        let l = Line::new(line_num, label, mnu, ops);
-        lines.push(l);
-        //println!("{}",l);
-        line_num+=1;
     }
+    if !rest.is_empty() {
+        let end = text.chars().count() + 1;
+        line.operand(text, &rest[start..], end)?;
+    }
+    Ok(line)
+}
 
-    lines
+/// Whether the program has an `END` directive.
+pub fn has_end(lines: &[Line]) -> bool {
+    lines.iter().any(|x| x.mnu.as_ref().map(|m| m.eq_ignore_ascii_case("end")).unwrap_or(false))
+}
+
+/// Whether `word` defines the name before it, as in `name EQU expr` or
+/// `name MACRO params`.
+fn is_definition(word: &str) -> bool {
+    matches!(word.to_lowercase().as_ref(), "equ" | "set" | "data" | "idata" | "xdata" | "bit" | "code" | "macro")
 }
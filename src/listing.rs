@@ -0,0 +1,127 @@
+use section::Section;
+use symbol::Symbol;
+use diagnostic::Diagnostic;
+use timing::Timing;
+use include::{self, Origin};
+
+/// Number of object bytes shown on each listing line.
+const BYTES_PER_LINE: usize = 4;
+
+/// Builds the text of a `.lst` file: every source line with its address,
+/// encoded bytes, machine cycles and diagnostics, followed by the user
+/// defined symbols and the timing report. Lines are numbered within their
+/// own file through `origins`.
+pub fn listing(source: &str, origins: &[Origin], sections: &[Section], symbols: &[Symbol], diagnostics: &[Diagnostic], timing: &Timing) -> String {
+    let mut entries = Vec::new();
+    for sec in sections {
+        entries.append(&mut sec.listing());
+    }
+
+    let mut out = String::from(" LINE  LOC   OBJ          CYC    SOURCE\n\n");
+    for (i, text) in source.lines().enumerate() {
+        let num = i as u64 + 1;
+        let shown = include::line_number(origins, num);
+        match entries.iter().find(|x| x.0 == num && x.3.is_none()) {
+            Some(&(_, addr, ref bytes, _)) => out += &code_line(&format!("{:5} ", shown), addr, bytes, timing, text),
+            None => out += &format!("{:5}  {:4}  {:12} {:6} {}\n", shown, "", "", "", text),
+        }
+        // Lines expanded from a macro follow the line that called it
+        for &(_, addr, ref bytes, ref expansion) in entries.iter().filter(|x| x.0 == num && x.3.is_some()) {
+            let text = format!("+{}", expansion.as_ref().unwrap());
+            out += &code_line("      ", addr, bytes, timing, &text);
+        }
+        for e in diagnostics.iter().filter(|x| x.line == num) {
+            out += &format!("*** {}\n", e.describe());
+        }
+    }
+
+    let mut user_symbols: Vec<&Symbol> = symbols.iter().filter(|x| x.line > 0).collect();
+    user_symbols.sort_by_key(|x| x.name.to_lowercase());
+    out += "\n\nSYMBOL TABLE\n\n";
+    out += "NAME                      TYPE    VALUE  LINE\n";
+    for sym in user_symbols {
+        out += &format!("{:<25} {:<7} {:04X}   {:>5}\n", sym.name, sym.kind.name(), sym.value, include::source_line(origins, sym.line));
+    }
+    out += &timing.report(origins);
+    out += &format!("\n{} error(s)\n", diagnostics.iter().filter(|x| x.is_error()).count());
+    out
+}
+
+/// Lists the bytes of one instruction, continuing on further lines if
+/// there are more than fit.
+fn code_line(num: &str, addr: u16, bytes: &[u8], timing: &Timing, text: &str) -> String {
+    let mut chunks = bytes.chunks(BYTES_PER_LINE);
+    let first = chunks.next().unwrap_or(&[]);
+    let cycles = match timing.cycles(addr) {
+        Some(c) if !bytes.is_empty() => c.to_string(),
+        _ => String::new(),
+    };
+    let mut out = format!("{} {:04X}  {:<12} {:<6} {}\n", num, addr, hex_bytes(first), cycles, text);
+    let mut addr = addr as usize + first.len();
+    for chunk in chunks {
+        out += &format!("       {:04X}  {}\n", addr & 0xFFFF, hex_bytes(chunk));
+        addr += chunk.len();
+    }
+    out
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    hex.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use timing::{self, Clock};
+    use {build, Options};
+
+    #[test]
+    fn lists_addresses_bytes_and_lines() {
+        let main = |line| Origin { file: String::from("main.asm"), line, chain: Vec::new() };
+        let inc = |line| Origin { file: String::from("defs.inc"), line, chain: vec![(String::from("main.asm"), 1)] };
+        let source = "; include \"defs.inc\"
+count equ 3
+wait macro n
+ mov r7, #n
+ djnz r7, $
+ endm
+start: wait count
+ db \"Hello, world\"
+ org 20h
+ sjmp start
+ end
+";
+        let origins = vec![main(1), inc(1), main(2), main(3), main(4), main(5), main(6), main(7), main(8), main(9), main(10)];
+        let build = build(source, &origins, &Options::parse(&[], false).0, Vec::new());
+        let timing = timing::analyse(&build.sections, &build.symbols, Clock::LPC9XX, &[]);
+        let text = listing(source, &origins, &build.sections, &build.symbols, &build.diagnostics, &timing);
+        let expected = " LINE  LOC   OBJ          CYC    SOURCE
+
+    1                            ; include \"defs.inc\"
+    1                            count equ 3
+    2                            wait macro n
+    3                             mov r7, #n
+    4                             djnz r7, $
+    5                             endm
+    6  0000                      start: wait count
+       0000  7F 03        1      +mov r7, #count
+       0002  DF FE        2      +djnz r7, $
+    7  0004  48 65 6C 6C          db \"Hello, world\"
+       0008  6F 2C 20 77
+       000C  6F 72 6C 64
+    8  0020                       org 20h
+    9  0020  80 DE        2       sjmp start
+   10                             end
+
+
+SYMBOL TABLE
+
+NAME                      TYPE    VALUE  LINE
+count                     NUMBER  0003   defs.inc:1
+start                     CODE    0000       6
+";
+        assert_eq!(&text[..expected.len()], expected);
+        assert!(text.ends_with("\n0 error(s)\n"));
+    }
+}
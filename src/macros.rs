@@ -0,0 +1,397 @@
+use std::collections::HashMap;
+
+use cond::{Conditions, Known};
+use diagnostic::Diagnostic;
+use expr::Expr;
+use line::Line;
+use symbol::Symbol;
+
+/// Deepest nesting of macro calls, which stops runaway recursion.
+const MAX_DEPTH: usize = 64;
+
+/// Most passes of one `REPT`, enough to fill the code space a byte at a time.
+const MAX_REPT: i32 = 0x10000;
+
+/// Most lines one call may expand to, counting every nested expansion.
+const MAX_LINES: usize = 0x40000;
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Line>,
+}
+
+struct Expander {
+    macros: HashMap<String, Macro>,
+    /// Names handed out for `LOCAL` labels so far.
+    locals: u32,
+    known: Known,
+    errors: Vec<Diagnostic>,
+    /// Lines expanded so far by the outermost call being expanded.
+    expanded: usize,
+}
+
+/// Expands `MACRO ... ENDM` definitions and calls, `REPT n`, `IRP p, <list>`
+/// and `IRPC p, text` blocks, and drops the lines that `IF`, `IFDEF` and
+/// `IFNDEF` skip. `defines` are the symbols given with `-D`. Expanded lines
+/// keep the line number of the line that expanded them and carry their
+/// text for the listing.
+pub fn expand(lines: Vec<Line>, defines: &[Symbol]) -> Result<Vec<Line>, Vec<Diagnostic>> {
+    let mut expander = Expander { macros: HashMap::new(), locals: 0, known: Known::new(defines), errors: Vec::new(), expanded: 0 };
+    let (lines, _) = expander.lines(lines, 0);
+    if expander.errors.is_empty() {
+        Ok(lines)
+    } else {
+        Err(expander.errors)
+    }
+}
+
+/// The directive of a line, lowercased.
+fn directive(line: &Line) -> String {
+    line.mnu.as_ref().map(|x| x.to_lowercase()).unwrap_or_default()
+}
+
+/// Whether a directive starts a block that ends with `ENDM`.
+fn opens_block(word: &str) -> bool {
+    matches!(word, "macro" | "rept" | "irp" | "irpc")
+}
+
+impl Expander {
+    /// Reports an error on `line`, with the expansions that produced it.
+    fn error<T: Into<String>>(&mut self, line: &Line, message: T) {
+        self.errors.push(Diagnostic::error(line.num, message).expanded(&line.expanded_from));
+    }
+
+    /// Expands `lines`, `depth` expansions deep. Returns the lines and
+    /// whether an `EXITM` ended the expansion early.
+    fn lines(&mut self, lines: Vec<Line>, depth: usize) -> (Vec<Line>, bool) {
+        let mut out = Vec::new();
+        let mut conds = Conditions::new();
+        let mut iter = lines.into_iter();
+        while let Some(line) = iter.next() {
+            match conds.directive(&line, &self.known) {
+                Ok(true) => continue,
+                Ok(false) => {},
+                Err(e) => {
+                    self.error(&line, e);
+                    continue;
+                },
+            }
+            if !conds.active() {
+                continue;
+            }
+            self.known.record(&line);
+            let word = directive(&line);
+            if depth == 0 {
+                self.expanded = 0;
+            }
+            match word.as_ref() {
+                "macro" => {
+                    let body = self.body(&mut iter, &line);
+                    match line.label {
+                        Some(ref name) => {
+                            let params = line.ops.iter().map(|x| x.to_lowercase()).collect();
+                            self.macros.insert(name.to_lowercase(), Macro { params, body });
+                        },
+                        None => self.error(&line, "MACRO without a name"),
+                    }
+                },
+                "rept" | "irp" | "irpc" => {
+                    let body = self.body(&mut iter, &line);
+                    let passes = match self.passes(&line) {
+                        Ok(p) => p,
+                        Err(e) => {
+                            self.error(&line, e);
+                            continue;
+                        },
+                    };
+                    if depth >= MAX_DEPTH {
+                        self.errors.push(Diagnostic::error(line.num, "Macros nested too deeply"));
+                        continue;
+                    }
+                    for args in passes {
+                        let lines = match self.instantiate(&body, &args, &line, &word.to_uppercase()) {
+                            Some(l) => l,
+                            None => break,
+                        };
+                        let (mut lines, exit) = self.lines(lines, depth + 1);
+                        out.append(&mut lines);
+                        if exit {
+                            break;
+                        }
+                    }
+                },
+                "endm" => self.error(&line, "ENDM without MACRO, REPT, IRP or IRPC"),
+                "exitm" if depth > 0 => return (out, true),
+                "exitm" => self.error(&line, "EXITM outside a macro"),
+                "local" => self.error(&line, "LOCAL outside a macro"),
+                _ if self.macros.contains_key(&word) => {
+                    if depth >= MAX_DEPTH {
+                        self.errors.push(Diagnostic::error(line.num, format!("Macros nested too deeply expanding {}", word)));
+                        continue;
+                    }
+                    let (params, body) = {
+                        let m = &self.macros[&word];
+                        (m.params.clone(), m.body.clone())
+                    };
+                    if line.ops.len() > params.len() {
+                        self.error(&line, format!("Too many arguments for macro {}", word));
+                        continue;
+                    }
+                    // A label on the call names the first expanded line
+                    if line.label.is_some() {
+                        out.push(Line { mnu: None, ops: Vec::new(), ..line.clone() });
+                    }
+                    let args: Vec<(String, String)> = params.into_iter()
+                        .zip(line.ops.iter().cloned().chain(::std::iter::repeat(String::new())))
+                        .collect();
+                    if let Some(lines) = self.instantiate(&body, &args, &line, &word.to_uppercase()) {
+                        let (mut lines, _) = self.lines(lines, depth + 1);
+                        out.append(&mut lines);
+                    }
+                },
+                _ => out.push(line),
+            }
+        }
+        if let Some(num) = conds.open() {
+            self.errors.push(Diagnostic::error(num, "IF without ENDIF"));
+        }
+        (out, false)
+    }
+
+    /// Takes the lines up to the `ENDM` that closes the block `open`.
+    fn body<I: Iterator<Item = Line>>(&mut self, iter: &mut I, open: &Line) -> Vec<Line> {
+        let mut body = Vec::new();
+        let mut depth = 1;
+        for line in iter {
+            let word = directive(&line);
+            if opens_block(&word) {
+                depth += 1;
+            } else if word == "endm" {
+                depth -= 1;
+                if depth == 0 {
+                    return body;
+                }
+            }
+            body.push(line);
+        }
+        self.error(open, format!("{} without ENDM", directive(open).to_uppercase()));
+        body
+    }
+
+    /// The parameter values of each pass of a `REPT`, `IRP` or `IRPC`.
+    fn passes(&self, line: &Line) -> Result<Vec<Vec<(String, String)>>, String> {
+        let word = directive(line);
+        if word == "rept" {
+            let count = match line.ops.first() {
+                Some(op) if line.ops.len() == 1 => self.known.value(&Expr::parse(op)?),
+                _ => return Err(String::from("REPT takes one count")),
+            };
+            return match count {
+                Ok(n) if n > MAX_REPT => Err(format!("REPT count {} is more than {}", n, MAX_REPT)),
+                Ok(n) if n >= 0 => Ok(vec![Vec::new(); n as usize]),
+                Ok(_) => Err(String::from("REPT count is negative")),
+                Err(e) => Err(format!("REPT count must be a constant: {}", e)),
+            };
+        }
+        let param = match line.ops.first() {
+            Some(p) if line.ops.len() > 1 => p.to_lowercase(),
+            _ => return Err(format!("{} takes a parameter and a list", word.to_uppercase())),
+        };
+        let list = line.ops[1..].join(",");
+        let list = list.trim();
+        let list = list.strip_prefix('<').and_then(|x| x.strip_suffix('>')).unwrap_or(list);
+        let values: Vec<String> = if word == "irp" {
+            list.split(',').map(|x| x.trim().to_string()).collect()
+        } else {
+            list.chars().filter(|c| !c.is_whitespace()).map(|c| c.to_string()).collect()
+        };
+        Ok(values.into_iter().map(|v| vec![(param.clone(), v)]).collect())
+    }
+
+    /// Makes one copy of the body of the block `name` with the parameters
+    /// replaced by `args` and its `LOCAL` labels by names unique to this
+    /// copy. Returns None, reporting it at the outermost call, once that
+    /// call has expanded to more than `MAX_LINES` lines.
+    fn instantiate(&mut self, body: &[Line], args: &[(String, String)], call: &Line, name: &str) -> Option<Vec<Line>> {
+        if self.expanded > MAX_LINES {
+            return None;
+        }
+        self.expanded += body.len();
+        if self.expanded > MAX_LINES {
+            let outer = call.expanded_from.last().map(|x| x.0.as_str()).unwrap_or(name);
+            self.errors.push(Diagnostic::error(call.num, format!("Expansion of {} is longer than {} lines", outer, MAX_LINES)));
+            return None;
+        }
+        let mut args = args.to_vec();
+        let mut depth = 0;
+        for line in body {
+            let word = directive(line);
+            if depth == 0 && word == "local" {
+                for name in &line.ops {
+                    self.locals += 1;
+                    args.push((name.to_lowercase(), format!("??{:04}", self.locals)));
+                }
+            }
+            if opens_block(&word) {
+                depth += 1;
+            } else if word == "endm" {
+                depth -= 1;
+            }
+        }
+
+        let mut depth = 0;
+        let mut lines = Vec::new();
+        for line in body {
+            let word = directive(line);
+            if opens_block(&word) {
+                depth += 1;
+            } else if word == "endm" {
+                depth -= 1;
+            } else if depth == 0 && word == "local" {
+                continue;
+            }
+            let mut new = Line::new(
+                call.num,
+                line.label.as_ref().map(|x| substitute(x, &args)),
+                line.mnu.as_ref().map(|x| substitute(x, &args)),
+                line.ops.iter().map(|x| substitute(x, &args)).filter(|x| !x.is_empty()).collect(),
+            );
+            new.expansion = Some(text(&new));
+            new.error = line.error.clone();
+            new.expanded_from = ::std::iter::once((name.to_string(), line.num)).chain(call.expanded_from.iter().cloned()).collect();
+            lines.push(new);
+        }
+        Some(lines)
+    }
+}
+
+/// Replaces the parameters in `text` by their values. A `&` next to a
+/// parameter joins it to the text around it, as in `loop&n`. Inside quoted
+/// strings only parameters marked with `&` are replaced, as in `'&c'`.
+fn substitute(text: &str, args: &[(String, String)]) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let is_name = |c: char| c.is_alphanumeric() || c == '_' || c == '?';
+    let mut out = String::new();
+    let mut quote = None;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if is_name(c) {
+            let start = i;
+            while i < chars.len() && is_name(chars[i]) {
+                i += 1;
+            }
+            let name: String = chars[start..i].iter().collect();
+            let marked = out.ends_with('&') || chars.get(i) == Some(&'&');
+            match args.iter().find(|x| x.0 == name.to_lowercase()) {
+                Some((_, value)) if quote.is_none() || marked => {
+                    if out.ends_with('&') {
+                        out.pop();
+                    }
+                    out += value;
+                    if chars.get(i) == Some(&'&') {
+                        i += 1;
+                    }
+                },
+                _ => out += &name,
+            }
+            continue;
+        }
+        if quote == Some(c) {
+            quote = None;
+        } else if quote.is_none() && (c == '\'' || c == '"') {
+            quote = Some(c);
+        }
+        out.push(c);
+        i += 1;
+    }
+    out
+}
+
+/// The source text of an expanded line.
+fn text(line: &Line) -> String {
+    let mut out = String::new();
+    if let Some(ref label) = line.label {
+        out += &format!("{}: ", label);
+    }
+    if let Some(ref mnu) = line.mnu {
+        out += &format!("{} {}", mnu, line.ops.join(", "));
+    }
+    out.trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use line;
+    use {build, Options};
+
+    /// The expanded source as text, one line per entry.
+    fn expanded(source: &str) -> Result<Vec<String>, Vec<String>> {
+        let lines = line::get_lines(source.to_string());
+        match expand(lines, &[]) {
+            Ok(lines) => Ok(lines.iter().map(text).filter(|x| !x.is_empty()).collect()),
+            Err(e) => Err(e.into_iter().map(|x| x.message).collect()),
+        }
+    }
+
+    #[test]
+    fn expands_macro_calls() {
+        let source = "load macro reg, value\n local again\nagain: mov reg, #value\n djnz reg, again\n endm\n load r0, 5\n load r1, 0FFh\n";
+        assert_eq!(expanded(source).unwrap(), vec![
+            "??0001: mov r0, #5", "djnz r0, ??0001",
+            "??0002: mov r1, #0FFh", "djnz r1, ??0002",
+        ]);
+    }
+
+    #[test]
+    fn joins_parameters_with_ampersand() {
+        let source = "name macro n\nloop&n: db 'n&', \"&n\", 'n'\n endm\n name 7\n";
+        assert_eq!(expanded(source).unwrap(), vec!["loop7: db '7', \"7\", 'n'"]);
+    }
+
+    #[test]
+    fn repeats_by_constant_count() {
+        let source = "n equ 3\n rept n\n nop\n endm\n rept n - 3\n inc a\n endm\n";
+        assert_eq!(expanded(source).unwrap(), vec!["n: equ 3", "nop", "nop", "nop"]);
+    }
+
+    #[test]
+    fn rejects_bad_repeat_counts() {
+        for count in &["later", "-1", "10001h", "here"] {
+            let source = format!("here: rept {}\n nop\n endm\nlater equ 2\n", count);
+            assert!(expanded(&source).is_err(), "{}", count);
+        }
+    }
+
+    #[test]
+    fn repeats_over_lists_and_characters() {
+        let source = " irp r, <r0, r1>\n inc r\n endm\n irpc c, AB\n mov a, #'&c'\n endm\n";
+        assert_eq!(expanded(source).unwrap(), vec!["inc r0", "inc r1", "mov a, #'A'", "mov a, #'B'"]);
+    }
+
+    #[test]
+    fn limits_the_lines_of_nested_expansions() {
+        // Each REPT alone is allowed, but together they expand too far
+        let source = " nop\n rept 1000h\n rept 1000h\n nop\n endm\n endm\n";
+        let errors = expand(line::get_lines(source.to_string()), &[]).unwrap_err();
+        assert_eq!(errors.iter().map(|x| (x.line, x.message.as_str())).collect::<Vec<_>>(),
+            [(2, "Expansion of REPT is longer than 262144 lines")]);
+    }
+
+    /// The diagnostics from assembling `source`, as printed.
+    fn reported(source: &str) -> Vec<String> {
+        build(source, &[], &Options::parse(&[], false).0, Vec::new()).diagnostics.into_iter()
+            .map(|x| x.in_file("main.asm").to_string())
+            .collect()
+    }
+
+    #[test]
+    fn reports_errors_in_the_expansions_they_came_from() {
+        let source = "inner macro\n frob\n endm\nouter macro\n nop\n inner\n endm\n outer\n end\n";
+        assert_eq!(reported(source), ["In expansion of INNER at main.asm:6,\n             of OUTER at main.asm:8:\n\
+            main.asm:8: error: Unknown mnemonic: frob"]);
+        let source = "dup macro\nhere: nop\n endm\n dup\n dup\n end\n";
+        assert_eq!(reported(source), ["In expansion of DUP at main.asm:5:\nmain.asm:5: error: here already defined at line 4"]);
+    }
+}
@@ -1,210 +1,657 @@
 use std::env;
-use std::error::Error;
 use std::fs::File;
 use std::io::prelude::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process;
 
 mod section;
 mod omf;
 mod line;
+mod lexer;
 mod instruction;
+mod opcode;
 mod hex_table;
+mod expr;
+mod symbol;
+mod listing;
+mod map;
+mod diagnostic;
+mod disasm;
+mod sim;
+mod periph;
+mod timing;
+mod include;
+mod macros;
+mod cond;
 use hex_table::HexTable;
+use symbol::{Symbol, SymbolKind};
+use diagnostic::{Diagnostic, Severity, Warning};
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    // let mut lines;
-    let label_table;
-    let mut errors = Vec::new(); //if there is an error in the compulation
+    if args.len() < 2 {
+        println!("usage: asm622 <file.asm> [-o | -h] [-l] [-m] [-r] [-Wno-<warning>] [-Werror] [-I <dir>] [-D <name>[=<value>]] [--clocks <n>] [--fosc <hz>] [--entry <label>]");
+        println!("       asm622 run <file.asm> [--max-cycles <n>] [--dump-ram] [--uart-in <file | ->] [--uart-out <file>] [--port P<n>=<hex>]");
+        println!("       asm622 disasm <file.bin | file.hex | file.obj>");
+        process::exit(2);
+    }
+    if args[1] == "disasm" {
+        disassemble(args.get(2).map(|x| x.as_str()).unwrap_or(""));
+    }
+
+    // `run` assembles the file and simulates it instead of writing output
+    let run = args[1] == "run";
+    let first = if run { 2 } else { 1 };
 
     // Create a path to the desired file
-    let path = Path::new(&args[1]);
-    let display = path.display();
-    let mut out_type = OutType::Bin;
-
-    if args.len() > 2 {
-        let obj_string = args[2].as_ref();
-        match obj_string {
-            "-o" => out_type = OutType::Obj,
-            "-h" => out_type = OutType::Hex,
-            _ => {},
+    let path = Path::new(args.get(first).map(|x| x.as_str()).unwrap_or(""));
+    let display = path.display().to_string();
+    let (options, mut diagnostics) = Options::parse(args.get(first + 1..).unwrap_or(&[]), run);
+
+    // Expand include files; diagnostics refer to lines of the expanded
+    // source until `finish` maps them back
+    let (file_text, origins) = match include::expand(path, &options.include_dirs) {
+        Ok((source, mut errors)) => {
+            diagnostics.append(&mut errors);
+            (source.text, source.origins)
+        },
+        Err(e) => {
+            diagnostics.push(Diagnostic::error(0, e));
+            finish(&display, diagnostics, &[]);
+        },
+    };
+
+    let Build { sections, symbols: label_table, records, mut diagnostics } = build(&file_text, &origins, &options, diagnostics);
+    options.apply(&mut diagnostics);
+
+    let name = String::from(path.file_stem().unwrap().to_str().unwrap());
+    if options.listing {
+        let mut entries = Vec::new();
+        for name in &options.entries {
+            match symbol::find(&label_table, name) {
+                Some(x) if x.kind == SymbolKind::Code => entries.push(x.value),
+                _ => diagnostics.push(Diagnostic::error(0, format!("--entry {} is not a code label", name))),
+            }
+        }
+        let timing = timing::analyse(&sections, &label_table, options.clock, &entries);
+        let text = listing::listing(&file_text, &origins, &sections, &label_table, &diagnostics, &timing);
+        if let Err(e) = output_text(&name, ".lst", text) {
+            diagnostics.push(e);
         }
     }
 
-    // Open the path in read-only mode, returns `io::Result<File>`
-    let mut file = match File::open(&path) {
-        // The `description` method of `io::Error` returns a string that
-        // describes the error
-        Err(why) => panic!("couldn't open {}: {}", display,
-        why.description()),
-        Ok(file) => file,
-    };
+    if let Some(ref run) = options.run {
+        if !has_errors(&diagnostics) {
+            if let Err(e) = simulate(&records, run) {
+                diagnostics.push(e);
+            }
+        }
+        finish(&display, diagnostics, &origins);
+    }
 
-    // Read the file contents into a string, returns `io::Result<usize>`
-    let mut file_text = String::new();
-    file.read_to_string(&mut file_text).unwrap();
+    if !has_errors(&diagnostics) {
+        if options.map {
+            if let Err(e) = output_text(&name, ".map", map::symbol_map(&sections, &label_table, &origins)) {
+                diagnostics.push(e);
+            }
+        }
+        let written = match options.out_type {
+            OutType::Bin => output_bin(name, records),
+            OutType::Obj => output_obj(name, records),
+            OutType::Hex => output_hex(name, records),
+        };
+        if let Err(e) = written {
+            diagnostics.push(e);
+        }
+    }
 
-    let lines = line::get_lines(file_text);
+    finish(&display, diagnostics, &origins);
+}
 
-    let sections;
-    match section::get_sections(lines){
-        Ok(s) => sections = s,
-        Err(e) => {
-            for err in e {
-                println!("{}",err);
+/// The options that follow the file name on the command line.
+struct Options {
+    out_type: OutType,
+    listing: bool,
+    map: bool,
+    relax: bool,
+    /// Warnings turned off with `-Wno-<name>`.
+    disabled: Vec<Warning>,
+    werror: bool,
+    clock: timing::Clock,
+    /// Labels of routines to time besides those called or at a vector.
+    entries: Vec<String>,
+    include_dirs: Vec<PathBuf>,
+    defines: Vec<Symbol>,
+    /// How to simulate the program, for `asm622 run`.
+    run: Option<RunOptions>,
+}
+
+impl Options {
+    /// Reads the options, reporting those it cannot use. The simulator's
+    /// options are only known with `run`.
+    fn parse(args: &[String], run: bool) -> (Options, Vec<Diagnostic>) {
+        let mut options = Options {
+            out_type: OutType::Bin,
+            listing: false,
+            map: false,
+            relax: false,
+            disabled: Vec::new(),
+            werror: false,
+            clock: timing::Clock::LPC9XX,
+            entries: Vec::new(),
+            include_dirs: Vec::new(),
+            defines: Vec::new(),
+            run: None,
+        };
+        if run {
+            options.run = Some(RunOptions { max_cycles: 1_000_000, dump_ram: false, uart_in: None, uart_out: None, pins: Vec::new() });
+        }
+        let mut diagnostics = Vec::new();
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            match arg.as_ref() {
+                "-o" => options.out_type = OutType::Obj,
+                "-h" => options.out_type = OutType::Hex,
+                "-l" => options.listing = true,
+                "-m" => options.map = true,
+                "-r" => options.relax = true,
+                "-Werror" => options.werror = true,
+                "-I" => match iter.next() {
+                    Some(dir) => options.include_dirs.push(PathBuf::from(dir)),
+                    None => diagnostics.push(Diagnostic::error(0, "-I needs a directory")),
+                },
+                a if a.starts_with("-I") => options.include_dirs.push(PathBuf::from(&a[2..])),
+                a if a.starts_with("-D") => {
+                    let text = if a == "-D" { iter.next().map(|x| x.as_str()).unwrap_or("") } else { &a[2..] };
+                    match define(text) {
+                        Ok(d) => options.defines.push(d),
+                        Err(e) => diagnostics.push(Diagnostic::error(0, e)),
+                    }
+                },
+                "--max-cycles" if run => match iter.next().and_then(|x| x.parse().ok()) {
+                    Some(n) => options.run.as_mut().unwrap().max_cycles = n,
+                    None => diagnostics.push(Diagnostic::error(0, "--max-cycles needs a number")),
+                },
+                "--clocks" | "--fosc" => match iter.next().and_then(|x| x.parse().ok()).filter(|&n| n > 0) {
+                    Some(n) if arg == "--clocks" => options.clock.clocks = n,
+                    Some(n) => options.clock.fosc = n,
+                    None => diagnostics.push(Diagnostic::error(0, format!("{} needs a number", arg))),
+                },
+                "--entry" => match iter.next() {
+                    Some(name) => options.entries.push(name.clone()),
+                    None => diagnostics.push(Diagnostic::error(0, "--entry needs a label")),
+                },
+                "--dump-ram" if run => options.run.as_mut().unwrap().dump_ram = true,
+                "--uart-in" | "--uart-out" if run => match iter.next() {
+                    Some(file) if arg == "--uart-in" => options.run.as_mut().unwrap().uart_in = Some(file.clone()),
+                    Some(file) => options.run.as_mut().unwrap().uart_out = Some(file.clone()),
+                    None => diagnostics.push(Diagnostic::error(0, format!("{} needs a file name", arg))),
+                },
+                "--port" if run => match iter.next().and_then(|x| port_pins(x)) {
+                    Some(p) => options.run.as_mut().unwrap().pins.push(p),
+                    None => diagnostics.push(Diagnostic::error(0, "--port needs P<n>=<hex>, e.g. P1=0F7")),
+                },
+                a if a.starts_with("-Wno-") => match Warning::from_name(&a[5..]) {
+                    Some(w) => options.disabled.push(w),
+                    None => diagnostics.push(Diagnostic::warning(0, Warning::UnknownOption, format!("unknown warning {}", a))),
+                },
+                _ => diagnostics.push(Diagnostic::warning(0, Warning::UnknownOption, format!("ignoring unknown option {}", arg))),
             }
-            return;
         }
-    };
+        (options, diagnostics)
+    }
 
+    /// Drops the warnings turned off with `-Wno-...`, and with `-Werror`
+    /// makes the rest errors.
+    fn apply(&self, diagnostics: &mut Vec<Diagnostic>) {
+        diagnostics.retain(|d| d.warning.is_none_or(|w| !self.disabled.contains(&w)));
+        if self.werror {
+            for d in diagnostics.iter_mut().filter(|x| x.severity == Severity::Warning) {
+                d.severity = Severity::Error;
+            }
+        }
+    }
+}
+
+/// What assembling a source gives: its sections and symbols, the code, and
+/// everything reported on the way.
+struct Build {
+    sections: Vec<section::Section>,
+    symbols: Vec<Symbol>,
+    records: Vec<omf::ContentRecord>,
+    diagnostics: Vec<Diagnostic>,
+}
 
+/// Assembles the expanded source `text`, whose lines came from `origins`,
+/// adding to the `diagnostics` reported before it. Each stage runs only if
+/// there are no errors so far.
+fn build(text: &str, origins: &[include::Origin], options: &Options, mut diagnostics: Vec<Diagnostic>) -> Build {
+    let defines = &options.defines[..];
+    let mut label_table = Vec::new();
+    let lines = line::get_lines(text.to_string());
+    // Only an END that conditional assembly keeps counts
+    let lines = match macros::expand(lines, defines) {
+        Ok(l) => {
+            if !line::has_end(&l) {
+                let last = text.lines().count() as u64;
+                diagnostics.push(Diagnostic::warning(last, Warning::MissingEnd, "missing END directive"));
+            }
+            l
+        },
+        Err(mut e) => {
+            diagnostics.append(&mut e);
+            Vec::new()
+        },
+    };
+    // Symbols only know their line, so name the outermost expansion there
+    let calls: Vec<(u64, (String, u64))> = lines.iter()
+        .filter_map(|x| x.expanded_from.last().map(|c| (x.num, c.clone())))
+        .collect();
+    let first = diagnostics.len();
+
+    let mut sections = match section::get_sections(lines, defines){
+        Ok(s) => s,
+        Err(mut e) => {
+            diagnostics.append(&mut e);
+            Vec::new()
+        }
+    };
 
     // Generate a table of all known labels
-    label_table = build_label_table(&sections);
+    if !has_errors(&diagnostics) {
+        match layout(&mut sections, defines, options.relax) {
+            Ok(t) => label_table = t,
+            Err(mut e) => diagnostics.append(&mut e),
+        }
+        diagnostics.append(&mut symbol::conflicts(&label_table, origins));
+        diagnostics.append(&mut section::overlaps(&sections, origins));
+        diagnostics.append(&mut section::overflows(&sections));
+    }
 
     let mut records: Vec<omf::ContentRecord> = Vec::new();
-    for mut sec in sections {
-        match sec.fix_labels(&label_table) {
-            Err(mut e) => errors.append(&mut e),
-            _ => {},
-        }
-        if errors.is_empty() {
-            let record;
-            match sec.get_content_record(){
-                Ok(r) => record = r,
-                Err(mut e) => {
-                    errors.append(&mut e);
-                    continue;
+    if !has_errors(&diagnostics) {
+        for sec in &mut sections {
+            if let Err(mut e) = sec.fix_labels(&mut label_table) {
+                diagnostics.append(&mut e);
+            }
+            // Only CODE has bytes; the other spaces just allocate addresses
+            if !has_errors(&diagnostics) && sec.space() == section::Space::Code {
+                match sec.get_content_record(){
+                    Ok(r) => records.push(r),
+                    Err(mut e) => diagnostics.append(&mut e),
                 }
             }
-            records.push(record);
         }
     }
 
-    if errors.is_empty(){
-        let name = String::from(path.file_stem().unwrap().to_str().unwrap());
+    if !has_errors(&diagnostics) {
+        diagnostics.append(&mut symbol::unused(&label_table));
+        for sec in &sections {
+            diagnostics.append(&mut sec.truncations());
+            diagnostics.append(&mut sec.relaxations());
+        }
+    }
+    for d in diagnostics[first..].iter_mut().filter(|x| x.expanded_from.is_empty()) {
+        if let Some(call) = calls.iter().find(|x| x.0 == d.line) {
+            *d = d.clone().expanded(::std::slice::from_ref(&call.1));
+        }
+    }
+    Build { sections, symbols: label_table, records, diagnostics }
+}
+
+struct RunOptions {
+    max_cycles: u64,
+    dump_ram: bool,
+    /// UART receive data, `-` for stdin.
+    uart_in: Option<String>,
+    /// UART transmit data, stdout if not given.
+    uart_out: Option<String>,
+    /// Levels driven onto port pins from outside, by port number.
+    pins: Vec<(usize, u8)>,
+}
 
-        match out_type {
-            OutType::Bin => output_bin(name, records),
-            OutType::Obj => output_obj(name, records),
-            OutType::Hex => output_hex(name, records),
+/// Parses a `-D` argument, `NAME` or `NAME=value`, into a number symbol.
+/// A name without a value is 1.
+fn define(arg: &str) -> Result<Symbol, String> {
+    let usage = || format!("-D needs <name>[=<value>], not '{}'", arg);
+    let mut parts = arg.splitn(2, '=');
+    let name = parts.next().unwrap_or("").trim();
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit())
+        || !name.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '?') {
+        return Err(usage());
+    }
+    let value = match parts.next() {
+        Some(v) => expr::Expr::parse(v).ok().and_then(|x| x.constant()).ok_or_else(usage)?,
+        None => 1,
+    };
+    let value = SymbolKind::Number.check(value).map_err(|e| format!("-D {}: {}", arg, e))?;
+    Ok(Symbol::new(name.to_string(), value, SymbolKind::Number, 0))
+}
+
+/// Parses a `--port` argument such as `P1=0F7`.
+fn port_pins(arg: &str) -> Option<(usize, u8)> {
+    let mut parts = arg.splitn(2, '=');
+    let port = parts.next()?.to_uppercase();
+    let n = match port.as_ref() {
+        "P0" => 0,
+        "P1" => 1,
+        "P2" => 2,
+        "P3" => 3,
+        _ => return None,
+    };
+    let value = parts.next()?.trim_end_matches(&['h', 'H'][..]);
+    u8::from_str_radix(value, 16).ok().map(|v| (n, v))
+}
+
+/// Loads the assembled records into the simulator, runs them and prints the
+/// final state.
+fn simulate(records: &[omf::ContentRecord], options: &RunOptions) -> Result<(), Diagnostic> {
+    let mut image = HexTable { table: vec![0; 0x10000] };
+    for record in records {
+        if record.offset() as usize + record.data().len() > image.table.len() {
+            return Err(Diagnostic::error(0, format!("code at {:04X} runs past the end of memory", record.offset())));
         }
+        image.update(record.offset(), &record.data());
     }
-    else {
-        for e in errors{
-            println!("{}", e);
+    let mut cpu = sim::Cpu::new(&image.table);
+    for &(n, value) in &options.pins {
+        cpu.periph.pins[n] = value;
+    }
+    match options.uart_in.as_deref() {
+        Some("-") => cpu.periph.uart.input = Some(Box::new(std::io::stdin())),
+        Some(file) => match File::open(file) {
+            Ok(f) => cpu.periph.uart.input = Some(Box::new(f)),
+            Err(why) => return Err(Diagnostic::error(0, format!("couldn't open {}: {}", file, why))),
+        },
+        None => {},
+    }
+    if let Some(ref file) = options.uart_out {
+        match File::create(file) {
+            Ok(f) => cpu.periph.uart.output = Box::new(f),
+            Err(why) => return Err(Diagnostic::error(0, format!("couldn't create {}: {}", file, why))),
         }
-        println!("Failed to build {}", path.display());
     }
+    let stop = match cpu.run(options.max_cycles) {
+        Ok(s) => s,
+        Err(e) => return Err(Diagnostic::error(0, e)),
+    };
+    match stop {
+        sim::Stop::Halted => println!("halted at {:04X} after {} cycles", cpu.pc, cpu.cycles),
+        sim::Stop::MaxCycles => println!("stopped at {:04X} after {} cycles (--max-cycles)", cpu.pc, cpu.cycles),
+    }
+    print!("{}", sim::registers(&cpu));
+    if options.dump_ram {
+        print!("{}", sim::dump_ram(&cpu));
+    }
+    Ok(())
+}
 
+/// Prints the disassembly of a code image and exits.
+fn disassemble(file: &str) -> ! {
+    let mut bytes = Vec::new();
+    if let Err(why) = File::open(file).and_then(|mut f| f.read_to_end(&mut bytes)) {
+        finish(file, vec![Diagnostic::error(0, format!("couldn't read {}: {}", file, why))], &[]);
+    }
+    let records = match disasm::load(file, &bytes) {
+        Ok(r) => r,
+        Err(e) => finish(file, vec![Diagnostic::error(0, format!("{}: {}", file, e))], &[]),
+    };
+    let mut symbols = sfr_table();
+    symbols.append(&mut bit_table());
+    print!("{}", disasm::disassemble(&records, &symbols));
+    finish(file, Vec::new(), &[]);
+}
+
+fn has_errors(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics.iter().any(|x| x.is_error())
+}
+
+/// Prints every diagnostic and exits, with a non-zero status if any of them
+/// is an error. `origins` maps lines of the expanded source back to the
+/// files they came from.
+fn finish(file: &str, diagnostics: Vec<Diagnostic>, origins: &[include::Origin]) -> ! {
+    let (text, status) = report(file, diagnostics, origins);
+    eprint!("{}", text);
+    process::exit(status);
+}
+
+/// Renders the diagnostics in source order as `finish` prints them, and
+/// returns the exit status: 1 if any of them is an error, else 0.
+fn report(file: &str, mut diagnostics: Vec<Diagnostic>, origins: &[include::Origin]) -> (String, i32) {
+    let failed = has_errors(&diagnostics);
+    diagnostics.sort_by_key(|x| x.line);
+    include::locate(&mut diagnostics, origins);
+    let mut out = String::new();
+    for d in diagnostics.into_iter().filter(|x| x.severity != Severity::Note) {
+        let d = if d.line > 0 { d.in_file(file) } else { d };
+        out += &format!("{}\n", d);
+    }
+    if failed {
+        out += &format!("Failed to build {}\n", file);
+        return (out, 1);
+    }
+    (out, 0)
 }
 
-fn build_label_table(sections: &Vec<section::Section>) -> Vec<(String, u16)>{
+/// Picks the form of every generic jump, and with `relax` of every
+/// conditional branch, repeating the layout until the addresses stop
+/// changing, and returns the final label table.
+fn layout(sections: &mut [section::Section], defines: &[Symbol], relax: bool) -> Result<Vec<Symbol>, Vec<Diagnostic>> {
+    loop {
+        let table = build_label_table(sections, defines)?;
+        let mut changed = false;
+        for sec in sections.iter_mut() {
+            if sec.relax(&table, relax) {
+                changed = true;
+            }
+        }
+        if !changed {
+            return Ok(table);
+        }
+        section::place(sections);
+    }
+}
+
+fn build_label_table(sections: &[section::Section], defines: &[Symbol]) -> Result<Vec<Symbol>, Vec<Diagnostic>>{
     let mut table = Vec::new();
+    let mut defs = Vec::new();
     for sec in sections {
         let mut  sub_table = sec.build_label_table();
         table.append(&mut sub_table);
+        defs.append(&mut sec.definitions());
     }
     //built in labels
+    table.append(&mut sfr_table());
+    table.append(&mut bit_table());
+    table.extend_from_slice(defines);
+
+    symbol::resolve(&mut table, defs)?;
+    Ok(table)
+}
 
-    table.push(("P0".to_string(),0x80));
-    table.push(("P1".to_string(),0x90));
-    table.push(("P2".to_string(),0xA0));
-    table.push(("P3".to_string(),0xB0));
-    table.push(("PSW".to_string(), 0xD0));
-    table.push(("ACC".to_string(), 0xE0));
-    table.push(("B".to_string(), 0xF0));
-    table.push(("SP".to_string(), 0x81));
-    table.push(("DPL".to_string(), 0x82));
-    table.push(("DPH".to_string(), 0x83));
-    table.push(("PCON".to_string(), 0x87));
-    table.push(("TCON".to_string(), 0x88));
-    table.push(("TMOD".to_string(), 0x89));
-    table.push(("TL0".to_string(), 0x8A));
-    table.push(("TL1".to_string(), 0x8B));
-    table.push(("TH0".to_string(), 0x8C));
-    table.push(("TH1".to_string(), 0x8D));
-    table.push(("IEN0".to_string(), 0xA8));
-    table.push(("IP0".to_string(), 0xB8));
-    table.push(("SCON".to_string(), 0x98));
-    table.push(("SBUF".to_string(), 0x99));
-    table.push(("AUXR1".to_string(), 0xA2));
-    table.push(("SADDR".to_string(), 0xA9));
-    table.push(("SADEN".to_string(), 0xB9));
-    table.push(("TL2".to_string(), 0xCC));
-    table.push(("TH2".to_string(), 0xCD));
-    table.push(("BRGR0".to_string(), 0xBE));
-    table.push(("BRGR1".to_string(), 0xBF));
-    table.push(("BRGCON".to_string(),0xBD));
-    table.push(("CCCRA".to_string(), 0xEA));
-    table.push(("CCCRB".to_string(), 0xEB));
-    table.push(("CCCRC".to_string(), 0xEC));
-    table.push(("CCCRD".to_string(), 0xED));
-    table.push(("CMP1".to_string(),0xAC));
-    table.push(("CMP2".to_string(),0xAD));
-    table.push(("DEECON".to_string(),0xF1));
-    table.push(("DEEDAT".to_string(),0xF2));
-    table.push(("DEEADR".to_string(),0xF3));
-    table.push(("DIVM".to_string(),0x95));
-    table.push(("I2ADR".to_string(), 0xDB));
-    table.push(("I2CON".to_string(), 0xD8));
-    table.push(("I2DAT".to_string(), 0xDA));
-    table.push(("I2SCLH".to_string(),0xDD));
-    table.push(("I2SCLL".to_string(),0xDC));
-    table.push(("I2STAT".to_string(),0xD9));
-    table.push(("ICRAH".to_string(), 0xAB));
-    table.push(("ICRAL".to_string(), 0xAA));
-    table.push(("ICRBH".to_string(), 0xAF));
-    table.push(("ICRBL".to_string(), 0xAE));
-    table.push(("IEN1".to_string(),0xE8));
-    table.push(("IP1".to_string(), 0xF8));
-    table.push(("IP1H".to_string(),0xF7));
-    table.push(("KBCON".to_string(), 0x94));
-    table.push(("KBMASK".to_string(),0x86));
-    table.push(("KBPATN".to_string(),0x93));
-    table.push(("OCRAH".to_string(), 0xEF));
-    table.push(("OCRAL".to_string(), 0xEE));
-    table.push(("OCRBH".to_string(), 0xFB));
-    table.push(("OCRBL".to_string(), 0xFA));
-    table.push(("OCRCH".to_string(), 0xFD));
-    table.push(("OCRCL".to_string(), 0xFC));
-    table.push(("OCRDH".to_string(), 0xFF));
-    table.push(("OCRDL".to_string(), 0xFE));
-    table.push(("P0M1".to_string(),0x84));
-    table.push(("P0M2".to_string(),0x85));
-    table.push(("P1M1".to_string(),0x91));
-    table.push(("P1M2".to_string(),0x92));
-    table.push(("P2M1".to_string(),0xA4));
-    table.push(("P2M2".to_string(),0xA5));
-    table.push(("P3M1".to_string(),0xB1));
-    table.push(("P3M2".to_string(),0xB2));
-    table.push(("PCONA".to_string(), 0xB5));
-    table.push(("PT0AD".to_string(), 0xF6));
-    table.push(("RSTSRC".to_string(),0xDF));
-    table.push(("RTCCON".to_string(),0xD1));
-    table.push(("RTCH".to_string(),0xD2));
-    table.push(("RTCL".to_string(),0xD3));
-    table.push(("SSTAT".to_string(), 0xBA));
-    table.push(("SPCTL".to_string(), 0xE2));
-    table.push(("SPSTAT".to_string(),0xE1));
-    table.push(("SPDAT".to_string(), 0xE3));
-    table.push(("TAMOD".to_string(), 0x8F));
-    table.push(("TCR20".to_string(), 0xC8));
-    table.push(("TCR21".to_string(), 0xF9));
-    table.push(("TICR2".to_string(), 0xC9));
-    table.push(("TIFR2".to_string(), 0xE9));
-    table.push(("TISE2".to_string(), 0xDE));
-    table.push(("TOR2H".to_string(), 0xCF));
-    table.push(("TOR2L".to_string(), 0xCE));
-    table.push(("TPCR2H".to_string(),0xCB));
-    table.push(("TPCR2L".to_string(),0xCA));
-    table.push(("TRIM".to_string(),0x96));
-    table.push(("WDCON".to_string(), 0xA7));
-    table.push(("WDL".to_string(), 0xC1));
-    table.push(("WFEED1".to_string(),0xC2));
-    table.push(("WFEED2".to_string(),0xC3));
-    table.push(("IP0H".to_string(),0xB7));
-    table
+/// Assembles `source` the way `main` does, without a listing or output
+/// files, for tests of the stages that use the code.
+#[cfg(test)]
+fn assemble(source: &str) -> Result<Vec<omf::ContentRecord>, Vec<Diagnostic>> {
+    let build = build(source, &[], &Options::parse(&[], false).0, Vec::new());
+    if has_errors(&build.diagnostics) {
+        return Err(build.diagnostics);
+    }
+    Ok(build.records)
+}
+
+fn sfr_table() -> Vec<Symbol> {
+    vec![
+        Symbol::new("P0".to_string(), 0x80, SymbolKind::Sfr, 0),
+        Symbol::new("P1".to_string(), 0x90, SymbolKind::Sfr, 0),
+        Symbol::new("P2".to_string(), 0xA0, SymbolKind::Sfr, 0),
+        Symbol::new("P3".to_string(), 0xB0, SymbolKind::Sfr, 0),
+        Symbol::new("PSW".to_string(), 0xD0, SymbolKind::Sfr, 0),
+        Symbol::new("ACC".to_string(), 0xE0, SymbolKind::Sfr, 0),
+        Symbol::new("B".to_string(), 0xF0, SymbolKind::Sfr, 0),
+        Symbol::new("SP".to_string(), 0x81, SymbolKind::Sfr, 0),
+        Symbol::new("DPL".to_string(), 0x82, SymbolKind::Sfr, 0),
+        Symbol::new("DPH".to_string(), 0x83, SymbolKind::Sfr, 0),
+        Symbol::new("PCON".to_string(), 0x87, SymbolKind::Sfr, 0),
+        Symbol::new("TCON".to_string(), 0x88, SymbolKind::Sfr, 0),
+        Symbol::new("TMOD".to_string(), 0x89, SymbolKind::Sfr, 0),
+        Symbol::new("TL0".to_string(), 0x8A, SymbolKind::Sfr, 0),
+        Symbol::new("TL1".to_string(), 0x8B, SymbolKind::Sfr, 0),
+        Symbol::new("TH0".to_string(), 0x8C, SymbolKind::Sfr, 0),
+        Symbol::new("TH1".to_string(), 0x8D, SymbolKind::Sfr, 0),
+        Symbol::new("IEN0".to_string(), 0xA8, SymbolKind::Sfr, 0),
+        Symbol::new("IP0".to_string(), 0xB8, SymbolKind::Sfr, 0),
+        Symbol::new("SCON".to_string(), 0x98, SymbolKind::Sfr, 0),
+        Symbol::new("SBUF".to_string(), 0x99, SymbolKind::Sfr, 0),
+        Symbol::new("AUXR1".to_string(), 0xA2, SymbolKind::Sfr, 0),
+        Symbol::new("SADDR".to_string(), 0xA9, SymbolKind::Sfr, 0),
+        Symbol::new("SADEN".to_string(), 0xB9, SymbolKind::Sfr, 0),
+        Symbol::new("TL2".to_string(), 0xCC, SymbolKind::Sfr, 0),
+        Symbol::new("TH2".to_string(), 0xCD, SymbolKind::Sfr, 0),
+        Symbol::new("BRGR0".to_string(), 0xBE, SymbolKind::Sfr, 0),
+        Symbol::new("BRGR1".to_string(), 0xBF, SymbolKind::Sfr, 0),
+        Symbol::new("BRGCON".to_string(), 0xBD, SymbolKind::Sfr, 0),
+        Symbol::new("CCCRA".to_string(), 0xEA, SymbolKind::Sfr, 0),
+        Symbol::new("CCCRB".to_string(), 0xEB, SymbolKind::Sfr, 0),
+        Symbol::new("CCCRC".to_string(), 0xEC, SymbolKind::Sfr, 0),
+        Symbol::new("CCCRD".to_string(), 0xED, SymbolKind::Sfr, 0),
+        Symbol::new("CMP1".to_string(), 0xAC, SymbolKind::Sfr, 0),
+        Symbol::new("CMP2".to_string(), 0xAD, SymbolKind::Sfr, 0),
+        Symbol::new("DEECON".to_string(), 0xF1, SymbolKind::Sfr, 0),
+        Symbol::new("DEEDAT".to_string(), 0xF2, SymbolKind::Sfr, 0),
+        Symbol::new("DEEADR".to_string(), 0xF3, SymbolKind::Sfr, 0),
+        Symbol::new("DIVM".to_string(), 0x95, SymbolKind::Sfr, 0),
+        Symbol::new("I2ADR".to_string(), 0xDB, SymbolKind::Sfr, 0),
+        Symbol::new("I2CON".to_string(), 0xD8, SymbolKind::Sfr, 0),
+        Symbol::new("I2DAT".to_string(), 0xDA, SymbolKind::Sfr, 0),
+        Symbol::new("I2SCLH".to_string(), 0xDD, SymbolKind::Sfr, 0),
+        Symbol::new("I2SCLL".to_string(), 0xDC, SymbolKind::Sfr, 0),
+        Symbol::new("I2STAT".to_string(), 0xD9, SymbolKind::Sfr, 0),
+        Symbol::new("ICRAH".to_string(), 0xAB, SymbolKind::Sfr, 0),
+        Symbol::new("ICRAL".to_string(), 0xAA, SymbolKind::Sfr, 0),
+        Symbol::new("ICRBH".to_string(), 0xAF, SymbolKind::Sfr, 0),
+        Symbol::new("ICRBL".to_string(), 0xAE, SymbolKind::Sfr, 0),
+        Symbol::new("IEN1".to_string(), 0xE8, SymbolKind::Sfr, 0),
+        Symbol::new("IP1".to_string(), 0xF8, SymbolKind::Sfr, 0),
+        Symbol::new("IP1H".to_string(), 0xF7, SymbolKind::Sfr, 0),
+        Symbol::new("KBCON".to_string(), 0x94, SymbolKind::Sfr, 0),
+        Symbol::new("KBMASK".to_string(), 0x86, SymbolKind::Sfr, 0),
+        Symbol::new("KBPATN".to_string(), 0x93, SymbolKind::Sfr, 0),
+        Symbol::new("OCRAH".to_string(), 0xEF, SymbolKind::Sfr, 0),
+        Symbol::new("OCRAL".to_string(), 0xEE, SymbolKind::Sfr, 0),
+        Symbol::new("OCRBH".to_string(), 0xFB, SymbolKind::Sfr, 0),
+        Symbol::new("OCRBL".to_string(), 0xFA, SymbolKind::Sfr, 0),
+        Symbol::new("OCRCH".to_string(), 0xFD, SymbolKind::Sfr, 0),
+        Symbol::new("OCRCL".to_string(), 0xFC, SymbolKind::Sfr, 0),
+        Symbol::new("OCRDH".to_string(), 0xFF, SymbolKind::Sfr, 0),
+        Symbol::new("OCRDL".to_string(), 0xFE, SymbolKind::Sfr, 0),
+        Symbol::new("P0M1".to_string(), 0x84, SymbolKind::Sfr, 0),
+        Symbol::new("P0M2".to_string(), 0x85, SymbolKind::Sfr, 0),
+        Symbol::new("P1M1".to_string(), 0x91, SymbolKind::Sfr, 0),
+        Symbol::new("P1M2".to_string(), 0x92, SymbolKind::Sfr, 0),
+        Symbol::new("P2M1".to_string(), 0xA4, SymbolKind::Sfr, 0),
+        Symbol::new("P2M2".to_string(), 0xA5, SymbolKind::Sfr, 0),
+        Symbol::new("P3M1".to_string(), 0xB1, SymbolKind::Sfr, 0),
+        Symbol::new("P3M2".to_string(), 0xB2, SymbolKind::Sfr, 0),
+        Symbol::new("PCONA".to_string(), 0xB5, SymbolKind::Sfr, 0),
+        Symbol::new("PT0AD".to_string(), 0xF6, SymbolKind::Sfr, 0),
+        Symbol::new("RSTSRC".to_string(), 0xDF, SymbolKind::Sfr, 0),
+        Symbol::new("RTCCON".to_string(), 0xD1, SymbolKind::Sfr, 0),
+        Symbol::new("RTCH".to_string(), 0xD2, SymbolKind::Sfr, 0),
+        Symbol::new("RTCL".to_string(), 0xD3, SymbolKind::Sfr, 0),
+        Symbol::new("SSTAT".to_string(), 0xBA, SymbolKind::Sfr, 0),
+        Symbol::new("SPCTL".to_string(), 0xE2, SymbolKind::Sfr, 0),
+        Symbol::new("SPSTAT".to_string(), 0xE1, SymbolKind::Sfr, 0),
+        Symbol::new("SPDAT".to_string(), 0xE3, SymbolKind::Sfr, 0),
+        Symbol::new("TAMOD".to_string(), 0x8F, SymbolKind::Sfr, 0),
+        Symbol::new("TCR20".to_string(), 0xC8, SymbolKind::Sfr, 0),
+        Symbol::new("TCR21".to_string(), 0xF9, SymbolKind::Sfr, 0),
+        Symbol::new("TICR2".to_string(), 0xC9, SymbolKind::Sfr, 0),
+        Symbol::new("TIFR2".to_string(), 0xE9, SymbolKind::Sfr, 0),
+        Symbol::new("TISE2".to_string(), 0xDE, SymbolKind::Sfr, 0),
+        Symbol::new("TOR2H".to_string(), 0xCF, SymbolKind::Sfr, 0),
+        Symbol::new("TOR2L".to_string(), 0xCE, SymbolKind::Sfr, 0),
+        Symbol::new("TPCR2H".to_string(), 0xCB, SymbolKind::Sfr, 0),
+        Symbol::new("TPCR2L".to_string(), 0xCA, SymbolKind::Sfr, 0),
+        Symbol::new("TRIM".to_string(), 0x96, SymbolKind::Sfr, 0),
+        Symbol::new("WDCON".to_string(), 0xA7, SymbolKind::Sfr, 0),
+        Symbol::new("WDL".to_string(), 0xC1, SymbolKind::Sfr, 0),
+        Symbol::new("WFEED1".to_string(), 0xC2, SymbolKind::Sfr, 0),
+        Symbol::new("WFEED2".to_string(), 0xC3, SymbolKind::Sfr, 0),
+        Symbol::new("IP0H".to_string(), 0xB7, SymbolKind::Sfr, 0),
+    ]
+}
+
+fn bit_table() -> Vec<Symbol> {
+    vec![
+        // TCON
+        Symbol::new("IT0".to_string(), 0x88, SymbolKind::Bit, 0),
+        Symbol::new("IE0".to_string(), 0x89, SymbolKind::Bit, 0),
+        Symbol::new("IT1".to_string(), 0x8A, SymbolKind::Bit, 0),
+        Symbol::new("IE1".to_string(), 0x8B, SymbolKind::Bit, 0),
+        Symbol::new("TR0".to_string(), 0x8C, SymbolKind::Bit, 0),
+        Symbol::new("TF0".to_string(), 0x8D, SymbolKind::Bit, 0),
+        Symbol::new("TR1".to_string(), 0x8E, SymbolKind::Bit, 0),
+        Symbol::new("TF1".to_string(), 0x8F, SymbolKind::Bit, 0),
+        // SCON
+        Symbol::new("RI".to_string(), 0x98, SymbolKind::Bit, 0),
+        Symbol::new("TI".to_string(), 0x99, SymbolKind::Bit, 0),
+        Symbol::new("RB8".to_string(), 0x9A, SymbolKind::Bit, 0),
+        Symbol::new("TB8".to_string(), 0x9B, SymbolKind::Bit, 0),
+        Symbol::new("REN".to_string(), 0x9C, SymbolKind::Bit, 0),
+        Symbol::new("SM2".to_string(), 0x9D, SymbolKind::Bit, 0),
+        Symbol::new("SM1".to_string(), 0x9E, SymbolKind::Bit, 0),
+        Symbol::new("SM0".to_string(), 0x9F, SymbolKind::Bit, 0),
+        Symbol::new("FE".to_string(), 0x9F, SymbolKind::Bit, 0),
+        // IEN0
+        Symbol::new("EX0".to_string(), 0xA8, SymbolKind::Bit, 0),
+        Symbol::new("ET0".to_string(), 0xA9, SymbolKind::Bit, 0),
+        Symbol::new("EX1".to_string(), 0xAA, SymbolKind::Bit, 0),
+        Symbol::new("ET1".to_string(), 0xAB, SymbolKind::Bit, 0),
+        Symbol::new("ES".to_string(), 0xAC, SymbolKind::Bit, 0),
+        Symbol::new("ESR".to_string(), 0xAC, SymbolKind::Bit, 0),
+        Symbol::new("EBO".to_string(), 0xAD, SymbolKind::Bit, 0),
+        Symbol::new("EWDRT".to_string(), 0xAE, SymbolKind::Bit, 0),
+        Symbol::new("EA".to_string(), 0xAF, SymbolKind::Bit, 0),
+        // IP0
+        Symbol::new("PX0".to_string(), 0xB8, SymbolKind::Bit, 0),
+        Symbol::new("PT0".to_string(), 0xB9, SymbolKind::Bit, 0),
+        Symbol::new("PX1".to_string(), 0xBA, SymbolKind::Bit, 0),
+        Symbol::new("PT1".to_string(), 0xBB, SymbolKind::Bit, 0),
+        Symbol::new("PS".to_string(), 0xBC, SymbolKind::Bit, 0),
+        Symbol::new("PSR".to_string(), 0xBC, SymbolKind::Bit, 0),
+        Symbol::new("PBO".to_string(), 0xBD, SymbolKind::Bit, 0),
+        Symbol::new("PWDRT".to_string(), 0xBE, SymbolKind::Bit, 0),
+        // PSW
+        Symbol::new("P".to_string(), 0xD0, SymbolKind::Bit, 0),
+        Symbol::new("F1".to_string(), 0xD1, SymbolKind::Bit, 0),
+        Symbol::new("OV".to_string(), 0xD2, SymbolKind::Bit, 0),
+        Symbol::new("RS0".to_string(), 0xD3, SymbolKind::Bit, 0),
+        Symbol::new("RS1".to_string(), 0xD4, SymbolKind::Bit, 0),
+        Symbol::new("F0".to_string(), 0xD5, SymbolKind::Bit, 0),
+        Symbol::new("AC".to_string(), 0xD6, SymbolKind::Bit, 0),
+        Symbol::new("CY".to_string(), 0xD7, SymbolKind::Bit, 0),
+        // I2CON
+        Symbol::new("CRSEL".to_string(), 0xD8, SymbolKind::Bit, 0),
+        Symbol::new("SI".to_string(), 0xDB, SymbolKind::Bit, 0),
+        Symbol::new("STO".to_string(), 0xDC, SymbolKind::Bit, 0),
+        Symbol::new("STA".to_string(), 0xDD, SymbolKind::Bit, 0),
+        Symbol::new("I2EN".to_string(), 0xDE, SymbolKind::Bit, 0),
+        // IEN1
+        Symbol::new("EI2C".to_string(), 0xE8, SymbolKind::Bit, 0),
+        Symbol::new("EKBI".to_string(), 0xE9, SymbolKind::Bit, 0),
+        Symbol::new("EC".to_string(), 0xEA, SymbolKind::Bit, 0),
+        Symbol::new("ESPI".to_string(), 0xEB, SymbolKind::Bit, 0),
+        Symbol::new("ECCU".to_string(), 0xEC, SymbolKind::Bit, 0),
+        Symbol::new("EST".to_string(), 0xEE, SymbolKind::Bit, 0),
+        // IP1
+        Symbol::new("PI2C".to_string(), 0xF8, SymbolKind::Bit, 0),
+        Symbol::new("PKBI".to_string(), 0xF9, SymbolKind::Bit, 0),
+        Symbol::new("PC".to_string(), 0xFA, SymbolKind::Bit, 0),
+        Symbol::new("PSPI".to_string(), 0xFB, SymbolKind::Bit, 0),
+        Symbol::new("PCCU".to_string(), 0xFC, SymbolKind::Bit, 0),
+        Symbol::new("PST".to_string(), 0xFE, SymbolKind::Bit, 0),
+    ]
 }
 
 enum OutType {
@@ -213,35 +660,16 @@ enum OutType {
     Hex,
 }
 
-fn output_bin(name:String, records:Vec<omf::ContentRecord>) {
+fn output_bin(name:String, records:Vec<omf::ContentRecord>) -> Result<(), Diagnostic> {
     let mut hex_table = HexTable::new_empty();
     for record in records {
         hex_table.append_content(record);
     }
 
 
-    let ext = ".bin";
-    let file_name = &([&name, ext].join(""));
-    let path = Path::new(file_name);
-    let display = path.display();
-
-    let mut file = match File::create(&path) {
-        Err(why) => panic!("couldn't create {}: {}",
-        display,
-        why.description()),
-        Ok(file) => file,
-    };
-
-    // Write the `LOREM_IPSUM` string to `file`, returns `io::Result<()>`
-
-    match file.write_all(&hex_table.table) {
-        Err(why) => {
-            panic!("couldn't write to {}: {}", display,
-            why.description())
-        },
-        Ok(_) => println!("successfully assembled to {}", display),
-    }
-
+    let file = write_file(&name, ".bin", &hex_table.table)?;
+    println!("successfully assembled to {}", file);
+    Ok(())
 }
 
 fn checksome(vec: &Vec<u8>)->u8{
@@ -257,23 +685,8 @@ fn checksome(vec: &Vec<u8>)->u8{
     sum8+1
 }
 
-fn output_hex(name:String, records:Vec<omf::ContentRecord>) {
-
-
-
-    let ext = ".hex";
-    let file_name = &([&name, ext].join(""));
-    let path = Path::new(file_name);
-    let display = path.display();
-
-    let mut file = match File::create(&path) {
-        Err(why) => panic!("couldn't create {}: {}",
-        display,
-        why.description()),
-        Ok(file) => file,
-    };
-
-    // Write the `LOREM_IPSUM` string to `file`, returns `io::Result<()>`
+fn output_hex(name:String, records:Vec<omf::ContentRecord>) -> Result<(), Diagnostic> {
+    let mut out = String::new();
     for record in records {
         if record.data().len()>0{
             for i in 0 .. record.data().len()/0x10 +1{
@@ -306,7 +719,8 @@ fn output_hex(name:String, records:Vec<omf::ContentRecord>) {
                     line_out+=&format!("{:02x}", h);
 
                 }
-                write!(file, "{}\n", line_out.to_uppercase()).unwrap();
+                out += &line_out.to_uppercase();
+                out.push('\n');
 
 
             }
@@ -314,13 +728,14 @@ fn output_hex(name:String, records:Vec<omf::ContentRecord>) {
         }
 
     }
-    write!(file, ":00000001FF\n").unwrap();
-
-    println!("successfully assembled to {}", display);
+    out += ":00000001FF\n";
 
+    let file = write_file(&name, ".hex", out.as_bytes())?;
+    println!("successfully assembled to {}", file);
+    Ok(())
 }
 
-fn output_obj(name: String, records:Vec<omf::ContentRecord>) {
+fn output_obj(name: String, records:Vec<omf::ContentRecord>) -> Result<(), Diagnostic> {
     let mut hex_table = HexTable::new_empty();
     let header = omf::HeaderRecord::new(name.clone().to_uppercase());
     let end = omf::EndRecord::new(name.clone().to_uppercase(), (true,false,false,false));
@@ -330,26 +745,127 @@ fn output_obj(name: String, records:Vec<omf::ContentRecord>) {
     }
     hex_table.append_record(end);
 
+    let file = write_file(&name, ".obj", &hex_table.table)?;
+    println!("successfully assembled to {}", file);
+    Ok(())
+}
 
-    let ext = ".obj";
-    let file_name = &([&name, ext].join(""));
-    let path = Path::new(file_name);
-    let display = path.display();
+fn output_text(name: &str, ext: &str, text: String) -> Result<(), Diagnostic> {
+    let file = write_file(name, ext, text.as_bytes())?;
+    println!("wrote {}", file);
+    Ok(())
+}
 
-    let mut file = match File::create(&path) {
-        Err(why) => panic!("couldn't create {}: {}",
-        display,
-        why.description()),
+/// Writes `data` to `name` + `ext` and returns the file name.
+fn write_file(name: &str, ext: &str, data: &[u8]) -> Result<String, Diagnostic> {
+    let file_name = [name, ext].join("");
+    let mut file = match File::create(&file_name) {
+        Err(why) => return Err(Diagnostic::error(0, format!("couldn't create {}: {}", file_name, why))),
         Ok(file) => file,
     };
+    match file.write_all(data) {
+        Err(why) => Err(Diagnostic::error(0, format!("couldn't write to {}: {}", file_name, why))),
+        Ok(_) => Ok(file_name),
+    }
+}
 
-    // Write the `LOREM_IPSUM` string to `file`, returns `io::Result<()>`
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use include::Origin;
+
+    /// What `finish` prints for `source`, with the lines from `origins`,
+    /// and the status it exits with.
+    fn output(source: &str, origins: &[Origin], args: &[&str]) -> (String, i32) {
+        let args: Vec<String> = args.iter().map(|x| x.to_string()).collect();
+        let (options, diagnostics) = Options::parse(&args, false);
+        let mut diagnostics = build(source, origins, &options, diagnostics).diagnostics;
+        options.apply(&mut diagnostics);
+        report("main.asm", diagnostics, origins)
+    }
 
-    match file.write_all(&hex_table.table) {
-        Err(why) => {
-            panic!("couldn't write to {}: {}", display,
-            why.description())
-        },
-        Ok(_) => println!("successfully assembled to {}", display),
+    #[test]
+    fn reports_in_source_order() {
+        let (text, status) = output(" nop\n frob a\n nop\n", &[], &[]);
+        assert_eq!(text, "main.asm:2:2: error: Unknown mnemonic: frob\n\
+            main.asm:3: warning: missing END directive [-Wmissing-end]\n\
+            Failed to build main.asm\n");
+        assert_eq!(status, 1);
+    }
+
+    #[test]
+    fn reports_the_include_chain() {
+        let main = |line| Origin { file: String::from("main.asm"), line, chain: Vec::new() };
+        let inc = |line| Origin { file: String::from("defs.inc"), line, chain: vec![(String::from("main.asm"), 2)] };
+        let source = " nop\n; include \"defs.inc\"\n frob\n end\n";
+        let (text, status) = output(source, &[main(1), main(2), inc(1), main(3)], &[]);
+        assert_eq!(text, "In file included from main.asm:2:\n\
+            defs.inc:1:2: error: Unknown mnemonic: frob\n\
+            Failed to build main.asm\n");
+        assert_eq!(status, 1);
+    }
+
+    #[test]
+    fn fails_only_on_errors() {
+        assert_eq!(output(" nop\n end\n", &[], &[]), (String::new(), 0));
+        assert_eq!(output(" nop\n", &[], &[]), (String::from("main.asm:1: warning: missing END directive [-Wmissing-end]\n"), 0));
+        assert_eq!(output(" nop\n", &[], &["-Werror"]), (String::from("main.asm:1: error: missing END directive [-Wmissing-end]\n\
+            Failed to build main.asm\n"), 1));
+    }
+
+    #[test]
+    fn checks_only_the_lines_conditional_assembly_keeps() {
+        assert_eq!(output(" nop\n if 0\n end\n endif\n", &[], &[]),
+            (String::from("main.asm:4: warning: missing END directive [-Wmissing-end]\n"), 0));
+        assert_eq!(output(" if 0\n db 'abc\n endif\n end\n", &[], &[]), (String::new(), 0));
+        assert_eq!(output(" if 1\n db 'abc\n endif\n end\n", &[], &[]),
+            (String::from("main.asm:2:5: error: Unterminated string\nFailed to build main.asm\n"), 1));
+        let source = "m macro\n db 'abc\n endm\n m\n end\n";
+        assert_eq!(output(source, &[], &[]),
+            (String::from("In expansion of M at main.asm:4:\nmain.asm:4: error: Unterminated string\nFailed to build main.asm\n"), 1));
+    }
+
+    #[test]
+    fn warns_of_each_kind_unless_disabled() {
+        let cases: [(Warning, &str, &[&str]); 7] = [
+            (Warning::UnknownOption, " end\n", &["-q"]),
+            (Warning::Truncation, " mov a, #300\n end\n", &[]),
+            (Warning::UnusedLabel, "here: nop\n end\n", &[]),
+            (Warning::DuplicateLabel, "n equ 1\nn equ 1\n end\n", &[]),
+            (Warning::DsOverlap, " ds 4\n org 2\n nop\n end\n", &[]),
+            (Warning::OrgOverlap, " nop\n nop\n org 1\n nop\n end\n", &[]),
+            (Warning::MissingEnd, " nop\n", &[]),
+        ];
+        for &(warning, source, args) in cases.iter() {
+            let flag = format!("[-W{}]", warning.name());
+            let (text, status) = output(source, &[], args);
+            assert!(text.contains("warning: ") && text.contains(&flag), "{}", text);
+            assert_eq!((text.lines().count(), status), (1, 0), "{}", text);
+
+            let off = format!("-Wno-{}", warning.name());
+            let disabled: Vec<&str> = args.iter().cloned().chain(Some(off.as_str())).collect();
+            assert_eq!(output(source, &[], &disabled), (String::new(), 0));
+
+            let werror: Vec<&str> = args.iter().cloned().chain(Some("-Werror")).collect();
+            let (text, status) = output(source, &[], &werror);
+            assert!(text.contains(&flag) && text.contains("error: "), "{}", text);
+            assert_eq!(status, 1);
+        }
+        assert!(Warning::ALL.iter().all(|x| cases.iter().any(|c| c.0 == *x)));
+    }
+
+    #[test]
+    fn defines_numbers_from_the_command_line() {
+        assert_eq!(output(" mov a, #x\n end\n", &[], &["-Dx=0FFFFh", "-Wno-truncation"]), (String::new(), 0));
+        assert_eq!(output(" end\n", &[], &["-Dx=70000"]),
+            (String::from("error: -D x=70000: Value 0x11170 out of range for Number symbol\nFailed to build main.asm\n"), 1));
+        assert_eq!(output("x: nop\n sjmp x\n end\n", &[], &["-D", "X=5"]),
+            (String::from("main.asm:1: error: x conflicts with X defined on the command line (-D) (05h)\nFailed to build main.asm\n"), 1));
+    }
+
+    #[test]
+    fn reports_unknown_warning_names() {
+        assert_eq!(output(" end\n", &[], &["-Wno-foo"]),
+            (String::from("warning: unknown warning -Wno-foo [-Wunknown-option]\n"), 0));
     }
 }
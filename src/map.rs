@@ -0,0 +1,112 @@
+use section::Section;
+use symbol::{Symbol, SymbolKind};
+use include::{self, Origin};
+
+/// The order symbols are grouped in: the address spaces, then plain
+/// numbers.
+const KINDS: [SymbolKind; 8] = [
+    SymbolKind::Code,
+    SymbolKind::Data,
+    SymbolKind::Idata,
+    SymbolKind::Bit,
+    SymbolKind::Xdata,
+    SymbolKind::Sfr,
+    SymbolKind::Number,
+    SymbolKind::Set,
+];
+
+/// Builds the text of a `.map` file: the segments of each space in address
+/// order with their sizes, then for each kind of symbol every user defined
+/// symbol and every predefined symbol the program uses, by name with the
+/// lines that refer to it and by value. Lines are numbered within their
+/// own file through `origins`.
+pub fn symbol_map(sections: &[Section], symbols: &[Symbol], origins: &[Origin]) -> String {
+    let mut segments: Vec<&Section> = sections.iter().filter(|x| x.end() > x.offset() as u32).collect();
+    segments.sort_by_key(|x| (x.space() as usize, x.offset()));
+    let mut out = String::from("SEGMENTS\n\nSPACE   START  END    SIZE\n");
+    for sec in segments {
+        out += &format!("{:<7} {:04X}   {:04X}   {:04X}\n", sec.space().name(), sec.offset(), sec.end() - 1, sec.end() - sec.offset() as u32);
+    }
+
+    for &kind in KINDS.iter() {
+        let mut used: Vec<&Symbol> = symbols.iter().filter(|x| x.kind == kind && (x.line > 0 || !x.refs.is_empty())).collect();
+        if used.is_empty() {
+            continue;
+        }
+        used.sort_by_key(|x| x.name.to_lowercase());
+        out += &format!("\n\n{} SYMBOLS\n\nNAME                      VALUE  DEFINED  REFERENCES\n", kind.name());
+        for sym in &used {
+            let defined = if sym.line > 0 { include::source_line(origins, sym.line) } else { String::from("-") };
+            let refs: Vec<String> = sym.refs.iter().map(|&x| include::source_line(origins, x)).collect();
+            out += format!("{:<25} {:04X}   {:>7}  {}", sym.name, sym.value, defined, refs.join(" ")).trim_end();
+            out.push('\n');
+        }
+        used.sort_by_key(|x| x.value);
+        out += "\nVALUE  NAME\n";
+        for sym in &used {
+            out += &format!("{:04X}   {}\n", sym.value, sym.name);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {build, Options};
+
+    #[test]
+    fn lists_segments_and_symbols_by_space() {
+        let source = " cseg at 100h
+main: mov buf, #size
+ sjmp main
+ dseg at 30h
+buf: ds 4
+ cseg at 0
+ ljmp main
+ dseg
+count: ds 1
+size equ 2
+ end
+";
+        let build = build(source, &[], &Options::parse(&[], false).0, Vec::new());
+        assert!(build.diagnostics.iter().all(|x| !x.is_error()));
+        assert_eq!(symbol_map(&build.sections, &build.symbols, &[]), "SEGMENTS
+
+SPACE   START  END    SIZE
+CODE    0000   0002   0003
+CODE    0100   0104   0005
+DATA    0030   0033   0004
+DATA    0034   0034   0001
+
+
+CODE SYMBOLS
+
+NAME                      VALUE  DEFINED  REFERENCES
+main                      0100         2  3 7
+
+VALUE  NAME
+0100   main
+
+
+DATA SYMBOLS
+
+NAME                      VALUE  DEFINED  REFERENCES
+buf                       0030         5  2
+count                     0034         9
+
+VALUE  NAME
+0030   buf
+0034   count
+
+
+NUMBER SYMBOLS
+
+NAME                      VALUE  DEFINED  REFERENCES
+size                      0002        10  2
+
+VALUE  NAME
+0002   size
+");
+    }
+}
@@ -38,8 +38,8 @@ impl Record for HeaderRecord {
     fn hex (&self) ->Vec<u8>{
         let mut vec = Vec::new();
         vec.push(HeaderRecord::TYPE);
-        vec.push((self.len()%0xFF) as u8);
-        vec.push((self.len()/0xFF) as u8);
+        vec.push((self.len() & 0xFF) as u8);
+        vec.push((self.len() >> 8) as u8);
         vec.push(self.name.len() as u8);
         let name_vec = self.name.as_bytes();
 
@@ -75,8 +75,8 @@ impl Record for EndRecord {
     fn hex (&self) ->Vec<u8>{
         let mut vec = Vec::new();
         vec.push(EndRecord::TYPE);
-        vec.push((self.len()&0xFF) as u8);
-        vec.push((self.len()/0xFF) as u8);
+        vec.push((self.len() & 0xFF) as u8);
+        vec.push((self.len() >> 8) as u8);
         vec.push(self.name.len() as u8);
         let name_vec = self.name.as_bytes();
         for c in name_vec {
@@ -124,11 +124,11 @@ impl Record for ContentRecord {
         }
         let mut vec = Vec::new();
         vec.push(ContentRecord::TYPE);
-        vec.push((self.len()%0xFF) as u8);
-        vec.push((self.len()/0xFF) as u8);
+        vec.push((self.len() & 0xFF) as u8);
+        vec.push((self.len() >> 8) as u8);
         vec.push(self.seg_id);
-        vec.push((self.offset%0xFF) as u8);
-        vec.push((self.offset/0xFF) as u8);
+        vec.push((self.offset & 0xFF) as u8);
+        vec.push((self.offset >> 8) as u8);
         for d in &self.data {
             vec.push(*d);
         }
@@ -138,6 +138,35 @@ impl Record for ContentRecord {
     }
 }
 
+/// Reads the content records of an OMF-51 object file.
+pub fn read(bytes: &[u8]) -> Result<Vec<ContentRecord>, String> {
+    let mut records = Vec::new();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        if pos + 3 > bytes.len() {
+            return Err(format!("Truncated record at {:#X}", pos));
+        }
+        let kind = bytes[pos];
+        let len = bytes[pos + 1] as usize | (bytes[pos + 2] as usize) << 8;
+        let end = pos + 3 + len;
+        if len == 0 || end > bytes.len() {
+            return Err(format!("Truncated record at {:#X}", pos));
+        }
+        if bytes[pos..end].iter().fold(0u8, |sum, x| sum.wrapping_add(*x)) != 0 {
+            return Err(format!("Bad checksum in record at {:#X}", pos));
+        }
+        if kind == ContentRecord::TYPE {
+            if len < 4 {
+                return Err(format!("Truncated record at {:#X}", pos));
+            }
+            let offset = bytes[pos + 4] as u16 | (bytes[pos + 5] as u16) << 8;
+            records.push(ContentRecord::new(offset, bytes[pos + 6..end - 1].to_vec(), bytes[pos + 3]));
+        }
+        pos = end;
+    }
+    Ok(records)
+}
+
 // pub struct SegmentRecord {
 //     pub name: String,
 //     seg_id: u8,
@@ -177,3 +206,29 @@ impl Record for ContentRecord {
 //         vec
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_record_length_and_offset_are_little_endian() {
+        let record = ContentRecord::new(0x12FF, vec![0xAA; 300], 0);
+        let bytes = record.hex();
+        assert_eq!(&bytes[..6], &[ContentRecord::TYPE, 0x30, 0x01, 0x00, 0xFF, 0x12]);
+        assert_eq!(bytes.len(), 3 + 304);
+        assert_eq!(bytes.iter().fold(0u8, |sum, x| sum.wrapping_add(*x)), 0);
+
+        let read = read(&bytes).unwrap();
+        assert_eq!(read[0].offset(), 0x12FF);
+        assert_eq!(read[0].data(), vec![0xAA; 300]);
+    }
+
+    #[test]
+    fn header_and_end_record_lengths() {
+        let header = HeaderRecord::new(String::from("TEST")).hex();
+        assert_eq!(&header[..3], &[HeaderRecord::TYPE, 8, 0]);
+        let end = EndRecord::new(String::from("TEST"), (true, false, false, false)).hex();
+        assert_eq!(&end[..3], &[EndRecord::TYPE, 10, 0]);
+    }
+}
@@ -0,0 +1,378 @@
+use self::Operand::*;
+
+/// The kind of each operand of an instruction form.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    A,
+    AB,
+    C,
+    Dptr,
+    AtDptr,
+    AtADptr,
+    AtAPc,
+    /// `Rn`, encoded in the opcode.
+    Reg(u8),
+    /// `@Ri`, encoded in the opcode.
+    AtReg(u8),
+    /// `#data`
+    Imm,
+    /// `#data16`
+    Imm16,
+    /// Internal RAM or SFR address.
+    Direct,
+    Bit,
+    /// `/bit`, the complement of a bit.
+    NotBit,
+    /// Signed displacement from the end of the instruction.
+    Rel,
+    /// Address in the 2K page of the next instruction; the top three bits
+    /// go in the opcode.
+    Addr11,
+    Addr16,
+}
+
+impl Operand {
+    /// Number of bytes the operand adds after the opcode.
+    pub fn size(self) -> usize {
+        match self {
+            Imm | Direct | Bit | NotBit | Rel | Addr11 => 1,
+            Imm16 | Addr16 => 2,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Opcode {
+    pub code: u8,
+    pub mnemonic: &'static str,
+    pub operands: &'static [Operand],
+}
+
+impl Opcode {
+    pub fn len(&self) -> usize {
+        1 + self.operands.iter().map(|x| x.size()).sum::<usize>()
+    }
+
+    /// Whether the operand bytes are stored in the reverse of the source
+    /// order. Only `MOV direct,direct` does this: the source comes first.
+    pub fn reversed(&self) -> bool {
+        self.code == 0x85
+    }
+
+    /// Machine cycles the instruction takes, whether or not a branch is
+    /// taken. A machine cycle is 12 clocks on the classic 8051 and 2 on the
+    /// LPC9xx.
+    pub fn cycles(&self) -> u32 {
+        match (self.mnemonic, self.operands) {
+            ("MUL", _) | ("DIV", _) => 4,
+            ("AJMP", _) | ("LJMP", _) | ("SJMP", _) | ("JMP", _) | ("ACALL", _) | ("LCALL", _)
+            | ("RET", _) | ("RETI", _) | ("JC", _) | ("JNC", _) | ("JZ", _) | ("JNZ", _) | ("JB", _)
+            | ("JNB", _) | ("JBC", _) | ("CJNE", _) | ("DJNZ", _) => 2,
+            ("MOVC", _) | ("MOVX", _) | ("PUSH", _) | ("POP", _) => 2,
+            ("INC", &[Dptr]) => 2,
+            ("ANL", &[C, _]) | ("ORL", &[C, _]) => 2,
+            ("ANL", &[Direct, Imm]) | ("ORL", &[Direct, Imm]) | ("XRL", &[Direct, Imm]) => 2,
+            ("MOV", &[Bit, C]) | ("MOV", &[Dptr, Imm16]) | ("MOV", &[Direct, Imm]) | ("MOV", &[Direct, Direct])
+            | ("MOV", &[Direct, Reg(_)]) | ("MOV", &[Direct, AtReg(_)]) | ("MOV", &[Reg(_), Direct])
+            | ("MOV", &[AtReg(_), Direct]) => 2,
+            _ => 1,
+        }
+    }
+
+    /// Extracts the operand values, in source order, from the encoded
+    /// instruction. Jump targets are made absolute using `end`, the address
+    /// of the next instruction; operands without a value give 0.
+    pub fn values(&self, bytes: &[u8], end: u16) -> Vec<u16> {
+        let mut values = Vec::new();
+        let mut pos = 1;
+        for kind in self.operands {
+            let value = match *kind {
+                Imm16 | Addr16 => (bytes[pos] as u16) << 8 | bytes[pos + 1] as u16,
+                Rel => end.wrapping_add(bytes[pos] as i8 as u16),
+                Addr11 => (end & 0xF800) | ((bytes[0] >> 5) as u16) << 8 | bytes[pos] as u16,
+                k if k.size() == 1 => bytes[pos] as u16,
+                _ => 0,
+            };
+            pos += kind.size();
+            values.push(value);
+        }
+        if self.reversed() {
+            values.reverse();
+        }
+        values
+    }
+}
+
+const fn op(code: u8, mnemonic: &'static str, operands: &'static [Operand]) -> Opcode {
+    Opcode { code, mnemonic, operands }
+}
+
+/// Every defined opcode, in order. 0xA5 is the only undefined one.
+pub static OPCODES: [Opcode; 255] = [
+    op(0x00, "NOP", &[]),
+    op(0x01, "AJMP", &[Addr11]),
+    op(0x02, "LJMP", &[Addr16]),
+    op(0x03, "RR", &[A]),
+    op(0x04, "INC", &[A]),
+    op(0x05, "INC", &[Direct]),
+    op(0x06, "INC", &[AtReg(0)]),
+    op(0x07, "INC", &[AtReg(1)]),
+    op(0x08, "INC", &[Reg(0)]),
+    op(0x09, "INC", &[Reg(1)]),
+    op(0x0A, "INC", &[Reg(2)]),
+    op(0x0B, "INC", &[Reg(3)]),
+    op(0x0C, "INC", &[Reg(4)]),
+    op(0x0D, "INC", &[Reg(5)]),
+    op(0x0E, "INC", &[Reg(6)]),
+    op(0x0F, "INC", &[Reg(7)]),
+    op(0x10, "JBC", &[Bit, Rel]),
+    op(0x11, "ACALL", &[Addr11]),
+    op(0x12, "LCALL", &[Addr16]),
+    op(0x13, "RRC", &[A]),
+    op(0x14, "DEC", &[A]),
+    op(0x15, "DEC", &[Direct]),
+    op(0x16, "DEC", &[AtReg(0)]),
+    op(0x17, "DEC", &[AtReg(1)]),
+    op(0x18, "DEC", &[Reg(0)]),
+    op(0x19, "DEC", &[Reg(1)]),
+    op(0x1A, "DEC", &[Reg(2)]),
+    op(0x1B, "DEC", &[Reg(3)]),
+    op(0x1C, "DEC", &[Reg(4)]),
+    op(0x1D, "DEC", &[Reg(5)]),
+    op(0x1E, "DEC", &[Reg(6)]),
+    op(0x1F, "DEC", &[Reg(7)]),
+    op(0x20, "JB", &[Bit, Rel]),
+    op(0x21, "AJMP", &[Addr11]),
+    op(0x22, "RET", &[]),
+    op(0x23, "RL", &[A]),
+    op(0x24, "ADD", &[A, Imm]),
+    op(0x25, "ADD", &[A, Direct]),
+    op(0x26, "ADD", &[A, AtReg(0)]),
+    op(0x27, "ADD", &[A, AtReg(1)]),
+    op(0x28, "ADD", &[A, Reg(0)]),
+    op(0x29, "ADD", &[A, Reg(1)]),
+    op(0x2A, "ADD", &[A, Reg(2)]),
+    op(0x2B, "ADD", &[A, Reg(3)]),
+    op(0x2C, "ADD", &[A, Reg(4)]),
+    op(0x2D, "ADD", &[A, Reg(5)]),
+    op(0x2E, "ADD", &[A, Reg(6)]),
+    op(0x2F, "ADD", &[A, Reg(7)]),
+    op(0x30, "JNB", &[Bit, Rel]),
+    op(0x31, "ACALL", &[Addr11]),
+    op(0x32, "RETI", &[]),
+    op(0x33, "RLC", &[A]),
+    op(0x34, "ADDC", &[A, Imm]),
+    op(0x35, "ADDC", &[A, Direct]),
+    op(0x36, "ADDC", &[A, AtReg(0)]),
+    op(0x37, "ADDC", &[A, AtReg(1)]),
+    op(0x38, "ADDC", &[A, Reg(0)]),
+    op(0x39, "ADDC", &[A, Reg(1)]),
+    op(0x3A, "ADDC", &[A, Reg(2)]),
+    op(0x3B, "ADDC", &[A, Reg(3)]),
+    op(0x3C, "ADDC", &[A, Reg(4)]),
+    op(0x3D, "ADDC", &[A, Reg(5)]),
+    op(0x3E, "ADDC", &[A, Reg(6)]),
+    op(0x3F, "ADDC", &[A, Reg(7)]),
+    op(0x40, "JC", &[Rel]),
+    op(0x41, "AJMP", &[Addr11]),
+    op(0x42, "ORL", &[Direct, A]),
+    op(0x43, "ORL", &[Direct, Imm]),
+    op(0x44, "ORL", &[A, Imm]),
+    op(0x45, "ORL", &[A, Direct]),
+    op(0x46, "ORL", &[A, AtReg(0)]),
+    op(0x47, "ORL", &[A, AtReg(1)]),
+    op(0x48, "ORL", &[A, Reg(0)]),
+    op(0x49, "ORL", &[A, Reg(1)]),
+    op(0x4A, "ORL", &[A, Reg(2)]),
+    op(0x4B, "ORL", &[A, Reg(3)]),
+    op(0x4C, "ORL", &[A, Reg(4)]),
+    op(0x4D, "ORL", &[A, Reg(5)]),
+    op(0x4E, "ORL", &[A, Reg(6)]),
+    op(0x4F, "ORL", &[A, Reg(7)]),
+    op(0x50, "JNC", &[Rel]),
+    op(0x51, "ACALL", &[Addr11]),
+    op(0x52, "ANL", &[Direct, A]),
+    op(0x53, "ANL", &[Direct, Imm]),
+    op(0x54, "ANL", &[A, Imm]),
+    op(0x55, "ANL", &[A, Direct]),
+    op(0x56, "ANL", &[A, AtReg(0)]),
+    op(0x57, "ANL", &[A, AtReg(1)]),
+    op(0x58, "ANL", &[A, Reg(0)]),
+    op(0x59, "ANL", &[A, Reg(1)]),
+    op(0x5A, "ANL", &[A, Reg(2)]),
+    op(0x5B, "ANL", &[A, Reg(3)]),
+    op(0x5C, "ANL", &[A, Reg(4)]),
+    op(0x5D, "ANL", &[A, Reg(5)]),
+    op(0x5E, "ANL", &[A, Reg(6)]),
+    op(0x5F, "ANL", &[A, Reg(7)]),
+    op(0x60, "JZ", &[Rel]),
+    op(0x61, "AJMP", &[Addr11]),
+    op(0x62, "XRL", &[Direct, A]),
+    op(0x63, "XRL", &[Direct, Imm]),
+    op(0x64, "XRL", &[A, Imm]),
+    op(0x65, "XRL", &[A, Direct]),
+    op(0x66, "XRL", &[A, AtReg(0)]),
+    op(0x67, "XRL", &[A, AtReg(1)]),
+    op(0x68, "XRL", &[A, Reg(0)]),
+    op(0x69, "XRL", &[A, Reg(1)]),
+    op(0x6A, "XRL", &[A, Reg(2)]),
+    op(0x6B, "XRL", &[A, Reg(3)]),
+    op(0x6C, "XRL", &[A, Reg(4)]),
+    op(0x6D, "XRL", &[A, Reg(5)]),
+    op(0x6E, "XRL", &[A, Reg(6)]),
+    op(0x6F, "XRL", &[A, Reg(7)]),
+    op(0x70, "JNZ", &[Rel]),
+    op(0x71, "ACALL", &[Addr11]),
+    op(0x72, "ORL", &[C, Bit]),
+    op(0x73, "JMP", &[AtADptr]),
+    op(0x74, "MOV", &[A, Imm]),
+    op(0x75, "MOV", &[Direct, Imm]),
+    op(0x76, "MOV", &[AtReg(0), Imm]),
+    op(0x77, "MOV", &[AtReg(1), Imm]),
+    op(0x78, "MOV", &[Reg(0), Imm]),
+    op(0x79, "MOV", &[Reg(1), Imm]),
+    op(0x7A, "MOV", &[Reg(2), Imm]),
+    op(0x7B, "MOV", &[Reg(3), Imm]),
+    op(0x7C, "MOV", &[Reg(4), Imm]),
+    op(0x7D, "MOV", &[Reg(5), Imm]),
+    op(0x7E, "MOV", &[Reg(6), Imm]),
+    op(0x7F, "MOV", &[Reg(7), Imm]),
+    op(0x80, "SJMP", &[Rel]),
+    op(0x81, "AJMP", &[Addr11]),
+    op(0x82, "ANL", &[C, Bit]),
+    op(0x83, "MOVC", &[A, AtAPc]),
+    op(0x84, "DIV", &[AB]),
+    op(0x85, "MOV", &[Direct, Direct]),
+    op(0x86, "MOV", &[Direct, AtReg(0)]),
+    op(0x87, "MOV", &[Direct, AtReg(1)]),
+    op(0x88, "MOV", &[Direct, Reg(0)]),
+    op(0x89, "MOV", &[Direct, Reg(1)]),
+    op(0x8A, "MOV", &[Direct, Reg(2)]),
+    op(0x8B, "MOV", &[Direct, Reg(3)]),
+    op(0x8C, "MOV", &[Direct, Reg(4)]),
+    op(0x8D, "MOV", &[Direct, Reg(5)]),
+    op(0x8E, "MOV", &[Direct, Reg(6)]),
+    op(0x8F, "MOV", &[Direct, Reg(7)]),
+    op(0x90, "MOV", &[Dptr, Imm16]),
+    op(0x91, "ACALL", &[Addr11]),
+    op(0x92, "MOV", &[Bit, C]),
+    op(0x93, "MOVC", &[A, AtADptr]),
+    op(0x94, "SUBB", &[A, Imm]),
+    op(0x95, "SUBB", &[A, Direct]),
+    op(0x96, "SUBB", &[A, AtReg(0)]),
+    op(0x97, "SUBB", &[A, AtReg(1)]),
+    op(0x98, "SUBB", &[A, Reg(0)]),
+    op(0x99, "SUBB", &[A, Reg(1)]),
+    op(0x9A, "SUBB", &[A, Reg(2)]),
+    op(0x9B, "SUBB", &[A, Reg(3)]),
+    op(0x9C, "SUBB", &[A, Reg(4)]),
+    op(0x9D, "SUBB", &[A, Reg(5)]),
+    op(0x9E, "SUBB", &[A, Reg(6)]),
+    op(0x9F, "SUBB", &[A, Reg(7)]),
+    op(0xA0, "ORL", &[C, NotBit]),
+    op(0xA1, "AJMP", &[Addr11]),
+    op(0xA2, "MOV", &[C, Bit]),
+    op(0xA3, "INC", &[Dptr]),
+    op(0xA4, "MUL", &[AB]),
+    op(0xA6, "MOV", &[AtReg(0), Direct]),
+    op(0xA7, "MOV", &[AtReg(1), Direct]),
+    op(0xA8, "MOV", &[Reg(0), Direct]),
+    op(0xA9, "MOV", &[Reg(1), Direct]),
+    op(0xAA, "MOV", &[Reg(2), Direct]),
+    op(0xAB, "MOV", &[Reg(3), Direct]),
+    op(0xAC, "MOV", &[Reg(4), Direct]),
+    op(0xAD, "MOV", &[Reg(5), Direct]),
+    op(0xAE, "MOV", &[Reg(6), Direct]),
+    op(0xAF, "MOV", &[Reg(7), Direct]),
+    op(0xB0, "ANL", &[C, NotBit]),
+    op(0xB1, "ACALL", &[Addr11]),
+    op(0xB2, "CPL", &[Bit]),
+    op(0xB3, "CPL", &[C]),
+    op(0xB4, "CJNE", &[A, Imm, Rel]),
+    op(0xB5, "CJNE", &[A, Direct, Rel]),
+    op(0xB6, "CJNE", &[AtReg(0), Imm, Rel]),
+    op(0xB7, "CJNE", &[AtReg(1), Imm, Rel]),
+    op(0xB8, "CJNE", &[Reg(0), Imm, Rel]),
+    op(0xB9, "CJNE", &[Reg(1), Imm, Rel]),
+    op(0xBA, "CJNE", &[Reg(2), Imm, Rel]),
+    op(0xBB, "CJNE", &[Reg(3), Imm, Rel]),
+    op(0xBC, "CJNE", &[Reg(4), Imm, Rel]),
+    op(0xBD, "CJNE", &[Reg(5), Imm, Rel]),
+    op(0xBE, "CJNE", &[Reg(6), Imm, Rel]),
+    op(0xBF, "CJNE", &[Reg(7), Imm, Rel]),
+    op(0xC0, "PUSH", &[Direct]),
+    op(0xC1, "AJMP", &[Addr11]),
+    op(0xC2, "CLR", &[Bit]),
+    op(0xC3, "CLR", &[C]),
+    op(0xC4, "SWAP", &[A]),
+    op(0xC5, "XCH", &[A, Direct]),
+    op(0xC6, "XCH", &[A, AtReg(0)]),
+    op(0xC7, "XCH", &[A, AtReg(1)]),
+    op(0xC8, "XCH", &[A, Reg(0)]),
+    op(0xC9, "XCH", &[A, Reg(1)]),
+    op(0xCA, "XCH", &[A, Reg(2)]),
+    op(0xCB, "XCH", &[A, Reg(3)]),
+    op(0xCC, "XCH", &[A, Reg(4)]),
+    op(0xCD, "XCH", &[A, Reg(5)]),
+    op(0xCE, "XCH", &[A, Reg(6)]),
+    op(0xCF, "XCH", &[A, Reg(7)]),
+    op(0xD0, "POP", &[Direct]),
+    op(0xD1, "ACALL", &[Addr11]),
+    op(0xD2, "SETB", &[Bit]),
+    op(0xD3, "SETB", &[C]),
+    op(0xD4, "DA", &[A]),
+    op(0xD5, "DJNZ", &[Direct, Rel]),
+    op(0xD6, "XCHD", &[A, AtReg(0)]),
+    op(0xD7, "XCHD", &[A, AtReg(1)]),
+    op(0xD8, "DJNZ", &[Reg(0), Rel]),
+    op(0xD9, "DJNZ", &[Reg(1), Rel]),
+    op(0xDA, "DJNZ", &[Reg(2), Rel]),
+    op(0xDB, "DJNZ", &[Reg(3), Rel]),
+    op(0xDC, "DJNZ", &[Reg(4), Rel]),
+    op(0xDD, "DJNZ", &[Reg(5), Rel]),
+    op(0xDE, "DJNZ", &[Reg(6), Rel]),
+    op(0xDF, "DJNZ", &[Reg(7), Rel]),
+    op(0xE0, "MOVX", &[A, AtDptr]),
+    op(0xE1, "AJMP", &[Addr11]),
+    op(0xE2, "MOVX", &[A, AtReg(0)]),
+    op(0xE3, "MOVX", &[A, AtReg(1)]),
+    op(0xE4, "CLR", &[A]),
+    op(0xE5, "MOV", &[A, Direct]),
+    op(0xE6, "MOV", &[A, AtReg(0)]),
+    op(0xE7, "MOV", &[A, AtReg(1)]),
+    op(0xE8, "MOV", &[A, Reg(0)]),
+    op(0xE9, "MOV", &[A, Reg(1)]),
+    op(0xEA, "MOV", &[A, Reg(2)]),
+    op(0xEB, "MOV", &[A, Reg(3)]),
+    op(0xEC, "MOV", &[A, Reg(4)]),
+    op(0xED, "MOV", &[A, Reg(5)]),
+    op(0xEE, "MOV", &[A, Reg(6)]),
+    op(0xEF, "MOV", &[A, Reg(7)]),
+    op(0xF0, "MOVX", &[AtDptr, A]),
+    op(0xF1, "ACALL", &[Addr11]),
+    op(0xF2, "MOVX", &[AtReg(0), A]),
+    op(0xF3, "MOVX", &[AtReg(1), A]),
+    op(0xF4, "CPL", &[A]),
+    op(0xF5, "MOV", &[Direct, A]),
+    op(0xF6, "MOV", &[AtReg(0), A]),
+    op(0xF7, "MOV", &[AtReg(1), A]),
+    op(0xF8, "MOV", &[Reg(0), A]),
+    op(0xF9, "MOV", &[Reg(1), A]),
+    op(0xFA, "MOV", &[Reg(2), A]),
+    op(0xFB, "MOV", &[Reg(3), A]),
+    op(0xFC, "MOV", &[Reg(4), A]),
+    op(0xFD, "MOV", &[Reg(5), A]),
+    op(0xFE, "MOV", &[Reg(6), A]),
+    op(0xFF, "MOV", &[Reg(7), A]),
+];
+
+/// All forms of the instruction `mnemonic` (upper case).
+pub fn forms<'a>(mnemonic: &'a str) -> impl Iterator<Item = &'static Opcode> + 'a {
+    OPCODES.iter().filter(move |x| x.mnemonic == mnemonic)
+}
+
+/// The instruction form of an opcode byte.
+pub fn decode(code: u8) -> Option<&'static Opcode> {
+    OPCODES.iter().find(|x| x.code == code)
+}
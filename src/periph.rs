@@ -0,0 +1,363 @@
+use std::io::{Read, Write};
+
+pub const SBUF: u8 = 0x99;
+pub const PORTS: [u8; 4] = [0x80, 0x90, 0xA0, 0xB0];
+
+const PCON: u8 = 0x87;
+const TCON: u8 = 0x88;
+const TMOD: u8 = 0x89;
+const TL0: u8 = 0x8A;
+const TL1: u8 = 0x8B;
+const TH0: u8 = 0x8C;
+const TH1: u8 = 0x8D;
+const SCON: u8 = 0x98;
+const IEN0: u8 = 0xA8;
+const IEN1: u8 = 0xE8;
+const IP0: u8 = 0xB8;
+const IP0H: u8 = 0xB7;
+const IP1: u8 = 0xF8;
+const IP1H: u8 = 0xF7;
+const KBMASK: u8 = 0x86;
+const KBPATN: u8 = 0x93;
+const KBCON: u8 = 0x94;
+const BRGCON: u8 = 0xBD;
+const BRGR0: u8 = 0xBE;
+const BRGR1: u8 = 0xBF;
+const RTCCON: u8 = 0xD1;
+const RTCH: u8 = 0xD2;
+const RTCL: u8 = 0xD3;
+
+/// Port 1 pins of the external interrupts on the LPC9xx.
+const INT0_PIN: u8 = 3;
+const INT1_PIN: u8 = 4;
+
+/// An interrupt source, in the order the LPC9xx polls them.
+struct Source {
+    vector: u16,
+    /// Bit number of the enable in IEN0 (0-7) or IEN1 (8-15), which is also
+    /// its priority bit in IP0/IP0H or IP1/IP1H.
+    enable: u8,
+    pending: fn(&Sfrs) -> bool,
+    /// Flag bit address cleared by hardware when the interrupt is taken.
+    clear: fn(&Sfrs) -> Option<u8>,
+}
+
+/// The sources of the peripherals modelled here; the brownout, I2C,
+/// comparator, SPI and CCU interrupts never become pending.
+const SOURCES: [Source; 7] = [
+    // External 0: IE0, cleared only when edge triggered
+    Source { vector: 0x03, enable: 0, pending: |s| s.bit(0x89), clear: |s| if s.bit(0x88) { Some(0x89) } else { None } },
+    // Timer 0: TF0
+    Source { vector: 0x0B, enable: 1, pending: |s| s.bit(0x8D), clear: |_| Some(0x8D) },
+    // External 1: IE1
+    Source { vector: 0x13, enable: 2, pending: |s| s.bit(0x8B), clear: |s| if s.bit(0x8A) { Some(0x8B) } else { None } },
+    // Timer 1: TF1
+    Source { vector: 0x1B, enable: 3, pending: |s| s.bit(0x8F), clear: |_| Some(0x8F) },
+    // UART: RI or TI
+    Source { vector: 0x23, enable: 4, pending: |s| s.bit(0x98) || s.bit(0x99), clear: |_| None },
+    // Keypad: KBIF in KBCON, enabled by EKBI in IEN1
+    Source { vector: 0x3B, enable: 9, pending: |s| s.get(KBCON) & 0x01 != 0, clear: |_| None },
+    // RTC, shared with the watchdog: RTCF when ERTC is set
+    Source { vector: 0x53, enable: 6, pending: |s| s.get(RTCCON) & 0x82 == 0x82, clear: |_| None },
+];
+
+/// The SFR space, addressed by SFR address.
+pub struct Sfrs(pub [u8; 128]);
+
+impl Sfrs {
+    pub fn get(&self, addr: u8) -> u8 {
+        self.0[(addr - 0x80) as usize]
+    }
+
+    pub fn set(&mut self, addr: u8, value: u8) {
+        self.0[(addr - 0x80) as usize] = value;
+    }
+
+    /// Reads a bit of a bit addressable SFR.
+    pub fn bit(&self, bit: u8) -> bool {
+        self.get(bit & 0xF8) >> (bit & 7) & 1 == 1
+    }
+
+    pub fn set_bit(&mut self, bit: u8, value: bool) {
+        let old = self.get(bit & 0xF8);
+        let mask = 1 << (bit & 7);
+        self.set(bit & 0xF8, if value { old | mask } else { old & !mask });
+    }
+}
+
+/// The UART, with its receiver fed from `input` and its transmitter
+/// writing to `output`.
+pub struct Uart {
+    pub input: Option<Box<dyn Read>>,
+    pub output: Box<dyn Write>,
+    /// Byte being sent and the bit times left.
+    tx: Option<(u8, u32)>,
+    rx: Option<(u8, u32)>,
+    /// Clocks, or timer 1 overflows, towards the next bit time.
+    ticks: u32,
+}
+
+impl Uart {
+    /// Starts sending a byte written to SBUF.
+    pub fn send(&mut self, sfr: &Sfrs, byte: u8) {
+        self.tx = Some((byte, frame_bits(sfr)));
+    }
+
+    /// Advances the UART by one machine cycle.
+    fn clock(&mut self, sfr: &mut Sfrs, t1_overflow: bool) {
+        let scon = sfr.get(SCON);
+        let mode = scon >> 6;
+        let smod1 = sfr.get(PCON) & 0x80 != 0;
+        let brg = sfr.get(BRGCON) & 0x03 == 0x03;
+        // Clocks per bit, or None when timer 1 overflows set the rate
+        let clocks = match mode {
+            0 => Some(6),
+            2 => Some(if smod1 { 16 } else { 32 }),
+            _ if brg => Some(((sfr.get(BRGR1) as u32) << 8 | sfr.get(BRGR0) as u32) + 16),
+            _ => None,
+        };
+        let bit = match clocks {
+            Some(n) => {
+                self.ticks += 2;
+                self.ticks >= n && { self.ticks -= n; true }
+            },
+            None => {
+                self.ticks += t1_overflow as u32;
+                let n = if smod1 { 16 } else { 32 };
+                self.ticks >= n && { self.ticks -= n; true }
+            },
+        };
+
+        // Start receiving when enabled and the last byte has been read
+        if self.rx.is_none() && scon & 0x10 != 0 && scon & 0x01 == 0 {
+            let mut byte = [0u8];
+            if let Some(ref mut input) = self.input {
+                if let Ok(1) = input.read(&mut byte) {
+                    self.rx = Some((byte[0], frame_bits(sfr)));
+                }
+            }
+        }
+        if !bit {
+            return;
+        }
+        if let Some((byte, left)) = self.tx {
+            if left <= 1 {
+                let _ = self.output.write_all(&[byte]);
+                let _ = self.output.flush();
+                sfr.set_bit(0x99, true);
+                self.tx = None;
+            } else {
+                self.tx = Some((byte, left - 1));
+            }
+        }
+        if let Some((byte, left)) = self.rx {
+            if left <= 1 {
+                sfr.set(SBUF, byte);
+                sfr.set_bit(0x9A, true);
+                sfr.set_bit(0x98, true);
+                self.rx = None;
+            } else {
+                self.rx = Some((byte, left - 1));
+            }
+        }
+    }
+}
+
+/// Bit times in a frame of the current UART mode.
+fn frame_bits(sfr: &Sfrs) -> u32 {
+    match sfr.get(SCON) >> 6 {
+        0 => 8,
+        1 => 10,
+        _ => 11,
+    }
+}
+
+/// Behavioural models of the LPC9xx timers 0 and 1, UART, real-time clock,
+/// keypad interrupt, port pins and interrupt controller. Registers live in the CPU's SFRs.
+pub struct Peripherals {
+    /// Levels driven onto the pins of P0 to P3 from outside. A pin reads
+    /// low if either its latch or its driver is low.
+    pub pins: [u8; 4],
+    pub uart: Uart,
+    /// The RTC's 23-bit down counter, in clocks.
+    rtc: u32,
+    /// The INT0 and INT1 pins at the last cycle, for edge detection.
+    int_pins: (bool, bool),
+}
+
+impl Peripherals {
+    pub fn new() -> Peripherals {
+        Peripherals {
+            pins: [0xFF; 4],
+            uart: Uart { input: None, output: Box::new(::std::io::stdout()), tx: None, rx: None, ticks: 0 },
+            rtc: 0,
+            int_pins: (true, true),
+        }
+    }
+
+    /// The external pin levels of the port at `addr`, if it is a port.
+    pub fn port_pins(&self, addr: u8) -> Option<u8> {
+        PORTS.iter().position(|&x| x == addr).map(|i| self.pins[i])
+    }
+
+    /// Advances every peripheral by `cycles` machine cycles.
+    pub fn tick(&mut self, sfr: &mut Sfrs, cycles: u32) {
+        for _ in 0..cycles {
+            self.external(sfr);
+            let t1_overflow = self.timers(sfr);
+            self.uart.clock(sfr, t1_overflow);
+            self.rtc(sfr);
+            self.keypad(sfr);
+        }
+    }
+
+    /// The pending interrupt that may preempt code running at
+    /// `current` priority (-1 outside any handler), with its vector and
+    /// priority. Clears the flags the hardware clears when it vectors.
+    pub fn interrupt(&mut self, sfr: &mut Sfrs, current: i8) -> Option<(u16, i8)> {
+        if sfr.get(IEN0) & 0x80 == 0 {
+            return None;
+        }
+        let mut best: Option<(&Source, i8)> = None;
+        for src in SOURCES.iter() {
+            let (ien, ip, iph, n) = if src.enable < 8 {
+                (IEN0, IP0, IP0H, src.enable)
+            } else {
+                (IEN1, IP1, IP1H, src.enable - 8)
+            };
+            if sfr.get(ien) >> n & 1 == 0 || !(src.pending)(sfr) {
+                continue;
+            }
+            let level = ((sfr.get(iph) >> n & 1) << 1 | (sfr.get(ip) >> n & 1)) as i8;
+            if level > current && best.is_none_or(|b| level > b.1) {
+                best = Some((src, level));
+            }
+        }
+        let (src, level) = best?;
+        if let Some(flag) = (src.clear)(sfr) {
+            sfr.set_bit(flag, false);
+        }
+        Some((src.vector, level))
+    }
+
+    /// Whether any interrupt is enabled, so that a program idling in a
+    /// loop may still be woken.
+    pub fn interrupts_enabled(&self, sfr: &Sfrs) -> bool {
+        sfr.get(IEN0) & 0x80 != 0 && (sfr.get(IEN0) & 0x7F != 0 || sfr.get(IEN1) != 0)
+    }
+
+    /// Sets IE0 and IE1 from the INT0 and INT1 pins: while low when level
+    /// triggered, on a falling edge when edge triggered.
+    fn external(&mut self, sfr: &mut Sfrs) {
+        let p1 = self.pins[1] & sfr.get(PORTS[1]);
+        let int0 = p1 >> INT0_PIN & 1 == 1;
+        let int1 = p1 >> INT1_PIN & 1 == 1;
+        for &(pin, last, it, ie) in &[(int0, self.int_pins.0, 0x88, 0x89), (int1, self.int_pins.1, 0x8A, 0x8B)] {
+            if sfr.bit(it) {
+                if last && !pin {
+                    sfr.set_bit(ie, true);
+                }
+            } else {
+                sfr.set_bit(ie, !pin);
+            }
+        }
+        self.int_pins = (int0, int1);
+    }
+
+    /// Sets KBIF when the P0 pins selected by KBMASK equal KBPATN, or with
+    /// PATN_SEL clear when they differ from it. Software clears KBIF.
+    fn keypad(&self, sfr: &mut Sfrs) {
+        let mask = sfr.get(KBMASK);
+        let p0 = self.pins[0] & sfr.get(PORTS[0]) & mask;
+        let kbcon = sfr.get(KBCON);
+        let matched = p0 == sfr.get(KBPATN) & mask;
+        if mask != 0 && matched == (kbcon & 0x02 != 0) {
+            sfr.set(KBCON, kbcon | 0x01);
+        }
+    }
+
+    /// Counts one machine cycle on timers 0 and 1. Returns whether timer 1
+    /// overflowed, which may clock the UART.
+    fn timers(&mut self, sfr: &mut Sfrs) -> bool {
+        let tmod = sfr.get(TMOD);
+        let tcon = sfr.get(TCON);
+        let p1 = self.pins[1] & sfr.get(PORTS[1]);
+        let mode0 = tmod & 0x03;
+        let mode1 = (tmod >> 4) & 0x03;
+        // Timers count machine cycles; counter mode (C/T set) needs pin
+        // events, which are not modelled
+        let gate0 = tmod & 0x08 == 0 || p1 >> INT0_PIN & 1 == 1;
+        let gate1 = tmod & 0x80 == 0 || p1 >> INT1_PIN & 1 == 1;
+        let run0 = tcon & 0x10 != 0 && gate0 && tmod & 0x04 == 0;
+        // In mode 3 timer 0 takes over TR1 and TF1, and timer 1 runs freely
+        let run1 = if mode0 == 3 { true } else { tcon & 0x40 != 0 && gate1 } && tmod & 0x40 == 0;
+
+        if run0 {
+            let mode = if mode0 == 3 { 2 } else { mode0 };
+            if count(sfr, TL0, TH0, mode, mode0 == 3) {
+                sfr.set_bit(0x8D, true);
+            }
+        }
+        if mode0 == 3 && tcon & 0x40 != 0 {
+            let th = sfr.get(TH0).wrapping_add(1);
+            sfr.set(TH0, th);
+            if th == 0 {
+                sfr.set_bit(0x8F, true);
+            }
+        }
+        let mut overflow = false;
+        if run1 && mode1 != 3 {
+            overflow = count(sfr, TL1, TH1, mode1, false);
+            if overflow && mode0 != 3 {
+                sfr.set_bit(0x8F, true);
+            }
+        }
+        overflow
+    }
+
+    /// Counts the RTC down by one machine cycle of clocks, setting RTCF and
+    /// reloading from RTCH:RTCL when it runs out.
+    fn rtc(&mut self, sfr: &mut Sfrs) {
+        let rtccon = sfr.get(RTCCON);
+        if rtccon & 0x01 == 0 {
+            self.rtc = 0;
+            return;
+        }
+        let reload = ((sfr.get(RTCH) as u32) << 8 | sfr.get(RTCL) as u32) << 7 | 0x7F;
+        if self.rtc == 0 {
+            self.rtc = reload;
+        }
+        if self.rtc <= 2 {
+            sfr.set(RTCCON, rtccon | 0x80);
+            self.rtc = reload;
+        } else {
+            self.rtc -= 2;
+        }
+    }
+}
+
+/// Counts one tick on a timer in `mode`. Mode 3 counts TL alone as an 8-bit
+/// timer (`split`). Returns whether it overflowed.
+fn count(sfr: &mut Sfrs, tl: u8, th: u8, mode: u8, split: bool) -> bool {
+    let (low, high) = (sfr.get(tl), sfr.get(th));
+    match mode {
+        0 => {
+            let value = ((high as u16) << 5 | (low & 0x1F) as u16) + 1;
+            sfr.set(tl, (low & 0xE0) | (value & 0x1F) as u8);
+            sfr.set(th, (value >> 5) as u8);
+            value > 0x1FFF
+        },
+        1 => {
+            let value = ((high as u32) << 8 | low as u32) + 1;
+            sfr.set(tl, value as u8);
+            sfr.set(th, (value >> 8) as u8);
+            value > 0xFFFF
+        },
+        _ => {
+            let value = low.wrapping_add(1);
+            let overflow = value == 0;
+            sfr.set(tl, if overflow && !split { high } else { value });
+            overflow
+        },
+    }
+}
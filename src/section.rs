@@ -2,36 +2,114 @@ use omf::ContentRecord;
 use instruction::Instruction;
 use line::Line;
 use hex_table::HexTable;
+use symbol::{self, Definition, Symbol, SymbolKind};
+use diagnostic::{Diagnostic, Warning};
+use include::{self, Origin};
+use cond::Known;
 
-pub fn get_sections(lines: Vec<Line>) -> Result<Vec<Section>, Vec<String>> {
+/// The 8051 address spaces, each laid out by its own segments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Space {
+    Code,
+    Data,
+    Idata,
+    Bit,
+    Xdata,
+}
+
+impl Space {
+    pub fn name(self) -> &'static str {
+        match self {
+            Space::Code => "CODE",
+            Space::Data => "DATA",
+            Space::Idata => "IDATA",
+            Space::Bit => "BIT",
+            Space::Xdata => "XDATA",
+        }
+    }
+
+    /// The highest address in the space. DATA stops below the SFRs at 80h,
+    /// and BIT covers the bit addressable RAM at 20h-2Fh.
+    fn limit(self) -> u32 {
+        match self {
+            Space::Code | Space::Xdata => 0xFFFF,
+            Space::Data | Space::Bit => 0x7F,
+            Space::Idata => 0xFF,
+        }
+    }
+
+    /// The kind of symbol a label in the space defines.
+    fn kind(self) -> SymbolKind {
+        match self {
+            Space::Code => SymbolKind::Code,
+            Space::Data => SymbolKind::Data,
+            Space::Idata => SymbolKind::Idata,
+            Space::Bit => SymbolKind::Bit,
+            Space::Xdata => SymbolKind::Xdata,
+        }
+    }
+}
+
+/// Splits the program into sections at each `ORG` and segment directive.
+/// Every space keeps its own location counter: a segment directive without
+/// `AT` carries on where the last segment of its space stopped.
+pub fn get_sections(lines: Vec<Line>, defines: &[Symbol]) -> Result<Vec<Section>, Vec<Diagnostic>> {
     let mut errors = Vec::new();
     let mut offset = 0;
     use section::Section;
     let mut sections = Vec::new();
-    let mut curr_sec = Section::new(0);
+    let mut curr_sec = Section::new(0, Space::Code);
+    let mut known = Known::new(defines);
     //get the instructions from each line
     for line in lines {
         // println!("{}", line );
-        let ins =  match Instruction::from_line(line,offset){
+        let num = line.num;
+        let from = line.expanded_from.clone();
+        let error = |column, e: String| Diagnostic { column, ..Diagnostic::error(num, e) }.expanded(&from);
+        // Columns in a macro body are not columns of the call
+        if let Some((column, ref e)) = line.error {
+            errors.push(error(Some(column).filter(|_| from.is_empty()), e.clone()));
+            continue;
+        }
+        known.record(&line);
+        let mut ins =  match Instruction::from_line(line,offset){
             Ok(i) => i,
-            Err(e) => {
-                errors.push(format!("Error: {}", e));
+            Err((column, e)) => {
+                errors.push(error(column, e));
                 continue;
             }
         };
-        offset =(offset as i32 +ins.len() as i32)as u16;
         // print!("{}", ins);
+        let segment = ins.segment();
         match ins.is_new_section() {
-            Some(d) => {
+            Ok(at) if at.is_some() || segment.is_some() => {
+                let space = segment.unwrap_or(curr_sec.space);
+                if let Some(start) = at.filter(|&x| x as u32 > space.limit()) {
+                    errors.push(error(None, format!("Address {:X}h is outside {} space (0-{:X}h)",
+                        start, space.name(), space.limit())));
+                }
                 sections.push(curr_sec);
-                curr_sec = Section::new(d);
+                curr_sec = Section::new(at.unwrap_or(0), space);
+                curr_sec.follows = at.is_none();
+                ins.set_offset(0);
                 offset = 0;
             },
-            None =>{},
+            Ok(_) => {},
+            Err(e) => errors.push(error(None, e)),
+        }
+        if curr_sec.space != Space::Code && !ins.is_allocation() {
+            errors.push(error(None, format!("Only DS and DBIT may be used in a {} segment", curr_sec.space.name())));
+        } else if ins.is_dbit() && curr_sec.space != Space::Bit {
+            errors.push(error(None, String::from("DBIT outside a BIT segment")));
+        }
+        if let Err((column, e)) = ins.resolve_size(&known) {
+            errors.push(error(column, e));
         }
+        offset =(offset as i32 +ins.len())as u16;
         curr_sec.push(ins);
     }
     sections.push(curr_sec);
+    place(&mut sections);
     if errors.is_empty() {
         Ok(sections)
     }
@@ -40,41 +118,219 @@ pub fn get_sections(lines: Vec<Line>) -> Result<Vec<Section>, Vec<String>> {
     }
 }
 
+/// Starts each section opened by a segment directive without an address
+/// where the one before it in the same space ends. Run again whenever
+/// relaxing changes the length of a section.
+pub fn place(sections: &mut [Section]) {
+    let mut ends = [0u32; 5];
+    for sec in sections.iter_mut() {
+        if sec.follows {
+            sec.set_offset(ends[sec.space as usize] as u16);
+        }
+        ends[sec.space as usize] = sec.end();
+    }
+}
+
+/// Warns about sections whose bytes overlap a later `ORG` or segment in the
+/// same space, either because code runs past it or because a `DS` or `DBIT`
+/// reservation covers it.
+pub fn overlaps(sections: &[Section], origins: &[Origin]) -> Vec<Diagnostic> {
+    let mut warnings = Vec::new();
+    let mut sorted: Vec<&Section> = sections.iter().collect();
+    sorted.sort_by_key(|x| x.offset);
+    for (i, a) in sorted.iter().enumerate() {
+        let a_spans = a.spans();
+        let a_end = a_spans.iter().map(|x| x.1).max().unwrap_or(0);
+        for b in sorted[i + 1..].iter().filter(|x| x.space == a.space) {
+            let b_spans = b.spans();
+            let b_end = b_spans.iter().map(|x| x.1).max().unwrap_or(0);
+            let start = b.offset as u32;
+            let end = a_end.min(b_end);
+            if start >= end {
+                continue;
+            }
+            let mut hits = a_spans.iter().chain(b_spans.iter()).filter(|x| x.0 < end && x.1 > start);
+            let org_line = b.instructions.first().map(|x| x.num()).unwrap_or(0);
+            let first = a_spans.iter().find(|x| x.0 < end && x.1 > start);
+            if let Some(ds) = hits.find(|x| x.2) {
+                let message = match a.space {
+                    Space::Code => format!("DS region {:04X}-{:04X} overlaps code", ds.0, ds.1 - 1),
+                    Space::Bit => format!("DBIT region {:02X}-{:02X} overlaps other BIT space reservations", ds.0, ds.1 - 1),
+                    space => format!("DS region {:04X}-{:04X} overlaps other {} space reservations", ds.0, ds.1 - 1, space.name()),
+                };
+                warnings.push(Diagnostic::warning(ds.3, Warning::DsOverlap, message));
+            } else if let Some(code) = first {
+                warnings.push(Diagnostic::warning(code.3, Warning::OrgOverlap,
+                    format!("Code at {:04X} runs past the ORG {:04X} at {}", code.0, b.offset, include::position(origins, org_line))));
+            }
+        }
+    }
+    warnings
+}
+
+/// Reports reservations and code that run past the end of their space.
+pub fn overflows(sections: &[Section]) -> Vec<Diagnostic> {
+    let mut errors = Vec::new();
+    for sec in sections {
+        let limit = sec.space.limit();
+        if let Some(span) = sec.spans().into_iter().find(|x| x.1 > limit + 1) {
+            errors.push(Diagnostic::error(span.3, format!("{:X}h-{:X}h runs past the end of {} space at {:X}h",
+                span.0, span.1 - 1, sec.space.name(), limit)));
+        }
+    }
+    errors
+}
+
 #[derive(Debug)]
 pub struct Section {
     offset: u16,
+    space: Space,
+    /// Whether the section continues its space from a bare `CSEG`, `DSEG`,
+    /// ..., rather than starting at an address.
+    follows: bool,
     instructions: Vec<Instruction>,
 }
 
 impl Section {
-    pub fn new(offset: u16) ->Section{
-        Section{offset:offset, instructions:Vec::new()}
+    pub fn new(offset: u16, space: Space) ->Section{
+        Section{offset, space, follows: false, instructions:Vec::new()}
     }
 
-    pub fn push(&mut self, instruction: Instruction) {
+    fn set_offset(&mut self, offset: u16) {
+        self.offset = offset;
+        for instruction in &mut self.instructions {
+            instruction.set_base(offset);
+        }
+    }
+
+    /// The address of the first byte of the section.
+    pub fn offset(&self) -> u16 {
+        self.offset
+    }
+
+    /// The address an `ORG` or segment directive opened the section at, or
+    /// None if it follows on from the one before.
+    pub fn placed_at(&self) -> Option<u16> {
+        if self.follows { None } else { Some(self.offset) }
+    }
+
+    /// The address after the last byte of the section.
+    pub fn end(&self) -> u32 {
+        self.offset as u32 + self.instructions.iter().map(|x| x.len().max(0) as u32).sum::<u32>()
+    }
+
+    pub fn space(&self) -> Space {
+        self.space
+    }
+
+    pub fn push(&mut self, mut instruction: Instruction) {
+        instruction.set_base(self.offset);
         self.instructions.push(instruction);
     }
 
-    pub fn build_label_table(&self) -> Vec<(String, u16)> {
-        let mut table: Vec<(String, u16)> = Vec::new();
+    pub fn build_label_table(&self) -> Vec<Symbol> {
+        let mut table = Vec::new();
         let mut label_instructions = self.instructions.clone();
-        label_instructions.retain(|x| x.label.is_some());
+        label_instructions.retain(|x| x.label.is_some() && x.definition(self.offset).is_none());
         for label in label_instructions {
             let offset = label.offset()+self.offset;
-            table.push((label.label.unwrap().to_lowercase(),offset));
+            table.push(Symbol::new(label.label.clone().unwrap(), offset, self.space.kind(), label.num()));
         }
         table
     }
+
+    /// Returns the line number, address, encoded bytes and macro expansion
+    /// text of every instruction, for the listing. Instructions that fail to
+    /// encode, and those outside CODE, are listed without bytes.
+    pub fn listing(&self) -> Vec<(u64, u16, Vec<u8>, Option<String>)> {
+        let mut listing = self.instructions.clone();
+        listing.retain(|x| !x.is_blank() && x.definition(self.offset).is_none());
+        listing.iter().map(|x| {
+            let expansion = x.expansion().map(|e| e.to_string());
+            let bytes = if self.space == Space::Code { x.to_hex().unwrap_or_default() } else { Vec::new() };
+            (x.num(), self.offset.wrapping_add(x.offset()), bytes, expansion)
+        }).collect()
+    }
+
+    /// Returns the start and end address, whether it is a `DS` and the line
+    /// of every instruction that occupies memory.
+    fn spans(&self) -> Vec<(u32, u32, bool, u64)> {
+        let mut spans = Vec::new();
+        for ins in &self.instructions {
+            if ins.len() > 0 {
+                let start = self.offset as u32 + ins.offset() as u32;
+                spans.push((start, start + ins.len() as u32, ins.is_ds(), ins.num()));
+            }
+        }
+        spans
+    }
+
+    /// Lengthens generic jumps, and with `branches` conditional branches,
+    /// that cannot reach their target and lays the section out again.
+    /// Returns whether anything changed.
+    pub fn relax(&mut self, label_table: &[Symbol], branches: bool) -> bool {
+        let mut changed = false;
+        for instruction in &mut self.instructions {
+            if instruction.relax(label_table, branches) {
+                changed = true;
+            }
+        }
+        if changed {
+            let mut offset = 0u16;
+            for instruction in &mut self.instructions {
+                instruction.set_offset(offset);
+                offset = offset.wrapping_add(instruction.len() as u16);
+            }
+        }
+        changed
+    }
+
+    /// Notes for the listing about every branch rewritten by `relax`.
+    pub fn relaxations(&self) -> Vec<Diagnostic> {
+        self.instructions.iter()
+            .filter_map(|x| x.relaxation().map(|m| Diagnostic::note(x.num(), m).expanded(x.expanded_from())))
+            .collect()
+    }
+
+    /// Warnings for every value cut to fit a byte.
+    pub fn truncations(&self) -> Vec<Diagnostic> {
+        self.instructions.iter().flat_map(|x| x.truncations().into_iter().map(move |(column, m)| {
+            Diagnostic { column, ..Diagnostic::warning(x.num(), Warning::Truncation, m) }.expanded(x.expanded_from())
+        })).collect()
+    }
+
+    /// Returns the `EQU`, `SET`, `DATA`, ... definitions in this section.
+    pub fn definitions(&self) -> Vec<Definition> {
+        self.instructions.iter().filter_map(|x| x.definition(self.offset)).collect()
+    }
+
+    /// Returns the line number, address and encoded bytes of every machine
+    /// instruction that encodes, for the timing analysis.
+    pub fn code(&self) -> Vec<(u64, u16, Vec<u8>)> {
+        self.instructions.iter().filter(|x| x.is_code() && self.space == Space::Code).filter_map(|x| {
+            x.to_hex().ok().map(|hex| (x.num(), self.offset.wrapping_add(x.offset()), hex))
+        }).collect()
+    }
     // pub fn instructions(&self) -> Vec<Instruction>{
     //     self.instructions.clone()
     // }
-    pub fn fix_labels(&mut self, label_table: &Vec<(String, u16)>) -> Result<(), Vec<String>>{
+    pub fn fix_labels(&mut self, label_table: &mut [Symbol]) -> Result<(), Vec<Diagnostic>>{
         let mut errors = Vec::new();
-        for mut instruction in &mut self.instructions {
-            match instruction.fix_label(label_table) {
+        for instruction in &mut self.instructions {
+            symbol::add_refs(label_table, &instruction.references(), instruction.num());
+            // SET symbols take their new value from this point on
+            if let Some(def) = instruction.definition(self.offset) {
+                if def.kind == SymbolKind::Set {
+                    if let Err(e) = symbol::redefine(label_table, &def) {
+                        errors.push(Diagnostic::error(instruction.num(), e).expanded(instruction.expanded_from()));
+                    }
+                }
+                continue;
+            }
+            match instruction.fix_label(label_table, self.offset) {
                 Ok(()) =>{},
-                Err(e) => {
-                    errors.push(format!("Error: {}", e));
+                Err((column, e)) => {
+                    errors.push(Diagnostic { column, ..Diagnostic::error(instruction.num(), e) }.expanded(instruction.expanded_from()));
                     continue;
                 }
             };
@@ -87,7 +343,7 @@ impl Section {
         }
     }
 
-    pub fn get_content_record(&self) -> Result<ContentRecord, Vec<String>> {
+    pub fn get_content_record(&self) -> Result<ContentRecord, Vec<Diagnostic>> {
         let mut errors = Vec::new();
         let mut hex_table = HexTable::new(&(self.instructions));
 
@@ -96,8 +352,8 @@ impl Section {
 
             let hex = match instruction.to_hex(){
                 Ok(h) => h,
-                Err(e) => {
-                    errors.push(format!("Error: {}", e));
+                Err((column, e)) => {
+                    errors.push(Diagnostic { column, ..Diagnostic::error(instruction.num(), e) }.expanded(instruction.expanded_from()));
                     continue
                 }
             };
@@ -115,3 +371,73 @@ impl Section {
 
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use line;
+
+    fn sections(source: &str) -> Result<Vec<Section>, Vec<Diagnostic>> {
+        get_sections(line::get_lines(source.to_string()), &[])
+    }
+
+    fn labels(sections: &[Section]) -> Vec<Symbol> {
+        sections.iter().flat_map(|x| x.build_label_table()).collect()
+    }
+
+    #[test]
+    fn continued_segment_follows_relaxed_code() {
+        let source = " cseg at 0
+ jmp far
+ dseg at 30h
+ ds 2
+ cseg
+next: sjmp next
+ cseg at 1000h
+far: ret
+";
+        let mut sections = sections(source).unwrap();
+        let address = |sections: &[Section]| symbol::find(&labels(sections), "next").map(|x| x.value);
+        assert_eq!(address(&sections), Some(2));
+        loop {
+            let table = labels(&sections);
+            let mut changed = false;
+            for sec in sections.iter_mut() {
+                changed |= sec.relax(&table, false);
+            }
+            if !changed {
+                break;
+            }
+            place(&mut sections);
+        }
+        assert_eq!(address(&sections), Some(3));
+        assert!(overlaps(&sections, &[]).is_empty());
+    }
+
+    #[test]
+    fn ds_is_sized_by_constants_defined_before_it() {
+        let source = "size equ 10\n dseg at 30h\nbuf: ds size\nbuf2: ds size*2\nafter: ds 1\n";
+        let table = labels(&sections(source).unwrap());
+        let address = |name: &str| symbol::find(&table, name).map(|x| x.value);
+        assert_eq!(address("buf"), Some(0x30));
+        assert_eq!(address("buf2"), Some(0x3A));
+        assert_eq!(address("after"), Some(0x4E));
+    }
+
+    #[test]
+    fn ds_rejects_sizes_not_yet_known() {
+        let errors = sections(" dseg at 30h\n ds later\n ds lab\nlab: ds 1\nlater equ 4\n").unwrap_err();
+        assert_eq!(errors.iter().map(|x| x.line).collect::<Vec<_>>(), vec![2, 3]);
+    }
+
+    #[test]
+    fn overlaps_name_their_space() {
+        let source = " dseg at 30h\n ds 4\n dseg at 32h\n ds 1\n bseg at 0\n dbit 8\n bseg at 4\n dbit 1\n";
+        let warnings = overlaps(&sections(source).unwrap(), &[]);
+        let messages: Vec<&str> = warnings.iter().map(|x| x.message.as_ref()).collect();
+        assert_eq!(messages, vec![
+            "DBIT region 00-07 overlaps other BIT space reservations",
+            "DS region 0030-0033 overlaps other DATA space reservations",
+        ]);
+    }
+}
@@ -0,0 +1,609 @@
+use opcode::{self, Opcode, Operand};
+use periph::{self, Peripherals, Sfrs};
+
+const ACC: u8 = 0xE0;
+const B: u8 = 0xF0;
+const PSW: u8 = 0xD0;
+const SP: u8 = 0x81;
+const DPL: u8 = 0x82;
+const DPH: u8 = 0x83;
+
+/// Bit addresses of the PSW flags.
+const CY: u8 = 0xD7;
+const AC: u8 = 0xD6;
+const OV: u8 = 0xD2;
+const P: u8 = 0xD0;
+
+/// Why `Cpu::run` stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// An unconditional jump to itself, as in `SJMP $`, with no interrupt
+    /// enabled that could leave it.
+    Halted,
+    MaxCycles,
+}
+
+/// Where an operand reads and writes its value.
+#[derive(Debug, Clone, Copy)]
+enum Loc {
+    /// A direct address: internal RAM below 80h, SFRs above.
+    Direct(u8),
+    /// Internal RAM, as addressed by `Rn` and `@Ri`.
+    Ram(u8),
+    Imm(u8),
+    Bit(u8),
+    NotBit(u8),
+    /// Operands handled by the instruction itself.
+    None,
+}
+
+/// An 8051 core with 256 bytes of internal RAM, the SFRs, 64K of code and
+/// 64K of external RAM, and the LPC9xx peripherals.
+pub struct Cpu {
+    code: Vec<u8>,
+    iram: [u8; 256],
+    sfr: Sfrs,
+    xram: Vec<u8>,
+    pub pc: u16,
+    /// Machine cycles executed since reset.
+    pub cycles: u64,
+    pub periph: Peripherals,
+    /// Priority levels of the interrupts being serviced, innermost last.
+    in_service: Vec<i8>,
+}
+
+impl Cpu {
+    /// Creates a core in its reset state with `code` loaded at address 0.
+    pub fn new(code: &[u8]) -> Cpu {
+        let mut memory = code.to_vec();
+        memory.resize(0x10000, 0);
+        let mut cpu = Cpu {
+            code: memory,
+            iram: [0; 256],
+            sfr: Sfrs([0; 128]),
+            xram: vec![0; 0x10000],
+            pc: 0,
+            cycles: 0,
+            periph: Peripherals::new(),
+            in_service: Vec::new(),
+        };
+        cpu.sfr.set(SP, 0x07);
+        for &port in &periph::PORTS {
+            cpu.sfr.set(port, 0xFF);
+        }
+        cpu
+    }
+
+    /// Runs until the program halts or `max_cycles` have been executed.
+    pub fn run(&mut self, max_cycles: u64) -> Result<Stop, String> {
+        while self.cycles < max_cycles {
+            let pc = self.pc;
+            let form = self.step()?;
+            let jump = matches!(form.mnemonic, "SJMP" | "AJMP" | "LJMP");
+            if jump && self.pc == pc && !self.periph.interrupts_enabled(&self.sfr) {
+                return Ok(Stop::Halted);
+            }
+        }
+        Ok(Stop::MaxCycles)
+    }
+
+    /// Executes one instruction, advances the peripherals by its cycles and
+    /// takes any interrupt that is due. Returns the instruction's form.
+    pub fn step(&mut self) -> Result<&'static Opcode, String> {
+        let pc = self.pc;
+        let code = self.code[pc as usize];
+        let form = match opcode::decode(code) {
+            Some(f) => f,
+            None => return Err(format!("Undefined opcode {:02X}h at {:04X}", code, pc)),
+        };
+        let bytes: Vec<u8> = (0..form.len()).map(|i| self.code[pc.wrapping_add(i as u16) as usize]).collect();
+        let next = pc.wrapping_add(form.len() as u16);
+        let values = form.values(&bytes, next);
+        self.pc = next;
+        self.cycles += form.cycles() as u64;
+        self.execute(form, &values);
+        let parity = self.acc().count_ones() % 2 == 1;
+        self.write_bit(P, parity);
+        self.periph.tick(&mut self.sfr, form.cycles());
+
+        // At least one more instruction runs after a RETI before the next
+        // interrupt
+        if form.mnemonic == "RETI" {
+            self.in_service.pop();
+            return Ok(form);
+        }
+        let current = self.in_service.last().cloned().unwrap_or(-1);
+        if let Some((vector, level)) = self.periph.interrupt(&mut self.sfr, current) {
+            let pc = self.pc;
+            self.push(pc as u8);
+            self.push((pc >> 8) as u8);
+            self.pc = vector;
+            self.cycles += 2;
+            self.periph.tick(&mut self.sfr, 2);
+            self.in_service.push(level);
+        }
+        Ok(form)
+    }
+
+    pub fn acc(&self) -> u8 {
+        self.read(Loc::Direct(ACC))
+    }
+
+    /// Register `n` of the bank selected in the PSW.
+    pub fn reg(&self, n: u8) -> u8 {
+        self.iram[self.bank() + n as usize]
+    }
+
+    pub fn dptr(&self) -> u16 {
+        (self.read_direct(DPH) as u16) << 8 | self.read_direct(DPL) as u16
+    }
+
+    pub fn iram(&self) -> &[u8] {
+        &self.iram
+    }
+
+    /// Reads a direct address. Ports read their pins, which are low where
+    /// either the latch or the outside drives them low.
+    pub fn read_direct(&self, addr: u8) -> u8 {
+        match self.periph.port_pins(addr) {
+            Some(pins) => self.latch(addr) & pins,
+            None => self.latch(addr),
+        }
+    }
+
+    /// Reads a direct address as stored, which for a port is its latch.
+    pub fn latch(&self, addr: u8) -> u8 {
+        if addr < 0x80 {
+            self.iram[addr as usize]
+        } else {
+            self.sfr.get(addr)
+        }
+    }
+
+    pub fn write_direct(&mut self, addr: u8, value: u8) {
+        if addr < 0x80 {
+            self.iram[addr as usize] = value;
+        } else if addr == periph::SBUF {
+            // Writes go to the transmitter; reads see the receive buffer
+            self.periph.uart.send(&self.sfr, value);
+        } else {
+            self.sfr.set(addr, value);
+        }
+    }
+
+    fn bank(&self) -> usize {
+        (self.read_direct(PSW) & 0x18) as usize
+    }
+
+    fn set_acc(&mut self, value: u8) {
+        self.write_direct(ACC, value);
+    }
+
+    fn set_dptr(&mut self, value: u16) {
+        self.write_direct(DPH, (value >> 8) as u8);
+        self.write_direct(DPL, value as u8);
+    }
+
+    fn flag(&self, bit: u8) -> bool {
+        self.read_bit(bit)
+    }
+
+    fn read_bit(&self, bit: u8) -> bool {
+        let (byte, n) = bit_byte(bit);
+        self.read_direct(byte) >> n & 1 == 1
+    }
+
+    fn write_bit(&mut self, bit: u8, value: bool) {
+        let (byte, n) = bit_byte(bit);
+        let old = self.latch(byte);
+        let new = if value { old | 1 << n } else { old & !(1 << n) };
+        self.write_direct(byte, new);
+    }
+
+    fn read(&self, loc: Loc) -> u8 {
+        match loc {
+            Loc::Direct(a) => self.read_direct(a),
+            Loc::Ram(a) => self.iram[a as usize],
+            Loc::Imm(v) => v,
+            Loc::Bit(b) => self.read_bit(b) as u8,
+            Loc::NotBit(b) => !self.read_bit(b) as u8,
+            Loc::None => 0,
+        }
+    }
+
+    /// Reads the destination of a read-modify-write instruction, which
+    /// sees a port's latch rather than its pins.
+    fn read_latch(&self, loc: Loc) -> u8 {
+        match loc {
+            Loc::Direct(a) => self.latch(a),
+            Loc::Bit(b) => {
+                let (byte, n) = bit_byte(b);
+                self.latch(byte) >> n & 1
+            },
+            _ => self.read(loc),
+        }
+    }
+
+    fn write(&mut self, loc: Loc, value: u8) {
+        match loc {
+            Loc::Direct(a) => self.write_direct(a, value),
+            Loc::Ram(a) => self.iram[a as usize] = value,
+            Loc::Bit(b) => self.write_bit(b, value != 0),
+            _ => {},
+        }
+    }
+
+    fn push(&mut self, value: u8) {
+        let sp = self.read_direct(SP).wrapping_add(1);
+        self.write_direct(SP, sp);
+        self.iram[sp as usize] = value;
+    }
+
+    fn pop(&mut self) -> u8 {
+        let sp = self.read_direct(SP);
+        self.write_direct(SP, sp.wrapping_sub(1));
+        self.iram[sp as usize]
+    }
+
+    fn locate(&self, kind: Operand, value: u16) -> Loc {
+        match kind {
+            Operand::A => Loc::Direct(ACC),
+            Operand::C => Loc::Bit(CY),
+            Operand::Reg(n) => Loc::Ram((self.bank() + n as usize) as u8),
+            Operand::AtReg(n) => Loc::Ram(self.reg(n)),
+            Operand::Imm => Loc::Imm(value as u8),
+            Operand::Direct => Loc::Direct(value as u8),
+            Operand::Bit => Loc::Bit(value as u8),
+            Operand::NotBit => Loc::NotBit(value as u8),
+            _ => Loc::None,
+        }
+    }
+
+    fn execute(&mut self, form: &Opcode, values: &[u16]) {
+        let ops: Vec<Loc> = form.operands.iter().zip(values).map(|(k, v)| self.locate(*k, *v)).collect();
+        let target = values.last().cloned().unwrap_or(0);
+        match form.mnemonic {
+            "NOP" => {},
+            "MOV" if form.operands[0] == Operand::Dptr => self.set_dptr(values[1]),
+            "MOV" => {
+                let v = self.read(ops[1]);
+                self.write(ops[0], v);
+            },
+            "MOVC" => {
+                let base = if form.operands[1] == Operand::AtAPc { self.pc } else { self.dptr() };
+                let v = self.code[base.wrapping_add(self.acc() as u16) as usize];
+                self.set_acc(v);
+            },
+            "MOVX" => {
+                let addr = |cpu: &Cpu, kind: Operand| match kind {
+                    Operand::AtReg(n) => cpu.reg(n) as usize,
+                    _ => cpu.dptr() as usize,
+                };
+                if form.operands[0] == Operand::A {
+                    let v = self.xram[addr(self, form.operands[1])];
+                    self.set_acc(v);
+                } else {
+                    let a = addr(self, form.operands[0]);
+                    self.xram[a] = self.acc();
+                }
+            },
+            "PUSH" => {
+                let v = self.read(ops[0]);
+                self.push(v);
+            },
+            "POP" => {
+                let v = self.pop();
+                self.write(ops[0], v);
+            },
+            "XCH" => {
+                let a = self.acc();
+                let v = self.read(ops[1]);
+                self.write(ops[1], a);
+                self.set_acc(v);
+            },
+            "XCHD" => {
+                let a = self.acc();
+                let v = self.read(ops[1]);
+                self.write(ops[1], (v & 0xF0) | (a & 0x0F));
+                self.set_acc((a & 0xF0) | (v & 0x0F));
+            },
+            "ADD" | "ADDC" => {
+                let carry = form.mnemonic == "ADDC" && self.flag(CY);
+                let v = self.read(ops[1]);
+                self.add(v, carry);
+            },
+            "SUBB" => {
+                let v = self.read(ops[1]);
+                self.subb(v);
+            },
+            "INC" if form.operands[0] == Operand::Dptr => {
+                let d = self.dptr().wrapping_add(1);
+                self.set_dptr(d);
+            },
+            "INC" => {
+                let v = self.read_latch(ops[0]).wrapping_add(1);
+                self.write(ops[0], v);
+            },
+            "DEC" => {
+                let v = self.read_latch(ops[0]).wrapping_sub(1);
+                self.write(ops[0], v);
+            },
+            "ANL" | "ORL" | "XRL" => {
+                let a = self.read_latch(ops[0]);
+                let b = self.read(ops[1]);
+                let v = match form.mnemonic {
+                    "ANL" => a & b,
+                    "ORL" => a | b,
+                    _ => a ^ b,
+                };
+                self.write(ops[0], v);
+            },
+            "CLR" => self.write(ops[0], 0),
+            "SETB" => self.write(ops[0], 1),
+            "CPL" => {
+                let v = self.read_latch(ops[0]);
+                let v = if form.operands[0] == Operand::A { !v } else { v ^ 1 };
+                self.write(ops[0], v);
+            },
+            "RL" => {
+                let a = self.acc();
+                self.set_acc(a.rotate_left(1));
+            },
+            "RR" => {
+                let a = self.acc();
+                self.set_acc(a.rotate_right(1));
+            },
+            "RLC" => {
+                let a = self.acc();
+                let c = self.flag(CY) as u8;
+                self.write_bit(CY, a & 0x80 != 0);
+                self.set_acc(a << 1 | c);
+            },
+            "RRC" => {
+                let a = self.acc();
+                let c = self.flag(CY) as u8;
+                self.write_bit(CY, a & 0x01 != 0);
+                self.set_acc(a >> 1 | c << 7);
+            },
+            "SWAP" => {
+                let a = self.acc();
+                self.set_acc(a.rotate_left(4));
+            },
+            "DA" => self.decimal_adjust(),
+            "MUL" => {
+                let r = self.acc() as u16 * self.read_direct(B) as u16;
+                self.set_acc(r as u8);
+                self.write_direct(B, (r >> 8) as u8);
+                self.write_bit(CY, false);
+                self.write_bit(OV, r > 0xFF);
+            },
+            "DIV" => {
+                let (a, b) = (self.acc(), self.read_direct(B));
+                self.write_bit(CY, false);
+                self.write_bit(OV, b == 0);
+                if let (Some(q), Some(r)) = (a.checked_div(b), a.checked_rem(b)) {
+                    self.set_acc(q);
+                    self.write_direct(B, r);
+                }
+            },
+            "AJMP" | "LJMP" | "SJMP" => self.pc = target,
+            "JMP" => self.pc = self.dptr().wrapping_add(self.acc() as u16),
+            "ACALL" | "LCALL" => {
+                let pc = self.pc;
+                self.push(pc as u8);
+                self.push((pc >> 8) as u8);
+                self.pc = target;
+            },
+            "RET" | "RETI" => {
+                let high = self.pop() as u16;
+                let low = self.pop() as u16;
+                self.pc = high << 8 | low;
+            },
+            "JC" | "JNC" | "JZ" | "JNZ" | "JB" | "JNB" | "JBC" => {
+                let taken = match form.mnemonic {
+                    "JC" => self.flag(CY),
+                    "JNC" => !self.flag(CY),
+                    "JZ" => self.acc() == 0,
+                    "JNZ" => self.acc() != 0,
+                    "JNB" => self.read(ops[0]) == 0,
+                    "JBC" => self.read_latch(ops[0]) != 0,
+                    _ => self.read(ops[0]) != 0,
+                };
+                if taken {
+                    if form.mnemonic == "JBC" {
+                        self.write(ops[0], 0);
+                    }
+                    self.pc = target;
+                }
+            },
+            "CJNE" => {
+                let (a, b) = (self.read(ops[0]), self.read(ops[1]));
+                self.write_bit(CY, a < b);
+                if a != b {
+                    self.pc = target;
+                }
+            },
+            "DJNZ" => {
+                let v = self.read_latch(ops[0]).wrapping_sub(1);
+                self.write(ops[0], v);
+                if v != 0 {
+                    self.pc = target;
+                }
+            },
+            m => unreachable!("no simulation of {}", m),
+        }
+    }
+
+    fn add(&mut self, v: u8, carry: bool) {
+        let a = self.acc();
+        let c = carry as u8;
+        let sum = a as u16 + v as u16 + c as u16;
+        let result = sum as u8;
+        self.write_bit(CY, sum > 0xFF);
+        self.write_bit(AC, (a & 0x0F) + (v & 0x0F) + c > 0x0F);
+        self.write_bit(OV, (a ^ result) & (v ^ result) & 0x80 != 0);
+        self.set_acc(result);
+    }
+
+    fn subb(&mut self, v: u8) {
+        let a = self.acc();
+        let c = self.flag(CY) as i16;
+        let diff = a as i16 - v as i16 - c;
+        let result = diff as u8;
+        self.write_bit(CY, diff < 0);
+        self.write_bit(AC, ((a & 0x0F) as i16) - ((v & 0x0F) as i16) - c < 0);
+        self.write_bit(OV, (a ^ v) & (a ^ result) & 0x80 != 0);
+        self.set_acc(result);
+    }
+
+    fn decimal_adjust(&mut self) {
+        let mut a = self.acc() as u16;
+        let mut carry = self.flag(CY);
+        if a & 0x0F > 9 || self.flag(AC) {
+            a += 0x06;
+            carry |= a > 0xFF;
+        }
+        if (a >> 4) & 0x0F > 9 || carry {
+            a += 0x60;
+            carry |= a > 0xFF;
+        }
+        self.write_bit(CY, carry);
+        self.set_acc(a as u8);
+    }
+}
+
+/// The byte and bit number of a bit address.
+fn bit_byte(bit: u8) -> (u8, u8) {
+    if bit < 0x80 {
+        (0x20 + bit / 8, bit % 8)
+    } else {
+        (bit & 0xF8, bit & 7)
+    }
+}
+
+/// Formats the registers after a run.
+pub fn registers(cpu: &Cpu) -> String {
+    let regs: Vec<String> = (0..8).map(|n| format!("R{}={:02X}", n, cpu.reg(n))).collect();
+    let ports: Vec<String> = periph::PORTS.iter().enumerate().map(|(n, &p)| format!("P{}={:02X}", n, cpu.latch(p))).collect();
+    format!("A={:02X} B={:02X} PSW={:02X} SP={:02X} DPTR={:04X}\n{}\n{}\n",
+        cpu.acc(), cpu.read_direct(B), cpu.read_direct(PSW), cpu.read_direct(SP), cpu.dptr(), regs.join(" "), ports.join(" "))
+}
+
+/// Formats the internal RAM as a hex dump.
+pub fn dump_ram(cpu: &Cpu) -> String {
+    let mut out = String::new();
+    for (i, row) in cpu.iram().chunks(16).enumerate() {
+        let bytes: Vec<String> = row.iter().map(|b| format!("{:02X}", b)).collect();
+        out += &format!("{:02X}: {}\n", i * 16, bytes.join(" "));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assemble;
+
+    /// Assembles and runs `source` until it halts.
+    fn run(source: &str) -> Cpu {
+        let mut code = vec![0; 0x10000];
+        for r in assemble(source).unwrap_or_else(|e| panic!("{}", e[0].describe())) {
+            let start = r.offset() as usize;
+            code[start..start + r.data().len()].copy_from_slice(&r.data());
+        }
+        let mut cpu = Cpu::new(&code);
+        assert_eq!(cpu.run(100_000), Ok(Stop::Halted), "stopped at {:04X}", cpu.pc);
+        cpu
+    }
+
+    #[test]
+    fn counts_timer_interrupts() {
+        let cpu = run("
+                org 0
+                ljmp main
+                org 0Bh
+                inc r7
+                reti
+        main:   mov tmod, #02h
+                mov th0, #0F0h
+                mov tl0, #0F0h
+                setb et0
+                setb ea
+                setb tr0
+        wait:   cjne r7, #3, wait
+                clr ea
+                sjmp $
+        ");
+        assert_eq!(cpu.reg(7), 3);
+        // The overflow flag is cleared each time the interrupt is taken
+        assert!(!cpu.sfr.bit(0x8D));
+    }
+
+    #[test]
+    fn adjusts_decimal_and_divides() {
+        let cpu = run("
+                mov a, #38h
+                add a, #49h
+                da a
+                mov r0, a
+                mov a, #99h
+                add a, #01h
+                da a
+                mov r1, a
+                mov r2, psw
+                mov a, #100
+                mov b, #7
+                div ab
+                mov r3, a
+                mov r4, b
+                mov r5, psw
+                mov b, #0
+                div ab
+                mov r6, psw
+                sjmp $
+        ");
+        assert_eq!(cpu.reg(0), 0x87);
+        assert_eq!((cpu.reg(1), cpu.reg(2) & 0x80), (0x00, 0x80));
+        assert_eq!((cpu.reg(3), cpu.reg(4), cpu.reg(5) & 0x84), (14, 2, 0));
+        // Dividing by zero sets OV and clears CY
+        assert_eq!(cpu.reg(6) & 0x84, 0x04);
+    }
+
+    #[test]
+    fn preempts_a_handler_of_lower_priority() {
+        let cpu = run("
+                org 0
+                ljmp main
+                org 0Bh
+                ljmp timer
+                org 3Bh
+                ljmp keypad
+        main:   mov tmod, #02h
+                mov th0, #0F0h
+                mov tl0, #0F0h
+                mov kbpatn, #0FFh
+                mov kbcon, #02h
+                orl ip1h, #02h
+                setb ekbi
+                setb et0
+                setb ea
+                setb tr0
+        wait:   cjne r5, #1, wait
+                clr ea
+                sjmp $
+        ; Priority 0: P0.0 is high, so unmasking it matches the pattern
+        timer:  clr tr0
+                mov kbmask, #01h
+        spin:   cjne r6, #1, spin
+                mov r5, #1
+                reti
+        ; Priority 2, from IP1H
+        keypad: mov kbmask, #0
+                anl kbcon, #0FEh
+                inc r6
+                reti
+        ");
+        assert_eq!((cpu.reg(5), cpu.reg(6)), (1, 1));
+        assert_eq!(cpu.sfr.get(0x94) & 0x01, 0);
+    }
+}
@@ -0,0 +1,235 @@
+use expr::Expr;
+use diagnostic::{Diagnostic, Warning};
+use include::{self, Origin};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Code,
+    Number,
+    Set,
+    Data,
+    Idata,
+    Xdata,
+    Bit,
+    Sfr,
+}
+
+impl SymbolKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            SymbolKind::Code => "CODE",
+            SymbolKind::Number => "NUMBER",
+            SymbolKind::Set => "SET",
+            SymbolKind::Data => "DATA",
+            SymbolKind::Idata => "IDATA",
+            SymbolKind::Xdata => "XDATA",
+            SymbolKind::Bit => "BIT",
+            SymbolKind::Sfr => "SFR",
+        }
+    }
+
+    /// Checks that `value` fits the address space of this kind of symbol.
+    pub fn check(self, value: i32) -> Result<u16, String> {
+        let max = match self {
+            SymbolKind::Data | SymbolKind::Idata | SymbolKind::Bit | SymbolKind::Sfr => 0xFF,
+            _ => 0xFFFF,
+        };
+        let min = match self {
+            SymbolKind::Number | SymbolKind::Set => -0x8000,
+            _ => 0,
+        };
+        if value < min || value > max {
+            return Err(format!("Value {:#X} out of range for {:?} symbol", value, self));
+        }
+        Ok(value as u16)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub value: u16,
+    pub kind: SymbolKind,
+    pub line: u64,
+    /// Lines that refer to the symbol.
+    pub refs: Vec<u64>,
+}
+
+impl Symbol {
+    pub fn new(name: String, value: u16, kind: SymbolKind, line: u64) -> Symbol {
+        Symbol { name, value, kind, line, refs: Vec::new() }
+    }
+}
+
+/// A `name EQU expr` style definition, evaluated once all code labels are
+/// known.
+#[derive(Debug, Clone)]
+pub struct Definition {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub expr: Expr,
+    pub here: u16,
+    pub line: u64,
+}
+
+pub fn find<'a>(table: &'a [Symbol], name: &str) -> Option<&'a Symbol> {
+    table.iter().find(|x| x.name.eq_ignore_ascii_case(name))
+}
+
+/// Resolves `name` to its value.
+pub fn lookup(table: &[Symbol], name: &str) -> Result<i32, String> {
+    match find(table, name) {
+        Some(x) => Ok(x.value as i32),
+        None => Err(format!("Could not find label: {}", name)),
+    }
+}
+
+/// Adds every definition to the table. Definitions may refer to each other
+/// in any order, so they are evaluated repeatedly until no more progress is
+/// made.
+pub fn resolve(table: &mut Vec<Symbol>, mut defs: Vec<Definition>) -> Result<(), Vec<Diagnostic>> {
+    let mut errors = Vec::new();
+    while !defs.is_empty() {
+        let mut pending = Vec::new();
+        let count = defs.len();
+        for def in defs {
+            let value = match def.expr.eval(&|s: &str| lookup(table, s), def.here) {
+                Ok(v) => v,
+                Err(_) => {
+                    pending.push(def);
+                    continue;
+                },
+            };
+            match def.kind.check(value) {
+                Ok(v) => {
+                    let redefined = find(table, &def.name).map(|x| x.kind) == Some(SymbolKind::Set);
+                    if def.kind == SymbolKind::Set && redefined {
+                        continue;
+                    }
+                    table.push(Symbol::new(def.name, v, def.kind, def.line));
+                },
+                Err(e) => errors.push(Diagnostic::error(def.line, e)),
+            }
+        }
+        if pending.len() == count {
+            for def in pending {
+                if let Err(e) = def.expr.eval(&|s: &str| lookup(table, s), def.here) {
+                    errors.push(Diagnostic::error(def.line, e));
+                }
+            }
+            break;
+        }
+        defs = pending;
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Names of registers, which can never be used as symbols.
+const RESERVED: [&str; 12] = ["a", "ab", "c", "dptr", "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7"];
+
+/// Checks the user defined symbols for clashes with each other and with the
+/// predefined SFR and bit names.
+///
+/// A symbol may only be defined once, except that `SET` symbols can be
+/// redefined by `SET`. Defining a predefined name again is allowed only with
+/// the same value (as register include files do); any other value is an
+/// error. A repeated definition with the same value is a warning.
+pub fn conflicts(table: &[Symbol], origins: &[Origin]) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    for (i, sym) in table.iter().enumerate() {
+        let name = sym.name.to_lowercase();
+        if sym.line > 0 && RESERVED.contains(&name.as_ref()) {
+            diagnostics.push(Diagnostic::error(sym.line, format!("{} is a reserved register name", sym.name)));
+            continue;
+        }
+        let first = match table[..i].iter().find(|x| x.name.to_lowercase() == name) {
+            Some(x) => x,
+            None => continue,
+        };
+        let (predefined, user) = match (first.line, sym.line) {
+            (0, 0) => continue,
+            (0, _) => (first, sym),
+            (_, 0) => (sym, first),
+            _ => {
+                if first.kind == SymbolKind::Set && sym.kind == SymbolKind::Set {
+                    continue;
+                }
+                if first.value == sym.value && first.kind == sym.kind {
+                    diagnostics.push(Diagnostic::warning(sym.line, Warning::DuplicateLabel,
+                        format!("{} redefined with the same value, first defined at {}", sym.name, include::position(origins, first.line))));
+                } else {
+                    diagnostics.push(Diagnostic::error(sym.line,
+                        format!("{} already defined at {}", sym.name, include::position(origins, first.line))));
+                }
+                continue;
+            },
+        };
+        if predefined.value != user.value {
+            // Only -D defines numbers without a line
+            let origin = if predefined.kind == SymbolKind::Number {
+                format!("{} defined on the command line (-D)", predefined.name)
+            } else {
+                format!("the predefined {} {}", predefined.kind.name(), predefined.name)
+            };
+            diagnostics.push(Diagnostic::error(user.line, format!("{} conflicts with {} ({:02X}h)",
+                user.name, origin, predefined.value)));
+        }
+    }
+    diagnostics
+}
+
+/// Warns about code labels that nothing refers to.
+pub fn unused(table: &[Symbol]) -> Vec<Diagnostic> {
+    table.iter()
+        .filter(|x| x.kind == SymbolKind::Code && x.line > 0 && x.refs.is_empty())
+        .map(|x| Diagnostic::warning(x.line, Warning::UnusedLabel, format!("Label {} is never used", x.name)))
+        .collect()
+}
+
+/// Records that `line` refers to each of `names`.
+pub fn add_refs(table: &mut [Symbol], names: &[String], line: u64) {
+    for name in names {
+        if let Some(sym) = table.iter_mut().find(|x| x.name.eq_ignore_ascii_case(name)) {
+            if !sym.refs.contains(&line) {
+                sym.refs.push(line);
+            }
+        }
+    }
+}
+
+/// Re-evaluates a `SET` symbol at the point of its definition so later
+/// lines see the new value.
+pub fn redefine(table: &mut [Symbol], def: &Definition) -> Result<(), String> {
+    let value = def.expr.eval(&|s: &str| lookup(table, s), def.here)?;
+    let value = def.kind.check(value)?;
+    if let Some(sym) = table.iter_mut().find(|x| x.kind == SymbolKind::Set && x.name.eq_ignore_ascii_case(&def.name)) {
+        sym.value = value;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use {build, Options};
+
+    /// The errors and warnings from assembling `source`.
+    fn messages(source: &str) -> Vec<String> {
+        build(source, &[], &Options::parse(&[], false).0, Vec::new()).diagnostics.iter()
+            .map(|x| x.describe())
+            .collect()
+    }
+
+    #[test]
+    fn reports_clashing_definitions() {
+        assert_eq!(messages("here: sjmp here\nHere: sjmp here\n end\n"), ["error: Here already defined at line 1"]);
+        assert_eq!(messages("n equ 1\nn equ 1\n end\n"),
+            ["warning: n redefined with the same value, first defined at line 1 [-Wduplicate-label]"]);
+        assert_eq!(messages("R0: sjmp R0\n end\n"), ["error: R0 is a reserved register name"]);
+        assert_eq!(messages("B: sjmp B\n end\n"), ["error: B conflicts with the predefined SFR B (F0h)"]);
+        assert_eq!(messages("Acc equ 0E0h\n mov Acc, #1\n end\n"), Vec::<String>::new());
+    }
+}
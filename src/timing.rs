@@ -0,0 +1,530 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::ops::Add;
+
+use opcode::{self, Operand};
+use section::{Section, Space};
+use symbol::{Symbol, SymbolKind};
+use include::{self, Origin};
+
+/// The clock that turns machine cycles into time.
+#[derive(Debug, Clone, Copy)]
+pub struct Clock {
+    /// Oscillator clocks per machine cycle: 2 on the LPC9xx, 12 on a
+    /// classic 8051.
+    pub clocks: u32,
+    /// Oscillator frequency in Hz.
+    pub fosc: u32,
+}
+
+impl Clock {
+    /// The LPC9xx running from its internal 7.373 MHz RC oscillator.
+    pub const LPC9XX: Clock = Clock { clocks: 2, fosc: 7_372_800 };
+
+    fn micros(&self, cycles: u64) -> f64 {
+        cycles as f64 * self.clocks as f64 * 1e6 / self.fosc as f64
+    }
+
+    fn time(&self, cycles: Cycles) -> String {
+        match cycles.max {
+            Some(max) if max != cycles.min => format!("{:.3}-{:.3}", self.micros(cycles.min), self.micros(max)),
+            Some(_) => format!("{:.3}", self.micros(cycles.min)),
+            None => format!("{:.3}+", self.micros(cycles.min)),
+        }
+    }
+}
+
+/// Machine cycles over the paths through some code: the fewest, and the
+/// most if that is bounded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cycles {
+    pub min: u64,
+    pub max: Option<u64>,
+}
+
+impl Cycles {
+    fn exact(n: u64) -> Cycles {
+        Cycles { min: n, max: Some(n) }
+    }
+
+    fn unbounded(min: u64) -> Cycles {
+        Cycles { min, max: None }
+    }
+}
+
+impl Add for Cycles {
+    type Output = Cycles;
+
+    fn add(self, other: Cycles) -> Cycles {
+        Cycles {
+            min: self.min + other.min,
+            max: self.max.and_then(|a| other.max.map(|b| a + b)),
+        }
+    }
+}
+
+impl ::std::fmt::Display for Cycles {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match self.max {
+            Some(max) if max != self.min => write!(f, "{}-{}", self.min, max),
+            Some(_) => write!(f, "{}", self.min),
+            None => write!(f, "{}+", self.min),
+        }
+    }
+}
+
+/// Where control goes when it leaves an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Flow {
+    Next(u16),
+    Return,
+    /// `JMP @A+DPTR`, or bytes that do not decode.
+    Unknown,
+}
+
+/// One way out of an instruction, with the cycles spent on the way and the
+/// routines called.
+#[derive(Debug, Clone)]
+struct Exit {
+    flow: Flow,
+    cycles: u64,
+    calls: Vec<u16>,
+}
+
+#[derive(Debug)]
+struct Node {
+    line: u64,
+    addr: u16,
+    end: u16,
+    exits: Vec<Exit>,
+}
+
+impl Node {
+    /// The cycles of the instruction itself, not counting calls.
+    fn cycles(&self) -> Cycles {
+        let min = self.exits.iter().map(|x| x.cycles).min().unwrap_or(0);
+        let max = self.exits.iter().map(|x| x.cycles).max().unwrap_or(0);
+        Cycles { min, max: Some(max) }
+    }
+
+    /// Whether control always carries on to the next instruction.
+    fn falls_through(&self) -> bool {
+        self.exits.iter().all(|x| x.flow == Flow::Next(self.end))
+    }
+}
+
+/// A straight-line run of instructions, entered only at the top and left
+/// only at the bottom.
+struct Block {
+    first: usize,
+    last: usize,
+    cycles: Cycles,
+}
+
+/// A called routine or interrupt handler and its cost up to RET or RETI,
+/// including the routines it calls.
+struct Routine {
+    name: String,
+    line: u64,
+    /// None if no path returns.
+    cycles: Option<Cycles>,
+    /// The first instruction of every loop and its cycles per iteration,
+    /// along the shortest path round it.
+    loops: Vec<(usize, u64)>,
+}
+
+/// Static cycle counts of the assembled code.
+pub struct Timing {
+    clock: Clock,
+    nodes: Vec<Node>,
+    blocks: Vec<Block>,
+    routines: Vec<Routine>,
+}
+
+/// Whether `node` is the jump to a handler at one of the LPC9xx interrupt
+/// vectors, which the program uses by placing code exactly there.
+fn is_vector(node: &Node, placed: &[u16]) -> bool {
+    node.addr < 0x80 && node.addr % 8 == 3 && node.exits.len() == 1 && placed.contains(&node.addr)
+}
+
+/// Builds the control flow graph of the machine instructions in `sections`
+/// and counts the cycles of every instruction, straight-line block and
+/// routine. Routines are the targets of calls, of the jumps in the
+/// interrupt vectors and the addresses in `entries`.
+pub fn analyse(sections: &[Section], symbols: &[Symbol], clock: Clock, entries: &[u16]) -> Timing {
+    let mut nodes: Vec<Node> = sections.iter().flat_map(|x| x.code()).map(|(line, addr, bytes)| Node {
+        line,
+        addr,
+        end: addr.wrapping_add(bytes.len() as u16),
+        exits: exits(addr, &bytes),
+    }).collect();
+    nodes.sort_by_key(|x| x.addr);
+    let index: HashMap<u16, usize> = nodes.iter().enumerate().map(|(i, x)| (x.addr, i)).collect();
+
+    let labels: Vec<&Symbol> = symbols.iter().filter(|x| x.kind == SymbolKind::Code && index.contains_key(&x.value)).collect();
+    let placed: Vec<u16> = sections.iter().filter(|x| x.space() == Space::Code).filter_map(|x| x.placed_at()).collect();
+    let mut entries: Vec<u16> = entries.iter().cloned().filter(|x| index.contains_key(x)).collect();
+    for node in &nodes {
+        for exit in &node.exits {
+            entries.extend(exit.calls.iter().filter(|x| index.contains_key(x)));
+            if let Flow::Next(t) = exit.flow {
+                if is_vector(node, &placed) && t != node.end && index.contains_key(&t) {
+                    entries.push(t);
+                }
+            }
+        }
+    }
+    entries.sort();
+    entries.dedup();
+
+    let mut graph = Graph { nodes, index, costs: HashMap::new() };
+    let blocks = graph.blocks(&labels);
+    let mut routines = Vec::new();
+    for &entry in &entries {
+        let cycles = graph.routine(entry);
+        let name = labels.iter().find(|x| x.value == entry).map(|x| x.name.clone())
+            .unwrap_or_else(|| format!("{:04X}", entry));
+        let start = graph.index[&entry];
+        routines.push(Routine { name, line: graph.nodes[start].line, cycles, loops: graph.loops(start) });
+    }
+    Timing { clock, nodes: graph.nodes, blocks, routines }
+}
+
+/// Follows the forms encoded in one instruction, which are several for a
+/// relaxed branch, to the ways out of it.
+fn exits(addr: u16, bytes: &[u8]) -> Vec<Exit> {
+    let mut exits = Vec::new();
+    let mut paths = vec![(0, 0, Vec::new())];
+    while let Some((pos, cycles, mut calls)) = paths.pop() {
+        let form = match opcode::decode(bytes[pos]) {
+            Some(f) if pos + f.len() <= bytes.len() => f,
+            _ => {
+                exits.push(Exit { flow: Flow::Unknown, cycles, calls });
+                continue;
+            },
+        };
+        let here = addr.wrapping_add(pos as u16);
+        let next = here.wrapping_add(form.len() as u16);
+        let values = form.values(&bytes[pos..pos + form.len()], next);
+        let target = form.operands.iter().zip(&values)
+            .find(|x| matches!(*x.0, Operand::Rel | Operand::Addr11 | Operand::Addr16))
+            .map(|x| *x.1);
+        let cycles = cycles + form.cycles() as u64;
+        let to = match (form.mnemonic, target) {
+            ("RET", _) | ("RETI", _) => vec![Flow::Return],
+            ("JMP", _) => vec![Flow::Unknown],
+            ("ACALL", Some(t)) | ("LCALL", Some(t)) => {
+                calls.push(t);
+                vec![Flow::Next(next)]
+            },
+            ("AJMP", Some(t)) | ("LJMP", Some(t)) | ("SJMP", Some(t)) => vec![Flow::Next(t)],
+            (_, Some(t)) => vec![Flow::Next(t), Flow::Next(next)],
+            _ => vec![Flow::Next(next)],
+        };
+        for flow in to {
+            // Forward jumps within the instruction carry on through it
+            match flow {
+                Flow::Next(t) if t > here && ((t.wrapping_sub(addr)) as usize) < bytes.len() => {
+                    paths.push((t.wrapping_sub(addr) as usize, cycles, calls.clone()));
+                },
+                _ => exits.push(Exit { flow, cycles, calls: calls.clone() }),
+            }
+        }
+    }
+    exits
+}
+
+struct Graph {
+    nodes: Vec<Node>,
+    index: HashMap<u16, usize>,
+    /// Cost of every routine analysed so far; None while it is being
+    /// analysed or if it never returns.
+    costs: HashMap<u16, Option<Cycles>>,
+}
+
+impl Graph {
+    /// The instruction that control goes to, if it is one of ours.
+    fn next(&self, exit: &Exit) -> Option<usize> {
+        match exit.flow {
+            Flow::Next(t) => self.index.get(&t).cloned(),
+            _ => None,
+        }
+    }
+
+    /// The cycles along an exit, including the routines it calls. A call
+    /// that cannot be costed, such as recursion, makes it unbounded.
+    fn step(&self, exit: &Exit) -> Cycles {
+        exit.calls.iter().fold(Cycles::exact(exit.cycles), |sum, x| {
+            sum + self.costs.get(x).cloned().unwrap_or(None).unwrap_or(Cycles::unbounded(0))
+        })
+    }
+
+    fn blocks(&self, labels: &[&Symbol]) -> Vec<Block> {
+        let mut leaders: HashSet<u16> = labels.iter().map(|x| x.value).collect();
+        for node in &self.nodes {
+            for exit in node.exits.iter().filter(|x| x.flow != Flow::Next(node.end)) {
+                if let Flow::Next(t) = exit.flow {
+                    leaders.insert(t);
+                }
+                leaders.extend(&exit.calls);
+            }
+        }
+        let mut blocks: Vec<Block> = Vec::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            let joins = i > 0 && self.nodes[i - 1].end == node.addr && self.nodes[i - 1].falls_through();
+            match blocks.last_mut() {
+                Some(b) if joins && !leaders.contains(&node.addr) => {
+                    b.last = i;
+                    b.cycles = b.cycles + node.cycles();
+                },
+                _ => blocks.push(Block { first: i, last: i, cycles: node.cycles() }),
+            }
+        }
+        blocks
+    }
+
+    /// Costs the routine at `entry`, first costing the routines it calls.
+    fn routine(&mut self, entry: u16) -> Option<Cycles> {
+        if let Some(&cost) = self.costs.get(&entry) {
+            return cost;
+        }
+        self.costs.insert(entry, None);
+        let start = self.index[&entry];
+        let reached = self.reachable(start);
+        let calls: Vec<u16> = reached.iter()
+            .flat_map(|&i| self.nodes[i].exits.iter().flat_map(|x| x.calls.clone()))
+            .filter(|x| self.index.contains_key(x))
+            .collect();
+        for call in calls {
+            self.routine(call);
+        }
+
+        let min = self.shortest(start, None)?;
+        let returns = self.returning(&reached);
+        let mut longest = HashMap::new();
+        let mut on_path = HashSet::new();
+        let max = self.longest(start, &returns, &mut longest, &mut on_path);
+        let cost = Some(Cycles { min, max });
+        self.costs.insert(entry, cost);
+        cost
+    }
+
+    fn reachable(&self, start: usize) -> Vec<usize> {
+        let mut seen = vec![start];
+        let mut todo = vec![start];
+        while let Some(i) = todo.pop() {
+            for next in self.nodes[i].exits.iter().filter_map(|x| self.next(x)) {
+                if !seen.contains(&next) {
+                    seen.push(next);
+                    todo.push(next);
+                }
+            }
+        }
+        seen
+    }
+
+    /// The instructions of `reached` from which a path returns.
+    fn returning(&self, reached: &[usize]) -> HashSet<usize> {
+        let mut returns: HashSet<usize> = reached.iter().cloned()
+            .filter(|&i| self.nodes[i].exits.iter().any(|x| x.flow == Flow::Return))
+            .collect();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &i in reached {
+                if !returns.contains(&i) && self.nodes[i].exits.iter().any(|x| self.next(x).is_some_and(|n| returns.contains(&n))) {
+                    returns.insert(i);
+                    changed = true;
+                }
+            }
+        }
+        returns
+    }
+
+    /// The fewest cycles from the start of `from` to the start of `to`, or
+    /// to a return if `to` is None.
+    fn shortest(&self, from: usize, to: Option<usize>) -> Option<u64> {
+        let mut done = HashSet::new();
+        let mut queue = BinaryHeap::new();
+        queue.push(Reverse((0, Some(from))));
+        while let Some(Reverse((cycles, at))) = queue.pop() {
+            let i = match at {
+                _ if at == to => return Some(cycles),
+                Some(i) => i,
+                None => continue,
+            };
+            if !done.insert(i) {
+                continue;
+            }
+            for exit in &self.nodes[i].exits {
+                let cost = cycles + self.step(exit).min;
+                match exit.flow {
+                    Flow::Return => queue.push(Reverse((cost, None))),
+                    _ => if let Some(n) = self.next(exit) {
+                        queue.push(Reverse((cost, Some(n))));
+                    },
+                }
+            }
+        }
+        None
+    }
+
+    /// The most cycles from `i` to a return, or None if a loop or an
+    /// unknown jump on the way makes it unbounded.
+    fn longest(&self, i: usize, returns: &HashSet<usize>, memo: &mut HashMap<usize, Option<u64>>, on_path: &mut HashSet<usize>) -> Option<u64> {
+        if let Some(&m) = memo.get(&i) {
+            return m;
+        }
+        if !on_path.insert(i) {
+            return None;
+        }
+        let mut max = Some(0);
+        for exit in &self.nodes[i].exits {
+            let rest = match exit.flow {
+                Flow::Return => Some(0),
+                Flow::Unknown => None,
+                Flow::Next(_) => match self.next(exit) {
+                    Some(n) if returns.contains(&n) => self.longest(n, returns, memo, on_path),
+                    Some(_) => continue,
+                    None => None,
+                },
+            };
+            let total = self.step(exit).max.and_then(|c| rest.map(|r| c + r));
+            max = max.and_then(|m| total.map(|t| m.max(t)));
+        }
+        on_path.remove(&i);
+        memo.insert(i, max);
+        max
+    }
+
+    /// Finds the loops reachable from `start` that can still return, with
+    /// their cycles per iteration.
+    fn loops(&self, start: usize) -> Vec<(usize, u64)> {
+        let reached = self.reachable(start);
+        let returns = self.returning(&reached);
+        let mut loops = Vec::new();
+        for &i in &reached {
+            for exit in &self.nodes[i].exits {
+                let head = match self.next(exit) {
+                    Some(n) if n <= i && returns.contains(&n) => n,
+                    _ => continue,
+                };
+                let round = if head == i { Some(0) } else { self.shortest(head, Some(i)) };
+                if let Some(cycles) = round {
+                    loops.push((head, cycles + self.step(exit).min));
+                }
+            }
+        }
+        loops.sort();
+        loops.dedup_by_key(|x| x.0);
+        loops
+    }
+}
+
+impl Timing {
+    /// The cycles of the instruction at `addr`, for the listing.
+    pub fn cycles(&self, addr: u16) -> Option<Cycles> {
+        self.nodes.binary_search_by_key(&addr, |x| x.addr).ok().map(|i| self.nodes[i].cycles())
+    }
+
+    /// The block and routine report at the end of the listing, with lines
+    /// numbered within their own file through `origins`.
+    pub fn report(&self, origins: &[Origin]) -> String {
+        let clock = &self.clock;
+        let mut out = format!("\n\nTIMING ({} clocks per cycle at {} Hz)\n\n", clock.clocks, clock.fosc);
+        out += "BLOCK      LINES        CYCLES     TIME (us)\n";
+        for b in &self.blocks {
+            let (first, last) = (&self.nodes[b.first], &self.nodes[b.last]);
+            let lines = include::source_lines(origins, first.line, last.line);
+            out += &format!("{:04X}-{:04X}  {:<12} {:<10} {}\n",
+                first.addr, last.end.wrapping_sub(1), lines, b.cycles.to_string(), clock.time(b.cycles));
+        }
+        out += "\nROUTINE                   LINE   CYCLES     TIME (us)\n";
+        for r in &self.routines {
+            match r.cycles {
+                Some(c) => out += &format!("{:<25} {:>5}   {:<10} {}\n", r.name, include::source_line(origins, r.line), c.to_string(), clock.time(c)),
+                None => out += &format!("{:<25} {:>5}   does not return\n", r.name, include::source_line(origins, r.line)),
+            }
+            for &(head, cycles) in &r.loops {
+                let node = &self.nodes[head];
+                out += &format!("    loop at {:04X} ({}): {} cycles, {:.3} us per iteration\n",
+                    node.addr, include::position(origins, node.line), cycles, clock.micros(cycles));
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {build, Options};
+
+    /// Times `source` on the LPC9xx clocked at 12 MHz.
+    fn timing(source: &str, entries: &[u16]) -> Timing {
+        let args: Vec<String> = ["--fosc", "12000000"].iter().map(|x| x.to_string()).collect();
+        let options = Options::parse(&args, false).0;
+        let build = build(source, &[], &options, Vec::new());
+        assert!(build.diagnostics.iter().all(|x| !x.is_error()));
+        analyse(&build.sections, &build.symbols, options.clock, entries)
+    }
+
+    fn routine<'a>(timing: &'a Timing, name: &str) -> &'a Routine {
+        timing.routines.iter().find(|x| x.name == name).unwrap_or_else(|| panic!("no routine {}", name))
+    }
+
+    #[test]
+    fn times_a_straight_line_block() {
+        let timing = timing("main: mov a, #1\n add a, r0\n mov r1, a\n inc dptr\n sjmp main\n end\n", &[]);
+        assert_eq!(timing.blocks[0].cycles, Cycles::exact(7));
+        assert_eq!(timing.clock.time(timing.blocks[0].cycles), "1.167");
+    }
+
+    #[test]
+    fn times_routines_with_loops_and_calls() {
+        let timing = timing("main: acall outer
+ acall delay
+ acall task
+ sjmp main
+outer: acall sub
+ ret
+sub: nop
+ nop
+ ret
+delay: mov r7, #10
+wait: djnz r7, wait
+ ret
+task: sjmp task
+ end
+", &[]);
+        assert_eq!(routine(&timing, "sub").cycles, Some(Cycles::exact(4)));
+        assert_eq!(routine(&timing, "outer").cycles, Some(Cycles::exact(8)));
+        assert_eq!(timing.clock.time(Cycles::exact(8)), "1.333");
+        // The loop has no bound, so only the fewest cycles are known
+        let delay = routine(&timing, "delay");
+        assert_eq!(delay.cycles, Some(Cycles::unbounded(5)));
+        assert_eq!(delay.loops.iter().map(|x| (timing.nodes[x.0].addr, x.1)).collect::<Vec<_>>(), [(0x10, 2)]);
+        assert_eq!(routine(&timing, "task").cycles, None);
+    }
+
+    #[test]
+    fn times_handlers_at_used_vectors_and_entries() {
+        let source = " org 0
+ ljmp main
+ org 0Bh
+ ljmp timer
+main: nop
+ nop
+ ljmp spare
+timer: reti
+spare: nop
+ ret
+ end
+";
+        let names = |t: &Timing| t.routines.iter().map(|x| x.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(&timing(source, &[])), ["timer"]);
+        assert_eq!(names(&timing(source, &[0x14])), ["timer", "spare"]);
+        // An LJMP that merely falls at 0003h is not a vector
+        assert_eq!(names(&timing(" nop\n nop\n nop\n ljmp spare\nspare: ret\n end\n", &[])), Vec::<String>::new());
+    }
+}